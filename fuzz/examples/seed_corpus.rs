@@ -0,0 +1,101 @@
+//! Writes a seed corpus for each fuzz target into `corpus/`, made of
+//! the same sorts of values that our round-trip tests use.  Run it
+//! with `cargo run --example seed_corpus` before fuzzing, so that the
+//! fuzzer starts from inputs that get past the tag bytes.
+
+#[macro_use] extern crate arrayref;
+extern crate onionsalt;
+extern crate pmail;
+
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use onionsalt::{crypto, PAYLOAD_LENGTH};
+use pmail::dht;
+use pmail::dht::{MyBytes, WireBytes, RoutingGift, NUM_IN_RESPONSE,
+                 DECRYPTED_USER_MESSAGE_LENGTH, USER_MESSAGE_LENGTH};
+use pmail::message;
+use pmail::pmail::{Message, Thread};
+use pmail::str255::Str255;
+
+fn save(target: &str, name: &str, data: &[u8]) {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("corpus");
+    dir.push(target);
+    fs::create_dir_all(&dir).unwrap();
+    dir.push(name);
+    fs::File::create(&dir).unwrap().write_all(data).unwrap();
+}
+
+fn main() {
+    let addrs: Vec<SocketAddr> = ["10.0.0.1:54321", "[2001:db8::1]:8080", "[::ffff:10.0.0.1]:80"]
+        .iter().map(|a| SocketAddr::from_str(a).unwrap()).collect();
+    for (i, a) in addrs.iter().enumerate() {
+        let mut buf = [0; 18];
+        a.bytes(&mut buf);
+        save("socket_addr", &format!("addr-{}", i), &buf);
+
+        // A RoutingInfo is a flags byte (1 for "is for me" and 2 for
+        // "who am I"), the address, and the eta.
+        for flags in 0..3 {
+            let mut routing = [0; 1 + 18 + 4 + 1];
+            routing[0] = flags;
+            routing[1..19].copy_from_slice(&buf);
+            (1000*i as u32).bytes(array_mut_ref![routing, 19, 4]);
+            save("routing_info", &format!("routing-{}-{}", i, flags), &routing);
+        }
+    }
+
+    let gifts = [RoutingGift { addr: addrs[0], key: crypto::box_keypair().public }; NUM_IN_RESPONSE];
+    let key = crypto::box_keypair();
+    let boxed = [7; USER_MESSAGE_LENGTH];
+    let messages = vec![
+        ("greetings", dht::Message::Greetings(gifts)),
+        ("response", dht::Message::Response(gifts)),
+        ("pickup", dht::Message::PickUp { destination: key.public, message: boxed }),
+        ("forward", dht::Message::ForwardPlease { destination: key.public, message: boxed }),
+        ("punch-please", dht::Message::PunchPlease(key.public)),
+        ("punch-at", dht::Message::PunchAt(gifts[0])),
+        ("delivery", dht::Message::Delivery { remaining: 3, message: boxed }),
+        ("handover", dht::Message::Handover { new_key: key.public, proof: [1; 48] }),
+    ];
+    for (name, m) in messages {
+        let mut buf = [0; PAYLOAD_LENGTH];
+        m.bytes(&mut buf);
+        save("dht_message", name, &buf);
+    }
+
+    let messages = vec![
+        ("query", Message::UserQuery { user: Str255::from("hello") }),
+        ("response", Message::UserResponse { user: Str255::from("hello"), key: key.public }),
+        ("comment", Message::Comment {
+            thread: Thread(0x1234),
+            time: 1,
+            message_length: 5,
+            message_start: 0,
+            contents: [b'x'; 394],
+        }),
+        ("acknowledge", Message::Acknowledge { msg_id: message::Id(key.public.0) }),
+    ];
+    for (name, m) in messages {
+        let mut buf = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+        m.bytes(&mut buf);
+        save("pmail_message", name, &buf);
+    }
+
+    // A message that really opens, preceded by the key that opens it,
+    // along with one where the key is wrong.
+    let sender = crypto::box_keypair();
+    let mut plain = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+    plain[5] = 3;
+    let (_, c) = dht::double_box(&plain, &key.public, &sender);
+    let mut buf = Vec::new();
+    buf.extend(key.secret.0.iter().cloned());
+    buf.extend(c.iter().cloned());
+    save("double_unbox", "good", &buf);
+    buf[0] ^= 1;
+    save("double_unbox", "wrong-key", &buf);
+}
@@ -0,0 +1,22 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+#[macro_use] extern crate arrayref;
+extern crate onionsalt;
+extern crate pmail;
+
+use onionsalt::PAYLOAD_LENGTH;
+use pmail::dht::{Message, WireBytes};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < PAYLOAD_LENGTH {
+        return;
+    }
+    if let Ok(m) = Message::decode(array_ref![data, 0, PAYLOAD_LENGTH]) {
+        // Messages have no equality, so we compare their encodings.
+        let mut out = [0; PAYLOAD_LENGTH];
+        m.bytes(&mut out);
+        let mut again = [0; PAYLOAD_LENGTH];
+        Message::decode(&out).unwrap().bytes(&mut again);
+        assert!(&out[..] == &again[..]);
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+#[macro_use] extern crate arrayref;
+extern crate onionsalt;
+extern crate pmail;
+
+use onionsalt::crypto;
+use pmail::dht::{double_unbox, USER_MESSAGE_LENGTH};
+
+// The input is our secret key followed by the doubly boxed message,
+// so that the seed corpus can hold messages that really open.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 32 + USER_MESSAGE_LENGTH {
+        return;
+    }
+    let sk = crypto::SecretKey(*array_ref![data, 0, 32]);
+    let _ = double_unbox(array_ref![data, 32, USER_MESSAGE_LENGTH], &sk);
+});
@@ -0,0 +1,20 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+#[macro_use] extern crate arrayref;
+extern crate pmail;
+
+use pmail::dht::{WireBytes, DECRYPTED_USER_MESSAGE_LENGTH};
+use pmail::pmail::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < DECRYPTED_USER_MESSAGE_LENGTH {
+        return;
+    }
+    if let Ok(m) = Message::decode(array_ref![data, 0, DECRYPTED_USER_MESSAGE_LENGTH]) {
+        let mut out = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+        m.bytes(&mut out);
+        let mut again = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+        Message::decode(&out).unwrap().bytes(&mut again);
+        assert!(&out[..] == &again[..]);
+    }
+});
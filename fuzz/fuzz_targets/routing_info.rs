@@ -0,0 +1,21 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+#[macro_use] extern crate arrayref;
+extern crate onionsalt;
+extern crate pmail;
+
+use onionsalt::ROUTING_LENGTH;
+use pmail::dht::{RoutingInfo, WireBytes};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < ROUTING_LENGTH {
+        return;
+    }
+    if let Ok(r) = RoutingInfo::decode(array_ref![data, 0, ROUTING_LENGTH]) {
+        let mut out = [0; ROUTING_LENGTH];
+        r.bytes(&mut out);
+        let mut again = [0; ROUTING_LENGTH];
+        RoutingInfo::decode(&out).unwrap().bytes(&mut again);
+        assert!(&out[..] == &again[..]);
+    }
+});
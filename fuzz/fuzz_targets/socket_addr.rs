@@ -0,0 +1,23 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+#[macro_use] extern crate arrayref;
+extern crate pmail;
+
+use std::net::SocketAddr;
+use pmail::dht::WireBytes;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 18 {
+        return;
+    }
+    if let Ok(a) = SocketAddr::decode(array_ref![data, 0, 18]) {
+        // Anything we accept must survive being sent on.  An
+        // IPv4-mapped address comes back as plain IPv4, so we compare
+        // encodings rather than addresses.
+        let mut out = [0; 18];
+        a.bytes(&mut out);
+        let mut again = [0; 18];
+        SocketAddr::decode(&out).unwrap().bytes(&mut again);
+        assert_eq!(out, again);
+    }
+});
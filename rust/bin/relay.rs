@@ -17,12 +17,15 @@ use tiny_http::{ServerBuilder, Response};
 
 use pmail::pmail::{AddressBook, Message};
 use pmail::dht;
+use pmail::config::{NodeConfig, Timing};
 
 use smtp::sender::{SenderBuilder};
 use smtp::email::SimpleSendableEmail;
 use onionsalt::crypto;
 
 use std::sync::{Arc,Mutex};
+use std::sync::atomic::Ordering;
+use std::io::Write;
 
 fn main() {
     {
@@ -30,7 +33,45 @@ fn main() {
         init().unwrap();
     }
 
-    let addressbook = Arc::new(Mutex::new(AddressBook::read(&pmail::pmail::relay_dir().unwrap()).unwrap()));
+    // `relay --capture FILE` records every packet to FILE, which can
+    // later be played back with the `replay` binary.  `relay
+    // --bootstrap FILE` joins the network of the relays listed in
+    // FILE, rather than the public one.  `relay --profile NAME` uses
+    // one of the timing profiles in `config::Timing`, e.g. "lan-test".
+    let mut config = NodeConfig::default();
+    let args: Vec<String> = std::env::args().collect();
+    let mut i = 1;
+    while i < args.len() {
+        match (&args[i][..], args.get(i+1)) {
+            ("--capture", Some(f)) => {
+                config.capture = Some(std::path::PathBuf::from(f));
+            },
+            ("--bootstrap", Some(f)) => {
+                config.bootstrap = Some(std::path::PathBuf::from(f));
+            },
+            ("--profile", Some(name)) => {
+                match Timing::profile(name) {
+                    Some(timing) => config.timing = timing,
+                    None => {
+                        println!("unknown profile {:?}", name);
+                        std::process::exit(1);
+                    },
+                }
+            },
+            _ => {
+                println!("usage: {} [--capture FILE] [--bootstrap FILE] [--profile NAME]",
+                         args[0]);
+                std::process::exit(1);
+            },
+        }
+        i += 2;
+    }
+
+    let addressbook = Arc::new(Mutex::new(AddressBook::read(&pmail::pmail::relay_dir().unwrap(),
+                                                            &config).unwrap()));
+    // We keep what the node knows in this file, so that whoever runs
+    // the relay can see what it is up to.
+    let stats_file = pmail::pmail::relay_dir().unwrap().join("stats");
 
     let response_keys = crypto::box_keypair();
     let secret_key_for_http = response_keys.public.0;
@@ -66,8 +107,27 @@ fn main() {
     });
 
     loop {
-        std::thread::sleep_ms(1000*30); // sleep a while before doing a pickup...
+        // sleep a while before doing a pickup...
+        std::thread::sleep_ms(config.timing.pickup_period_ms as u32);
         let mut addressbook = addressbook.lock().unwrap();
+        {
+            let state = addressbook.relay_state();
+            if state.evicted > 0 || state.refused > 0 {
+                info!("Relay state under pressure: {:?}", state);
+            }
+            let flood = addressbook.flood_counters();
+            if flood.dropped() > 0 {
+                info!("Flood protection: accepted {}, dropped {} per source and {} overall",
+                      flood.accepted.load(Ordering::Relaxed),
+                      flood.dropped_per_source.load(Ordering::Relaxed),
+                      flood.dropped_global.load(Ordering::Relaxed));
+            }
+            let stats = format!("{}", addressbook.node_stats());
+            if let Err(e) = std::fs::File::create(&stats_file)
+                .and_then(|mut f| f.write_all(stats.as_bytes())) {
+                info!("Unable to write {:?}: {}", stats_file, e);
+            }
+        }
         addressbook.pickup();
         if let Some((p,msg_id,m)) = addressbook.listen() {
             info!("I got personal message {:?} with id {}!", m, msg_id);
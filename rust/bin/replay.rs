@@ -0,0 +1,65 @@
+//! Play back the packets received in a capture file (as recorded by
+//! `relay --capture FILE`) through the same code that handles packets
+//! on the real network, so that misbehaviour can be reproduced
+//! offline.
+//!
+//! Usage: replay CAPTURE DIR
+//!
+//! The packets can only be opened with the routing key of the node
+//! that recorded them, so DIR must hold that key.  There is no
+//! default: pointing replay at the directory of a live relay is asking
+//! for trouble, so copy the key somewhere else first.  The key is only
+//! ever read, and neither the routing table nor the key is written
+//! back.  Note that the routing key is named after the host, so it
+//! may need renaming when replaying on another machine.
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate pmail;
+
+use std::path::PathBuf;
+
+use pmail::capture;
+use pmail::capture::Direction;
+use pmail::config::NodeConfig;
+use pmail::dht;
+
+fn main() {
+    {
+        use env_logger::init;
+        init().unwrap();
+    }
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        println!("usage: {} CAPTURE DIR", args[0]);
+        std::process::exit(1);
+    }
+    let path = PathBuf::from(&args[1]);
+    let dir = PathBuf::from(&args[2]);
+
+    let records = capture::read_capture(&path).unwrap();
+    for r in records.iter() {
+        let arrow = match r.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        println!("{:>14} {} {:<40} {}", r.time_ms, arrow, r.packet.ip,
+                 dht::codename(&r.packet.data));
+    }
+    let received: Vec<u64> = records.iter()
+        .filter(|r| r.direction == Direction::Received)
+        .map(|r| r.time_ms).collect();
+    let duration_ms = match (received.first(), received.last()) {
+        (Some(a), Some(b)) => b.saturating_sub(*a),
+        _ => 0,
+    };
+
+    let config = NodeConfig { replay: Some(path), .. NodeConfig::default() };
+    let node = dht::start_static_node(&dir, &config).unwrap();
+    info!("Replaying {} packets over {} seconds", received.len(), duration_ms/1000);
+    // We give the node a while after the last packet to act on it.
+    std::thread::sleep_ms((duration_ms + 30*1000) as u32);
+    drop(node);
+}
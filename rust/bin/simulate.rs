@@ -0,0 +1,75 @@
+//! Run a whole pmail network in memory, with users sending one
+//! another messages, and report how many got through and how long
+//! they took.  See the `sim` module.
+//!
+//! Usage: simulate [--relays N] [--users N] [--latency MS] [--jitter MS]
+//!                 [--loss FRACTION] [--nat FRACTION] [--churn SECS]
+//!                 [--minutes N] [--profile NAME]
+
+extern crate env_logger;
+extern crate pmail;
+
+use std::str::FromStr;
+
+use pmail::config::Timing;
+use pmail::sim;
+
+fn usage(program: &str) -> ! {
+    println!("usage: {} [--relays N] [--users N] [--latency MS] [--jitter MS] \
+              [--loss FRACTION] [--nat FRACTION] [--churn SECS] [--minutes N] [--profile NAME]",
+             program);
+    std::process::exit(1);
+}
+
+fn parse<T: FromStr>(program: &str, v: &str) -> T {
+    match v.parse() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("bad value {:?}", v);
+            usage(program)
+        },
+    }
+}
+
+fn main() {
+    {
+        use env_logger::init;
+        init().unwrap();
+    }
+
+    let mut config = sim::SimConfig::default();
+    let args: Vec<String> = std::env::args().collect();
+    let p = &args[0];
+    let mut i = 1;
+    while i < args.len() {
+        match (&args[i][..], args.get(i+1)) {
+            ("--relays", Some(v)) => config.relays = parse(p, v),
+            ("--users", Some(v)) => config.users = parse(p, v),
+            ("--latency", Some(v)) => config.conditions.latency_ms = parse(p, v),
+            ("--jitter", Some(v)) => config.conditions.jitter_ms = parse(p, v),
+            ("--loss", Some(v)) => config.conditions.loss = parse(p, v),
+            ("--nat", Some(v)) => config.nat_fraction = parse(p, v),
+            ("--churn", Some(v)) => config.churn_period_ms = Some(1000*parse::<u64>(p, v)),
+            ("--minutes", Some(v)) => config.duration_ms = 60*1000*parse::<u64>(p, v),
+            ("--profile", Some(name)) => {
+                match Timing::profile(name) {
+                    Some(timing) => config.node.timing = timing,
+                    None => {
+                        println!("unknown profile {:?}", name);
+                        std::process::exit(1);
+                    },
+                }
+            },
+            _ => usage(p),
+        }
+        i += 2;
+    }
+    if config.relays < 3 || config.users < 2 {
+        println!("we need at least 3 relays and 2 users");
+        std::process::exit(1);
+    }
+
+    println!("Simulating {} relays and {} users for {} minutes...",
+             config.relays, config.users, config.duration_ms/60/1000);
+    println!("{}", sim::run(&config));
+}
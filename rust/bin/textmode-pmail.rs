@@ -24,6 +24,7 @@ use pmail::dht;
 use pmail::mailbox;
 use pmail::udp;
 use pmail::format;
+use pmail::config::NodeConfig;
 
 struct LogData {
     messages: Vec<String>,
@@ -166,7 +167,13 @@ fn main() {
         }
     };
 
-    let mut addressbook = AddressBook::read(&pmail::pmail::pmail_dir().unwrap()).unwrap();
+    let config = NodeConfig {
+        // We are just a client, so any port will do if a relay is
+        // already running on this host.
+        allow_port_fallback: true,
+        .. NodeConfig::default()
+    };
+    let mut addressbook = AddressBook::read(&pmail::pmail::pmail_dir().unwrap(), &config).unwrap();
     let mut mailbox = mailbox::Mailbox::new().unwrap();
     let mut which_thread = 0;
     let mut selected_user = 0;
@@ -183,7 +190,8 @@ fn main() {
     let mut finduser_query = String::new();
     let mut message_tosend = String::new();
     let mut dummy = String::new();
-    let mut count_to_pickup = 0;
+    let pickup_period_ns = config.timing.pickup_period_ms*1000*1000;
+    let mut next_pickup = time::precise_time_ns() + pickup_period_ns;
     loop {
         match us {
             UserState::Logs => {
@@ -225,7 +233,13 @@ fn main() {
                     Some(Key::Char(c)) => { editing.push(c); }
                     Some(Key::Enter) => {
                         match us {
-                            UserState::Logs => { info!("Noop"); }
+                            UserState::Logs => {
+                                // Enter in the log view shows what our
+                                // node knows about the network.
+                                for line in format!("{}", addressbook.node_stats()).lines() {
+                                    info!("{}", line);
+                                }
+                            }
                             UserState::FindUser => {
                                 if editing.len() == 0 { continue; }
                                 let e = which_user_selected(&addressbook, selected_user);
@@ -292,10 +306,9 @@ fn main() {
             Err(e) => panic!("{}", e),
             _ => { }
         }
-        count_to_pickup += 1;
-        if count_to_pickup == 120 { // this is very hokey...
+        if time::precise_time_ns() >= next_pickup {
             addressbook.pickup();
-            count_to_pickup = 0;
+            next_pickup = time::precise_time_ns() + pickup_period_ns;
         }
         if let Some((p,msg_id,m)) = addressbook.listen() {
             info!("I got personal message {:?}!", m);
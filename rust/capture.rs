@@ -0,0 +1,216 @@
+//! Recording and replaying the packets a node sends and receives.
+//!
+//! A capture file starts with `MAGIC`, followed by one record per
+//! packet.  Each record holds a direction byte (`b's'` for sent and
+//! `b'r'` for received), the time in ms since recording started (on a
+//! monotonic clock, so that the records stay in order even if the
+//! wall clock is stepped), the peer's address (in the same 18 bytes we use in a `RoutingGift`)
+//! and the packet itself.  Capture files grow without bound, so
+//! recording is only turned on when asked for.
+
+extern crate time;
+
+use std;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use dht::{MyBytes, WireBytes};
+use udp::{RawEncryptedMessage, Transport, PACKET_LENGTH, POLL_MS};
+
+const MAGIC: &'static [u8; 8] = b"pmailcap";
+const RECORD_LENGTH: usize = 1 + 8 + 18 + PACKET_LENGTH;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub direction: Direction,
+    /// When the packet was sent or received, in ms since recording
+    /// started.
+    pub time_ms: u64,
+    /// The packet, with `ip` being the peer it was sent to or received
+    /// from.
+    pub packet: RawEncryptedMessage,
+}
+
+impl Record {
+    fn bytes(&self, out: &mut [u8; RECORD_LENGTH]) {
+        let (d, t, a, p) = mut_array_refs!(out, 1, 8, 18, PACKET_LENGTH);
+        d[0] = match self.direction {
+            Direction::Sent => b's',
+            Direction::Received => b'r',
+        };
+        self.time_ms.bytes(t);
+        self.packet.ip.bytes(a);
+        *p = self.packet.data;
+    }
+    fn from_bytes(inp: &[u8; RECORD_LENGTH]) -> Result<Record, Error> {
+        let (d, t, a, p) = array_refs!(inp, 1, 8, 18, PACKET_LENGTH);
+        let direction = match d[0] {
+            b's' => Direction::Sent,
+            b'r' => Direction::Received,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "bad record in capture")),
+        };
+        Ok(Record {
+            direction: direction,
+            time_ms: u64::from_bytes(t),
+            packet: RawEncryptedMessage { ip: try!(SocketAddr::decode(a)), data: *p },
+        })
+    }
+}
+
+/// Read every record in a capture file.
+pub fn read_capture(path: &Path) -> Result<Vec<Record>, Error> {
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != &MAGIC[..] {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("{:?} is not a capture file", path)));
+    }
+    let mut out = Vec::new();
+    for chunk in data[MAGIC.len()..].chunks(RECORD_LENGTH) {
+        if chunk.len() != RECORD_LENGTH {
+            // The recorder was probably killed mid-write, so we just
+            // drop the partial record.
+            warn!("Ignoring truncated record at the end of {:?}", path);
+            break;
+        }
+        out.push(try!(Record::from_bytes(array_ref![chunk, 0, RECORD_LENGTH])));
+    }
+    Ok(out)
+}
+
+/// A `Transport` that writes every packet that passes through it to a
+/// capture file.
+pub struct Recording<T: Transport> {
+    inner: T,
+    file: Mutex<File>,
+    /// The monotonic time (in ns) at which we started recording.
+    start_ns: u64,
+}
+
+impl<T: Transport> Recording<T> {
+    pub fn create(inner: T, path: &Path) -> Result<Recording<T>, Error> {
+        let mut f = try!(File::create(path));
+        try!(f.write_all(MAGIC));
+        info!("Recording packets to {:?}", path);
+        Ok(Recording { inner: inner, file: Mutex::new(f), start_ns: time::precise_time_ns() })
+    }
+    fn record(&self, direction: Direction, packet: &RawEncryptedMessage) {
+        let mut buf = [0; RECORD_LENGTH];
+        Record {
+            direction: direction,
+            time_ms: (time::precise_time_ns() - self.start_ns)/1000000,
+            packet: *packet,
+        }.bytes(&mut buf);
+        // We write each record in one go, so that a capture cut short
+        // by a crash is still readable.
+        if let Err(e) = self.file.lock().unwrap().write_all(&buf) {
+            error!("Unable to record packet: {:?}", e);
+        }
+    }
+}
+
+impl<T: Transport> Transport for Recording<T> {
+    fn send_packet(&self, msg: &RawEncryptedMessage) -> Result<(), Error> {
+        self.record(Direction::Sent, msg);
+        self.inner.send_packet(msg)
+    }
+    fn recv_packet(&self) -> Result<RawEncryptedMessage, Error> {
+        let p = try!(self.inner.recv_packet());
+        self.record(Direction::Received, &p);
+        Ok(p)
+    }
+}
+
+/// A `Transport` that plays back the packets received in a capture,
+/// keeping their original spacing in time, so that the flood guard and
+/// the scheduler see what they saw the first time around.  Anything
+/// sent is only logged.  Once the capture is exhausted, nothing more
+/// ever arrives.
+pub struct Replay {
+    pending: Mutex<VecDeque<Record>>,
+    /// The time of the first received packet in the capture, and the
+    /// monotonic time (in ns) at which we started playing it back.
+    start: (u64, u64),
+}
+
+impl Replay {
+    pub fn open(path: &Path) -> Result<Replay, Error> {
+        let pending: VecDeque<Record> = try!(read_capture(path)).into_iter()
+            .filter(|r| r.direction == Direction::Received).collect();
+        info!("Replaying {} packets from {:?}", pending.len(), path);
+        let first = pending.front().map(|r| r.time_ms).unwrap_or(0);
+        Ok(Replay {
+            pending: Mutex::new(pending),
+            start: (first, time::precise_time_ns()),
+        })
+    }
+}
+
+impl Transport for Replay {
+    fn send_packet(&self, msg: &RawEncryptedMessage) -> Result<(), Error> {
+        info!("Replay drops packet to {}", msg.ip);
+        Ok(())
+    }
+    fn recv_packet(&self) -> Result<RawEncryptedMessage, Error> {
+        let mut pending = self.pending.lock().unwrap();
+        let due_ms = match pending.front() {
+            // Captures made before we recorded monotonic time may
+            // go backwards, in which case the packet is simply due.
+            Some(r) => r.time_ms.saturating_sub(self.start.0),
+            None => {
+                std::thread::sleep_ms(POLL_MS as u32);
+                return Err(Error::new(ErrorKind::TimedOut, "replay is finished"));
+            },
+        };
+        let elapsed_ms = (time::precise_time_ns() - self.start.1)/1000000;
+        if due_ms > elapsed_ms {
+            std::thread::sleep_ms(std::cmp::min(due_ms - elapsed_ms, POLL_MS) as u32);
+            if due_ms > elapsed_ms + POLL_MS {
+                return Err(Error::new(ErrorKind::TimedOut, "no packet due yet"));
+            }
+        }
+        Ok(pending.pop_front().unwrap().packet)
+    }
+}
+
+#[test]
+fn record_and_replay() {
+    use std::str::FromStr;
+    let path = std::env::temp_dir().join(format!("pmail-capture-{}",
+                                                 ::onionsalt::crypto::random_u32()));
+    let net = ::loopback::LoopbackNetwork::new();
+    let a_addr = SocketAddr::from_str("10.0.0.1:54321").unwrap();
+    let b_addr = SocketAddr::from_str("10.0.0.2:54321").unwrap();
+    let b = net.bind(b_addr).unwrap();
+    {
+        let a = Recording::create(net.bind(a_addr).unwrap(), &path).unwrap();
+        a.send_packet(&RawEncryptedMessage { ip: b_addr, data: [1; PACKET_LENGTH] }).unwrap();
+        b.send_packet(&RawEncryptedMessage { ip: a_addr, data: [2; PACKET_LENGTH] }).unwrap();
+        assert_eq!(a.recv_packet().unwrap().data[0], 2);
+    }
+    let records = read_capture(&path).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].direction, Direction::Sent);
+    assert_eq!(records[0].packet.ip, b_addr);
+    assert_eq!(records[1].direction, Direction::Received);
+    assert_eq!(records[1].packet.ip, b_addr);
+    assert_eq!(records[1].packet.data[0], 2);
+
+    // Only the received packet is played back.
+    let replay = Replay::open(&path).unwrap();
+    let p = replay.recv_packet().unwrap();
+    assert_eq!(p.ip, b_addr);
+    assert_eq!(p.data[7], 2);
+    assert!(::udp::is_timeout(&replay.recv_packet().unwrap_err()));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,132 @@
+//! Our sense of time.  We need two kinds of time: a monotonic clock,
+//! which we use for pacing our transmissions and for the send queue
+//! of the DHT, and wall-clock time, which we only use for the `eta`
+//! of a `RoutingInfo` since that is sent over the network.  Keeping
+//! these separate means that an NTP step or a suspend/resume does not
+//! throw our constant-rate sending into disarray.
+//!
+//! Tests can use a `FakeClock`, which only moves when told to, so that
+//! scheduling can be checked deterministically.
+
+extern crate time;
+
+use std;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use udp;
+
+pub trait Clock: Send + Sync + 'static {
+    /// Milliseconds on a monotonic clock.  These are only meaningful
+    /// when compared with other times from the same clock.
+    fn monotonic_ms(&self) -> u64;
+    /// Seconds since `udp::EPOCH` on the wall clock.  This is only
+    /// for times that are sent over the network.
+    fn epoch_time(&self) -> u32;
+    /// Sleep until `monotonic_ms()` reaches `when_ms`, waking up early
+    /// if `stop` is set.  Returns false without sleeping if we are
+    /// already past `when_ms`.
+    fn sleep_until_unless(&self, when_ms: u64, stop: &AtomicBool) -> bool;
+}
+
+/// The clock of the system we run on.
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn new() -> Arc<Clock> {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn monotonic_ms(&self) -> u64 {
+        time::precise_time_ns()/1000000
+    }
+    fn epoch_time(&self) -> u32 {
+        udp::epoch_time()
+    }
+    fn sleep_until_unless(&self, when_ms: u64, stop: &AtomicBool) -> bool {
+        let mut ms = self.monotonic_ms();
+        if ms > when_ms {
+            info!("I am behind by {} seconds", (ms - when_ms) as f64 / 1000.0);
+            return false;
+        }
+        while ms < when_ms && !stop.load(Ordering::SeqCst) {
+            std::thread::sleep_ms(std::cmp::min(when_ms - ms, udp::POLL_MS) as u32);
+            ms = self.monotonic_ms();
+        }
+        true
+    }
+}
+
+/// A clock that only moves when `advance` is called.  Its monotonic
+/// time and its wall time start out the same, but the wall time may be
+/// stepped independently with `step_wall`, like NTP would do.
+pub struct FakeClock {
+    /// The monotonic time in ms, and the offset of the wall clock from
+    /// it in seconds.
+    now: Mutex<(u64, i64)>,
+    moved: Condvar,
+}
+
+impl FakeClock {
+    pub fn new(start_ms: u64) -> Arc<FakeClock> {
+        Arc::new(FakeClock { now: Mutex::new((start_ms, 0)), moved: Condvar::new() })
+    }
+    /// Move time forward by `ms`, waking anyone whose sleep is over.
+    pub fn advance(&self, ms: u64) {
+        self.now.lock().unwrap().0 += ms;
+        self.moved.notify_all();
+    }
+    /// Step the wall clock by `secs`, leaving monotonic time alone.
+    pub fn step_wall(&self, secs: i64) {
+        self.now.lock().unwrap().1 += secs;
+    }
+}
+
+impl Clock for FakeClock {
+    fn monotonic_ms(&self) -> u64 {
+        self.now.lock().unwrap().0
+    }
+    fn epoch_time(&self) -> u32 {
+        let now = self.now.lock().unwrap();
+        ((now.0/1000) as i64 + now.1) as u32
+    }
+    fn sleep_until_unless(&self, when_ms: u64, stop: &AtomicBool) -> bool {
+        let mut now = self.now.lock().unwrap();
+        if now.0 > when_ms {
+            return false;
+        }
+        // We wake up regularly in real time to check on `stop`.
+        while now.0 < when_ms && !stop.load(Ordering::SeqCst) {
+            now = self.moved.wait_timeout(now, Duration::from_millis(udp::POLL_MS)).unwrap().0;
+        }
+        true
+    }
+}
+
+#[test]
+fn fake_clock_wakes_sleepers() {
+    let clock = FakeClock::new(1000);
+    let stop = Arc::new(AtomicBool::new(false));
+    let (c, s) = (clock.clone(), stop.clone());
+    let sleeper = std::thread::spawn(move|| {
+        c.sleep_until_unless(1500, &s)
+    });
+    std::thread::sleep_ms(3*udp::POLL_MS as u32);
+    assert_eq!(clock.monotonic_ms(), 1000);
+    clock.advance(500);
+    assert!(sleeper.join().unwrap());
+    // We are now behind, so this returns at once.
+    assert!(!clock.sleep_until_unless(1200, &stop));
+}
+
+#[test]
+fn fake_clock_wall_steps() {
+    let clock = FakeClock::new(10000);
+    assert_eq!(clock.epoch_time(), 10);
+    clock.step_wall(-5);
+    assert_eq!(clock.epoch_time(), 5);
+    assert_eq!(clock.monotonic_ms(), 10000);
+}
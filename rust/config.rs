@@ -0,0 +1,188 @@
+//! This module holds the knobs that determine how a node runs.
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use flood::FloodLimits;
+use onionsalt::{crypto, ROUTE_COUNT};
+use udp;
+use work;
+
+/// How fast a node talks.  Faster settings make for quicker round
+/// trips, at the cost of more traffic and of less cover for our
+/// users, since there is less time for packets to get mixed up with
+/// one another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    /// We send exactly one packet every `send_period_ms`.
+    pub send_period_ms: u64,
+    /// Each hop of a user message is delayed by a send period plus a
+    /// random amount up to this.
+    pub message_delay_spread_ms: u64,
+    /// The same, for each hop of a greeting.
+    pub greeting_delay_spread_ms: u64,
+    /// How often a client asks its rendezvous nodes for messages.
+    pub pickup_period_ms: u64,
+    /// How long we wait for a message to be acknowledged before we
+    /// send it again.
+    pub retry_period_ms: u64,
+    /// The number of hops in a route is chosen at random from
+    /// `min_hops` to `max_hops`, inclusive.
+    pub min_hops: usize,
+    pub max_hops: usize,
+}
+
+impl Timing {
+    /// The timing profile with the given name, if there is one:
+    ///
+    /// - `"default"` is what the real pmail network uses.
+    /// - `"lan-test"` is for test networks, where we want round trips
+    ///   in seconds rather than minutes, and do not care about cover.
+    /// - `"paranoid"` sends less often, waits longer and takes longer
+    ///   routes, for more cover at the cost of speed.
+    pub fn profile(name: &str) -> Option<Timing> {
+        match name {
+            "default" => Some(Timing {
+                send_period_ms: 10*1000,
+                message_delay_spread_ms: 600,
+                greeting_delay_spread_ms: 60*1000,
+                pickup_period_ms: 30*1000,
+                retry_period_ms: 60*1000,
+                min_hops: 3,
+                max_hops: ROUTE_COUNT,
+            }),
+            "lan-test" => Some(Timing {
+                send_period_ms: 100,
+                message_delay_spread_ms: 100,
+                greeting_delay_spread_ms: 500,
+                pickup_period_ms: 1000,
+                retry_period_ms: 5*1000,
+                min_hops: 3,
+                max_hops: 3,
+            }),
+            "paranoid" => Some(Timing {
+                send_period_ms: 30*1000,
+                message_delay_spread_ms: 30*1000,
+                greeting_delay_spread_ms: 3*60*1000,
+                pickup_period_ms: 2*60*1000,
+                retry_period_ms: 10*60*1000,
+                min_hops: ROUTE_COUNT - 1,
+                max_hops: ROUTE_COUNT,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Make sure these settings make sense.  A route has at least two
+    /// hops, so that no single relay knows both ends of it, and no
+    /// more than fit in an onion.
+    pub fn check(&self) -> Result<(), Error> {
+        if 2 <= self.min_hops && self.min_hops <= self.max_hops && self.max_hops <= ROUTE_COUNT {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidInput,
+                           format!("bad hop count range {} to {}, which should be within 2 to {}",
+                                   self.min_hops, self.max_hops, ROUTE_COUNT)))
+        }
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing::profile("default").unwrap()
+    }
+}
+
+/// The configuration of a single node.  `NodeConfig::default()` gives
+/// the settings used on the real pmail network.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    /// The address to bind to.  If this is `None`, we listen on both
+    /// `::` and `0.0.0.0`.
+    pub bind_address: Option<IpAddr>,
+    /// The port to bind to.
+    pub port: u16,
+    /// If we are unable to bind to `port`, may we quietly use a random
+    /// port instead?  This is fine for a client, which learns its own
+    /// address from the relays, but a relay that others need to find
+    /// should fail instead.
+    pub allow_port_fallback: bool,
+    /// How fast we are willing to receive packets.
+    pub flood_limits: FloodLimits,
+    /// How fast we send them.
+    pub timing: Timing,
+    /// How many bits of work we put into our own routing keys, see
+    /// `work`.
+    pub key_difficulty: u32,
+    /// How many bits of work another node's routing key needs before
+    /// we will route through it.  This is a property of the network,
+    /// so every node on it should use the same value, and it should
+    /// be no more than the `key_difficulty` they make their keys with.
+    pub required_key_difficulty: u32,
+    /// Whether each hop of a route must be on a different network
+    /// (IPv4 /16 or IPv6 /48), so that nobody can own a whole route
+    /// just by running lots of relays in one place.  Test networks
+    /// where every node has a similar address will want to turn this
+    /// off.
+    pub distinct_networks: bool,
+    /// Whether the routes for our messages favour relays that have
+    /// been answering reliably and quickly (see `quality`), rather
+    /// than treating all live relays alike.  This is off by default
+    /// until we have more experience of how well it works.
+    pub route_by_quality: bool,
+    /// Groups of relays that are known to be run by the same people,
+    /// no two of which will be put in one route.
+    pub families: Vec<Vec<crypto::PublicKey>>,
+    /// How often we replace our routing key, if ever.  Relays listed
+    /// as bootstrap relays keep theirs, since everyone knows them by
+    /// it.
+    pub key_lifetime_secs: Option<u32>,
+    /// How long after replacing our routing key we still accept
+    /// packets for the old one, and tell our peers about the new one.
+    pub key_grace_secs: u32,
+    /// After this many greetings in a row sent by udp to a peer go
+    /// unanswered, we try reaching it over tcp instead.  `None` means
+    /// we never fall back to tcp.
+    pub tcp_fallback_after: Option<u32>,
+    /// Peers that we always reach over tcp.
+    pub tcp_peers: Vec<SocketAddr>,
+    /// If set, every packet we send and receive is recorded to this
+    /// file.  See the `capture` module.
+    pub capture: Option<PathBuf>,
+    /// If set, we do not touch the network at all, but instead play
+    /// back the packets received in this capture file.
+    pub replay: Option<PathBuf>,
+    /// A file listing the relays to bootstrap from, one address and
+    /// hex public key per line (see `dht::read_bootstrap`).  This is
+    /// how you run a private pmail network.  If it is `None`, we look
+    /// for a `bootstrap` file in the pmail directory, and failing that
+    /// we use the relays of the real network.  A bootstrap file that
+    /// is unusable is an error, rather than a reason to join the real
+    /// network after all.
+    pub bootstrap: Option<PathBuf>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> NodeConfig {
+        NodeConfig {
+            bind_address: None,
+            port: udp::PORT,
+            allow_port_fallback: false,
+            flood_limits: FloodLimits::default(),
+            timing: Timing::default(),
+            key_difficulty: work::DEFAULT_KEY_DIFFICULTY,
+            required_key_difficulty: work::DEFAULT_REQUIRED_KEY_DIFFICULTY,
+            distinct_networks: true,
+            route_by_quality: false,
+            families: Vec::new(),
+            key_lifetime_secs: Some(30*24*60*60),
+            key_grace_secs: 24*60*60,
+            tcp_fallback_after: Some(3),
+            tcp_peers: Vec::new(),
+            capture: None,
+            replay: None,
+            bootstrap: None,
+        }
+    }
+}
@@ -12,30 +12,80 @@ use std::fmt;
 use std::io::Error;
 use std;
 use super::udp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::FromStr;
 use std::sync::mpsc::{ Receiver, Sender, channel,
-                       SyncSender, sync_channel, };
+                       SyncSender, sync_channel, TryRecvError, };
 use std::sync::{Arc,Mutex};
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
 
 use message;
+use clock::{Clock, SystemClock};
+use config::{NodeConfig, Timing};
+use flood::FloodCounters;
+use expiring::ExpiringMap;
+use scheduler::{Scheduler, Priority};
+use routing;
+use routing::{RoutingTable, Insertion};
+use quality;
+use quality::Quality;
+use tcp::Reachability;
+use work;
 
 const REPORT_WHOAMIS: bool = false;
 
-fn key_distance(a: &crypto::PublicKey, b: &crypto::PublicKey) -> u64 {
-    let mut out = 0;
-    for i in 0..8 {
-        out += ((a.0[i] ^ b.0[i]) as u64) << (i*8);
-    }
-    out
-}
+/// How many rendezvous nodes hold each message.  Any one of them
+/// might restart, or might not be the node that the recipient thinks
+/// is closest, so we keep copies on several.
+pub const RENDEZVOUS_REPLICAS: usize = 3;
 
 pub trait MyBytes<T> {
     fn bytes(&self, &mut T);
     fn from_bytes(&T) -> Self;
 }
 
-impl MyBytes<[u8; 18]> for SocketAddr {
+/// What was wrong with some bytes that we could not decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The tag byte does not name any kind of message we know.
+    UnknownTag(u8),
+    /// Flag bits that we do not understand are set.
+    UnknownFlags(u8),
+    /// An IPv4 address followed by junk where the zeros should be.
+    BadAddress,
+    /// A string that is not UTF-8, or has junk past its length.
+    BadString,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DecodeError::UnknownTag(t) => write!(f, "unknown tag {:?}", t as char),
+            DecodeError::UnknownFlags(x) => write!(f, "unknown flags {:#04x}", x),
+            DecodeError::BadAddress => f.write_str("bad address"),
+            DecodeError::BadString => f.write_str("bad string"),
+        }
+    }
+}
+
+impl std::convert::From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Error {
+        Error::new(std::io::ErrorKind::InvalidData, format!("{}", e))
+    }
+}
+
+/// Like `MyBytes`, but for types that are read off the wire, where
+/// not every sequence of bytes is a valid encoding.  Anything we
+/// receive may be garbage (or written by someone who wants us to
+/// crash), so `decode` tells us what was wrong rather than
+/// panicking or making something up.
+pub trait WireBytes<T>: Sized {
+    fn bytes(&self, &mut T);
+    fn decode(&T) -> Result<Self, DecodeError>;
+}
+
+impl WireBytes<[u8; 18]> for SocketAddr {
     fn bytes(&self, out: &mut[u8; 18]) {
         match *self {
             SocketAddr::V6(sa) => {
@@ -68,20 +118,23 @@ impl MyBytes<[u8; 18]> for SocketAddr {
             },
         }
     }
-    fn from_bytes(inp: &[u8; 18]) -> SocketAddr {
+    fn decode(inp: &[u8; 18]) -> Result<SocketAddr, DecodeError> {
         if inp[0] == 0 && inp[1] == 0 {
-            SocketAddr::V4(SocketAddrV4::new(
+            if inp[8..].iter().any(|&b| b != 0) {
+                return Err(DecodeError::BadAddress);
+            }
+            Ok(SocketAddr::V4(SocketAddrV4::new(
                 Ipv4Addr::new(inp[4], inp[5], inp[6], inp[7]),
-                u16::from_bytes(array_ref![inp,2,2])))
+                u16::from_bytes(array_ref![inp,2,2]))))
         } else {
             let mut addr = [0; 8];
             for i in 0..8 {
                 addr[i] = u16::from_bytes(array_ref![inp,2 + 2*i, 2]);
             }
-            SocketAddr::V6(SocketAddrV6::new(
+            Ok(SocketAddr::V6(SocketAddrV6::new(
                 Ipv6Addr::new(addr[0],addr[1],addr[2],addr[3],
                               addr[4],addr[5],addr[6],addr[7]),
-                u16::from_bytes(array_ref![inp,0,2]), 0, 0))
+                u16::from_bytes(array_ref![inp,0,2]), 0, 0)))
         }
     }
 }
@@ -141,21 +194,24 @@ impl MyBytes<[u8; 2]> for u16 {
     }
 }
 
-impl MyBytes<[u8; ROUTING_LENGTH]> for RoutingInfo {
+impl WireBytes<[u8; ROUTING_LENGTH]> for RoutingInfo {
     fn bytes(&self, out: &mut[u8; ROUTING_LENGTH]) {
         let (flags,addr,eta,_) = mut_array_refs!(out, 1, 18, 4,1);
         flags[0] = self.is_for_me as u8 + ((self.who_am_i as u8) << 1);
         self.ip.bytes(addr);
         self.eta.bytes(eta);
     }
-    fn from_bytes(inp: &[u8; ROUTING_LENGTH]) -> RoutingInfo {
+    fn decode(inp: &[u8; ROUTING_LENGTH]) -> Result<RoutingInfo, DecodeError> {
         let (flags,addr,eta,_) = array_refs!(inp, 1, 18, 4,1);
-        RoutingInfo {
-            ip: SocketAddr::from_bytes(addr),
+        if flags[0] & !3 != 0 {
+            return Err(DecodeError::UnknownFlags(flags[0]));
+        }
+        Ok(RoutingInfo {
+            ip: try!(SocketAddr::decode(addr)),
             eta: u32::from_bytes(eta),
             is_for_me: flags[0] & 1 == 1,
             who_am_i: flags[0] & 2 == 2,
-        }
+        })
     }
 }
 
@@ -173,6 +229,12 @@ pub const USER_MESSAGE_LENGTH: usize = 511;
 /// that can be encrypted and authenticated to send to some receiver.
 pub const DECRYPTED_USER_MESSAGE_LENGTH: usize = USER_MESSAGE_LENGTH - 96;
 
+/// The first byte of the (otherwise empty) contents of a `PickUp`
+/// from a client that understands `Delivery`.  Older clients send all
+/// zeros, and get each message as a `ForwardPlease` instead, which is
+/// all they know how to read.
+pub const PICKUP_WANTS_DELIVERY: u8 = 1;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct RoutingGift {
     pub addr: SocketAddr,
@@ -196,36 +258,36 @@ impl MyBytes<[u8; 32]> for message::Id {
     }
 }
 
-impl MyBytes<[u8; 18+32]> for RoutingGift {
+impl WireBytes<[u8; 18+32]> for RoutingGift {
     fn bytes(&self, out: &mut[u8; 18+32]) {
         self.addr.bytes(array_mut_ref![out,0,18]);
         self.key.bytes(array_mut_ref![out,18,32]);
     }
-    fn from_bytes(inp: &[u8; 18+32]) -> RoutingGift {
-        RoutingGift {
-            addr: SocketAddr::from_bytes(array_ref![inp,0,18]),
+    fn decode(inp: &[u8; 18+32]) -> Result<RoutingGift, DecodeError> {
+        Ok(RoutingGift {
+            addr: try!(SocketAddr::decode(array_ref![inp,0,18])),
             key: crypto::PublicKey::from_bytes(array_ref![inp,18,32]),
-        }
+        })
     }
 }
 
 type RoutingGifts = [RoutingGift; NUM_IN_RESPONSE];
 
-impl MyBytes<[u8; (18+32)*NUM_IN_RESPONSE]> for [RoutingGift; NUM_IN_RESPONSE] {
+impl WireBytes<[u8; (18+32)*NUM_IN_RESPONSE]> for [RoutingGift; NUM_IN_RESPONSE] {
     fn bytes(&self, out: &mut[u8; (18+32)*NUM_IN_RESPONSE]) {
         for i in 0 .. NUM_IN_RESPONSE {
             self[i].bytes(array_mut_ref![out,i*(18+32),18+32]);
         }
     }
-    fn from_bytes(inp: &[u8; (18+32)*NUM_IN_RESPONSE]) -> [RoutingGift; NUM_IN_RESPONSE] {
+    fn decode(inp: &[u8; (18+32)*NUM_IN_RESPONSE]) -> Result<RoutingGifts, DecodeError> {
         let (g0,g1,g2,g3,g4,g5,g6,g7,g8,g9) = array_refs!(inp,
                                                           50, 50, 50, 50, 50,
                                                           50, 50, 50, 50, 50);
-        [RoutingGift::from_bytes(g0), RoutingGift::from_bytes(g1),
-         RoutingGift::from_bytes(g2), RoutingGift::from_bytes(g3),
-         RoutingGift::from_bytes(g4), RoutingGift::from_bytes(g5),
-         RoutingGift::from_bytes(g6), RoutingGift::from_bytes(g7),
-         RoutingGift::from_bytes(g8), RoutingGift::from_bytes(g9)]
+        Ok([try!(RoutingGift::decode(g0)), try!(RoutingGift::decode(g1)),
+            try!(RoutingGift::decode(g2)), try!(RoutingGift::decode(g3)),
+            try!(RoutingGift::decode(g4)), try!(RoutingGift::decode(g5)),
+            try!(RoutingGift::decode(g6)), try!(RoutingGift::decode(g7)),
+            try!(RoutingGift::decode(g8)), try!(RoutingGift::decode(g9))])
     }
 }
 
@@ -240,9 +302,28 @@ pub enum Message {
         destination: crypto::PublicKey,
         message: [u8; USER_MESSAGE_LENGTH],
     },
+    /// A request to a relay to introduce us to the node with this key,
+    /// so that we can punch holes through our NATs.
+    PunchPlease(crypto::PublicKey),
+    /// The address at which a relay has seen the node we are to punch
+    /// through to.  This is sent both in response to `PunchPlease`,
+    /// and to the node whose key was asked for.
+    PunchAt(RoutingGift),
+    /// A message that a relay held for us, sent in response to our
+    /// `PickUp`, along with how many more it is still holding.
+    Delivery {
+        remaining: u16,
+        message: [u8; USER_MESSAGE_LENGTH],
+    },
+    /// Sent from our old key, to say that we are now known by
+    /// `new_key`.  See `handover_proof`.
+    Handover {
+        new_key: crypto::PublicKey,
+        proof: [u8; 48],
+    },
 }
 
-impl MyBytes<[u8; PAYLOAD_LENGTH]> for Message {
+impl WireBytes<[u8; PAYLOAD_LENGTH]> for Message {
     fn bytes(&self, out: &mut[u8; PAYLOAD_LENGTH]) {
         match *self {
             Message::Greetings(gifts) => {
@@ -264,12 +345,30 @@ impl MyBytes<[u8; PAYLOAD_LENGTH]> for Message {
                 destination.bytes(d);
                 *m = message;
             },
+            Message::PunchPlease(target) => {
+                out[0] = b'h';
+                target.bytes(array_mut_ref![out,1,32]);
+            },
+            Message::PunchAt(peer) => {
+                out[0] = b'a';
+                peer.bytes(array_mut_ref![out,1,50]);
+            },
+            Message::Delivery { remaining, message } => {
+                out[0] = b'd';
+                remaining.bytes(array_mut_ref![out,1,2]);
+                *array_mut_ref![out,3,511] = message;
+            },
+            Message::Handover { new_key, proof } => {
+                out[0] = b'k';
+                new_key.bytes(array_mut_ref![out,1,32]);
+                *array_mut_ref![out,33,48] = proof;
+            },
         }
     }
-    fn from_bytes(inp: &[u8; PAYLOAD_LENGTH]) -> Message {
-        match inp[0] {
-            b'g' => Message::Greetings(RoutingGifts::from_bytes(array_ref![inp,1,500])),
-            b'r' => Message::Response(RoutingGifts::from_bytes(array_ref![inp,1,500])),
+    fn decode(inp: &[u8; PAYLOAD_LENGTH]) -> Result<Message, DecodeError> {
+        Ok(match inp[0] {
+            b'g' => Message::Greetings(try!(RoutingGifts::decode(array_ref![inp,1,500]))),
+            b'r' => Message::Response(try!(RoutingGifts::decode(array_ref![inp,1,500]))),
             b'p' => {
                 let (_,d,m) = array_refs![inp,1,32,511];
                 let destination = crypto::PublicKey::from_bytes(d);
@@ -280,8 +379,18 @@ impl MyBytes<[u8; PAYLOAD_LENGTH]> for Message {
                 let destination = crypto::PublicKey::from_bytes(d);
                 Message::ForwardPlease{ destination: destination, message: *m }
             },
-            _ => unimplemented!(),
-        }
+            b'h' => Message::PunchPlease(crypto::PublicKey::from_bytes(array_ref![inp,1,32])),
+            b'a' => Message::PunchAt(try!(RoutingGift::decode(array_ref![inp,1,50]))),
+            b'd' => Message::Delivery {
+                remaining: u16::from_bytes(array_ref![inp,1,2]),
+                message: *array_ref![inp,3,511],
+            },
+            b'k' => Message::Handover {
+                new_key: crypto::PublicKey::from_bytes(array_ref![inp,1,32]),
+                proof: *array_ref![inp,33,48],
+            },
+            t => return Err(DecodeError::UnknownTag(t)),
+        })
     }
 }
 
@@ -292,8 +401,11 @@ pub struct EncryptedMessage {
 
 
 impl RoutingInfo {
-    pub fn new(saddr: SocketAddr, delay_time: u32) -> RoutingInfo {
-        let eta = (udp::now_ms()/1000+1) as u32 + delay_time;
+    /// Routing info for a packet that should arrive at `saddr`
+    /// `delay_time` seconds after `now`, which is the wall-clock
+    /// `epoch_time`.
+    pub fn new(saddr: SocketAddr, now: u32, delay_time: u32) -> RoutingInfo {
+        let eta = now + 1 + delay_time;
         RoutingInfo {
             ip: saddr,
             eta: eta,
@@ -304,18 +416,31 @@ impl RoutingInfo {
 }
 
 pub fn read_keypair(name: &std::path::Path) -> Result<crypto::KeyPair, Error> {
+    read_routing_keypair(name).map(|(kp, _)| kp)
+}
+
+/// Read a routing key, along with the `epoch_time` at which we
+/// started using it.  That comes after the key itself in the file, and
+/// is missing from files written before we kept track of it.
+fn read_routing_keypair(name: &std::path::Path) -> Result<(crypto::KeyPair, Option<u32>), Error> {
     use std::io::Read;
 
     let mut f = try!(std::fs::File::open(name));
     let mut data = Vec::new();
     try!(f.read_to_end(&mut data));
-    if data.len() != 64 {
+    if data.len() != 64 && data.len() != 68 {
         return Err(Error::new(std::io::ErrorKind::Other, "oh no!"));
     }
-    Ok(crypto::KeyPair {
+    let kp = crypto::KeyPair {
         public: crypto::PublicKey(*array_ref![data, 0, 32]),
         secret: crypto::SecretKey(*array_ref![data, 32, 32]),
-    })
+    };
+    let created = if data.len() == 68 {
+        Some(u32::from_bytes(array_ref![data, 64, 4]))
+    } else {
+        None
+    };
+    Ok((kp, created))
 }
 
 /// This is just a crude guess as to the hostname.  I didn't put much
@@ -336,8 +461,6 @@ pub fn gethostname() -> Result<String, Error> {
 
 pub fn read_or_generate_keypair(orig_name: std::path::PathBuf)
                                 -> Result<crypto::KeyPair, Error> {
-    use std::io::Write;
-
     let name = orig_name.as_path();
     match read_keypair(name) {
         Ok(kp) => {
@@ -346,21 +469,165 @@ pub fn read_or_generate_keypair(orig_name: std::path::PathBuf)
         },
         _ => {
             let kp = crypto::box_keypair();
-            let mut f = try!(std::fs::File::create(name));
-            let mut data = [0; 64];
-            *array_mut_ref![data, 0, 32] = kp.public.0;
-            *array_mut_ref![data, 32, 32] = kp.secret.0;
-            try!(f.write_all(&data));
-            info!("Created new key!  [");
-            for i in 0..32 {
-                info!("{}, ", kp.public.0[i]);
-            }
-            info!("]");
+            try!(write_keypair(name, &kp));
             Ok(kp)
         }
     }
 }
 
+fn write_keypair(name: &std::path::Path, kp: &crypto::KeyPair) -> Result<(), Error> {
+    use std::io::Write;
+
+    let mut f = try!(std::fs::File::create(name));
+    let mut data = [0; 64];
+    *array_mut_ref![data, 0, 32] = kp.public.0;
+    *array_mut_ref![data, 32, 32] = kp.secret.0;
+    try!(f.write_all(&data));
+    info!("Created new key!  [");
+    for i in 0..32 {
+        info!("{}, ", kp.public.0[i]);
+    }
+    info!("]");
+    Ok(())
+}
+
+/// Write a routing key that we started using at `created`.  We write
+/// it alongside first, so that a crash can never leave us with half a
+/// key.
+fn write_routing_keypair(name: &std::path::Path, kp: &crypto::KeyPair, created: u32)
+                         -> Result<(), Error> {
+    use std::io::Write;
+
+    let mut new = name.as_os_str().to_owned();
+    new.push(".new");
+    {
+        let mut f = try!(std::fs::File::create(&new));
+        let mut data = [0; 68];
+        *array_mut_ref![data, 0, 32] = kp.public.0;
+        *array_mut_ref![data, 32, 32] = kp.secret.0;
+        created.bytes(array_mut_ref![data, 64, 4]);
+        try!(f.write_all(&data));
+        try!(f.sync_all());
+    }
+    std::fs::rename(&new, name)
+}
+
+/// Where we keep the routing key we used before the one at `name`.
+fn old_key_path(name: &std::path::Path) -> std::path::PathBuf {
+    let mut old = name.as_os_str().to_owned();
+    old.push(".old");
+    std::path::PathBuf::from(old)
+}
+
+/// Proof, for `recipient` only, that whoever holds `new` is taking
+/// over from `old`.  It is `old` itself, boxed from `new` to
+/// `recipient`, without the 16 zero bytes that every box starts with.
+/// A `Handover` is sent from the old key, so once the recipient has
+/// checked this, it knows that both keys agree.
+fn handover_proof(old: &crypto::PublicKey, new: &crypto::KeyPair,
+                  recipient: &crypto::PublicKey) -> [u8; 48] {
+    let mut plain = [0; 64];
+    *array_mut_ref![plain, 32, 32] = old.0;
+    let mut boxed = [0; 64];
+    crypto::box_up(&mut boxed, &plain, &crypto::Nonce([0; 24]), recipient, &new.secret);
+    *array_ref![boxed, 16, 48]
+}
+
+fn check_handover(old: &crypto::PublicKey, new: &crypto::PublicKey, proof: &[u8; 48],
+                  me: &crypto::KeyPair) -> bool {
+    let mut boxed = [0; 64];
+    *array_mut_ref![boxed, 16, 48] = *proof;
+    let mut plain = [0; 64];
+    crypto::box_open(&mut plain, &boxed, &crypto::Nonce([0; 24]), new, &me.secret).is_ok()
+        && *array_ref![plain, 32, 32] == old.0
+}
+
+/// Read our routing key, or make one with at least `difficulty` bits
+/// of work behind it (see `work`), along with the `epoch_time` at
+/// which we started using it.  A key we already have is used whatever
+/// work went into it, e.g. because it was made before we did any.  It
+/// gets replaced in the usual way when it is due (see
+/// `DHT::rotation_due`), so that our peers hear about the new one.  A
+/// key file that does not say when it was made is taken to be `now`,
+/// and we write that down, so that its lifetime starts only once.
+pub fn read_or_generate_routing_keypair(name: &std::path::Path, difficulty: u32, now: u32)
+                                        -> Result<(crypto::KeyPair, u32), Error> {
+    match read_routing_keypair(name) {
+        Ok((kp, Some(created))) => {
+            info!("Key {:?} {:?}", name, kp);
+            return Ok((kp, created));
+        },
+        Ok((kp, None)) => {
+            info!("Key {:?} {:?}, which we start counting the age of now", name, kp);
+            try!(write_routing_keypair(name, &kp, now));
+            return Ok((kp, now));
+        },
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    info!("Generating a routing key with {} bits of work...", difficulty);
+    let kp = work::generate_keypair(difficulty);
+    try!(write_routing_keypair(name, &kp, now));
+    Ok((kp, now))
+}
+
+/// Read a list of bootstrap relays.  Each line holds the address of a
+/// relay and its public key as 64 hex digits, separated by whitespace.
+/// Blank lines and anything after a `#` are ignored.
+pub fn read_bootstrap(name: &std::path::Path) -> Result<Vec<RoutingGift>, Error> {
+    use std::io::Read;
+
+    let bad = |n: usize, why: &str| {
+        Error::new(std::io::ErrorKind::InvalidData,
+                   format!("{:?} line {}: {}", name, n + 1, why))
+    };
+    let mut text = String::new();
+    try!(try!(std::fs::File::open(name)).read_to_string(&mut text));
+    let mut out = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() == 0 {
+            continue;
+        }
+        if words.len() != 2 {
+            return Err(bad(n, "expected an address and a key"));
+        }
+        let addr = try!(SocketAddr::from_str(words[0]).map_err(|_| bad(n, "bad address")));
+        let hex = words[1].as_bytes();
+        if hex.len() != 64 {
+            return Err(bad(n, "key should be 64 hex digits"));
+        }
+        let mut key = [0; 32];
+        for i in 0..32 {
+            let byte = std::str::from_utf8(&hex[2*i .. 2*i+2]).ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            key[i] = try!(byte.ok_or_else(|| bad(n, "key should be 64 hex digits")));
+        }
+        out.push(RoutingGift { addr: addr, key: crypto::PublicKey(key) });
+    }
+    Ok(out)
+}
+
+/// The relays we start out knowing about.  These come from
+/// `config.bootstrap` if it is set, and otherwise are the ones
+/// compiled in for the real pmail network.  Someone who set up a
+/// private network would not want us to quietly join the real one,
+/// so a bootstrap file we cannot use is an error.
+fn bootstrap_nodes(config: &NodeConfig) -> Result<Vec<RoutingGift>, Error> {
+    match config.bootstrap {
+        Some(ref name) => {
+            let gs = try!(read_bootstrap(name));
+            if gs.len() == 0 {
+                return Err(Error::new(std::io::ErrorKind::InvalidData,
+                                      format!("no relays in bootstrap file {:?}", name)));
+            }
+            Ok(gs)
+        },
+        None => Ok(vec![bingley(), knightley(), wentworth()]),
+    }
+}
+
 fn bingley() -> RoutingGift {
     let bingley_addr = SocketAddr::from_str("128.193.96.51:54321").unwrap();
     let bingley_key = crypto::PublicKey([242, 121, 245, 62, 249, 186, 221,
@@ -422,35 +689,248 @@ pub fn codename(text: &[u8]) -> String {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-struct ScheduledTransmission {
-    eta: u64,
-    msg: udp::RawEncryptedMessage,
-}
-
 #[derive(Clone, Debug)]
 struct SentMsg {
     ob: onionsalt::OnionBox,
     who_relayed: [crypto::PublicKey; ROUTE_COUNT],
+    /// If this is a `PickUp`, the destination it is for and the
+    /// rendezvous relay we asked, since the `Delivery` that comes back
+    /// has no room to say.
+    pickup_for: Option<(crypto::PublicKey, crypto::PublicKey)>,
+    /// When we sent it, on our monotonic clock.  This is set by
+    /// `expect_response`.
+    sent_ms: u64,
+    /// If this is a greeting straight to a peer, the address we sent
+    /// it to, which will answer straight back.
+    greeted: Option<SocketAddr>,
+    /// If this is a `PunchPlease`, the key we asked to be introduced
+    /// to, which is the only `PunchAt` we will take in response.
+    punch_for: Option<crypto::PublicKey>,
+    /// Whether it is a greeting or maintenance packet, whose response
+    /// comes straight back, so that it tells us how good the relays
+    /// along its route are (see `quality`).
+    rates_relays: bool,
+}
+
+/// How many messages a relay holds for any one destination.
+const MAX_HELD_PER_DESTINATION: usize = 16;
+/// How many messages a relay holds in all.  At 511 bytes apiece, this
+/// is about 10 MB.
+const MAX_HELD_MESSAGES: usize = 20000;
+/// How long a relay holds a message that nobody picks up.
+const HELD_MESSAGE_EXPIRY_MS: u64 = 24*60*60*1000;
+/// How long we wait for the response to a packet we sent, and how
+/// many such packets we keep track of at once.
+const SENT_EXPIRY_MS: u64 = 60*60*1000;
+const MAX_SENT: usize = 10000;
+/// How long a relay waits with a pickup request for a message to
+/// arrive, and how many pickups it will wait with at once.
+const PICKUP_EXPIRY_MS: u64 = 60*60*1000;
+const MAX_PICKUPS_WAITING: usize = 10000;
+
+/// The messages a relay is holding until their destinations pick them
+/// up, oldest first, along with when each expires on our monotonic
+/// clock.
+struct HeldMessages {
+    queues: HashMap<crypto::PublicKey, VecDeque<(u64, [u8; USER_MESSAGE_LENGTH])>>,
+    /// The destinations in `queues`, by how many messages we hold for
+    /// each, so that we can find the longest queue without looking
+    /// at all of them.
+    by_length: Vec<HashSet<crypto::PublicKey>>,
+    /// The number of messages in all of `queues`.
+    total: usize,
+    /// How many messages expired before they were picked up.
+    expired: usize,
+    /// How many messages we dropped to make room for others.
+    evicted: usize,
+    /// How many messages we refused to hold because their destination
+    /// already had its share.
+    refused: usize,
+}
+
+impl HeldMessages {
+    fn new() -> HeldMessages {
+        HeldMessages {
+            queues: HashMap::new(),
+            by_length: (0..MAX_HELD_PER_DESTINATION+1).map(|_| HashSet::new()).collect(),
+            total: 0,
+            expired: 0,
+            evicted: 0,
+            refused: 0,
+        }
+    }
+    fn len(&self, destination: &crypto::PublicKey) -> usize {
+        self.queues.get(destination).map(|q| q.len()).unwrap_or(0)
+    }
+    /// Keep `by_length` and `queues` in step with a queue that has
+    /// changed length from `old`.
+    fn resized(&mut self, destination: &crypto::PublicKey, old: usize) {
+        let new = self.len(destination);
+        if old != 0 {
+            self.by_length[old].remove(destination);
+        }
+        if new != 0 {
+            self.by_length[new].insert(*destination);
+        } else {
+            self.queues.remove(destination);
+        }
+    }
+    fn expire(&mut self, destination: &crypto::PublicKey, now_ms: u64) {
+        let old = self.len(destination);
+        if let Some(q) = self.queues.get_mut(destination) {
+            // Everything expires after the same time, so the oldest
+            // messages are at the front.
+            while q.front().map(|m| m.0 <= now_ms).unwrap_or(false) {
+                q.pop_front();
+                self.total -= 1;
+                self.expired += 1;
+            }
+        }
+        if old != 0 {
+            self.resized(destination, old);
+        }
+    }
+    /// Forget the expired messages for every destination.  This is
+    /// for the maintenance thread, not for every message.
+    fn expire_all(&mut self, now_ms: u64) {
+        let destinations: Vec<crypto::PublicKey> = self.queues.keys().cloned().collect();
+        for d in destinations {
+            self.expire(&d, now_ms);
+        }
+    }
+    /// Drop the oldest message from whichever destination we are
+    /// holding the most for.
+    fn evict_from_longest(&mut self) {
+        let longest = match self.by_length.iter().rev().filter_map(|s| s.iter().next()).next() {
+            Some(d) => *d,
+            None => return,
+        };
+        let old = self.len(&longest);
+        self.queues.get_mut(&longest).unwrap().pop_front();
+        self.total -= 1;
+        self.evicted += 1;
+        self.resized(&longest, old);
+    }
+    /// Hold `message` for `destination`.  Returns false if we are
+    /// already holding as much as we are willing to for it, since the
+    /// sender of a refused message will retry it when it goes
+    /// unacknowledged.  When we are full in all, we make room by
+    /// dropping the oldest message for the destination we hold the
+    /// most for, so that nobody can crowd everyone else out by
+    /// sending to a great many made-up destinations.
+    fn push(&mut self, destination: &crypto::PublicKey, message: &[u8; USER_MESSAGE_LENGTH],
+            now_ms: u64) -> bool {
+        self.expire(destination, now_ms);
+        if self.len(destination) >= MAX_HELD_PER_DESTINATION {
+            self.refused += 1;
+            return false;
+        }
+        if self.total >= MAX_HELD_MESSAGES {
+            self.evict_from_longest();
+        }
+        let old = self.len(destination);
+        self.queues.entry(*destination).or_insert_with(|| VecDeque::new())
+            .push_back((now_ms + HELD_MESSAGE_EXPIRY_MS, *message));
+        self.total += 1;
+        self.resized(destination, old);
+        true
+    }
+    /// Take the oldest message for `destination`, along with how many
+    /// are left.
+    fn pop(&mut self, destination: &crypto::PublicKey, now_ms: u64)
+           -> Option<([u8; USER_MESSAGE_LENGTH], u16)> {
+        self.expire(destination, now_ms);
+        let old = self.len(destination);
+        let out = match self.queues.get_mut(destination) {
+            None => return None,
+            Some(q) => q.pop_front().map(|m| m.1),
+        };
+        if out.is_some() {
+            self.total -= 1;
+        }
+        self.resized(destination, old);
+        self.expire(destination, now_ms);
+        out.map(|m| (m, self.len(destination) as u16))
+    }
 }
 
-const TIMER_WINDOW: usize = 60*6; // one hour?
+/// The most packets we let wait to be sent, which is an hour's worth
+/// at our usual pace.
+const MAX_SCHEDULED: usize = 60*6;
+/// The most retired keys of other nodes we keep track of.
+const MAX_RETIRED_KEYS: usize = 1000;
 const MAX_LIVENESS: u8 = (ROUTE_COUNT as u8);
 
+/// How often we save our routing table.
+const TABLE_SAVE_PERIOD_MS: u64 = 10*60*1000;
+/// Nodes we have not heard from in a week are forgotten when we load
+/// our routing table.
+const MAX_TABLE_AGE: u32 = 7*24*60*60;
+/// A node loses one point of liveness for each hour since we last
+/// heard from it, so after a long enough break it has to prove itself
+/// again before we route through it.
+const LIVENESS_DECAY_SECS: u32 = 60*60;
+const TABLE_MAGIC: &'static [u8; 8] = b"pmailrt1";
+const TABLE_RECORD_LENGTH: usize = 32 + 18 + 1 + 4;
+/// If we have heard nothing from any node in a bucket of our routing
+/// table for this long, we check up on one of them.
+const BUCKET_REFRESH_MS: u64 = 60*60*1000;
+
 struct DHT {
     newbies: HashSet<crypto::PublicKey>,
+    /// Every node in `addresses` other than ourselves is in `table`,
+    /// which limits how many we know in each part of the key space.
+    table: RoutingTable,
     addresses: HashMap<crypto::PublicKey, SocketAddr>,
     pubkeys: HashMap<SocketAddr, crypto::PublicKey>,
     liveness: HashMap<crypto::PublicKey, u8>,
     old_liveness: HashMap<crypto::PublicKey, u8>,
-    to_forward: HashMap<crypto::PublicKey, onionsalt::OpenedOnionBox>,
-    to_pickup: HashMap<crypto::PublicKey, Message>,
+    /// When we last heard from each node (or first heard of it), as
+    /// wall-clock `epoch_time`, since this is saved across restarts.
+    last_heard: HashMap<crypto::PublicKey, u32>,
+    /// Pickup requests waiting for a message to arrive for them.
+    /// Pickups waiting for a message to arrive, and whether each
+    /// understands `Delivery`.
+    to_forward: ExpiringMap<crypto::PublicKey, (onionsalt::OpenedOnionBox, bool)>,
+    to_pickup: HeldMessages,
     my_key: crypto::KeyPair,
-    timer: [Option<ScheduledTransmission>; TIMER_WINDOW],
+    scheduler: Scheduler,
     /// When we send messages, we should store their OnionBoxen in this
     /// map, so we can listen for the return...
-    onionboxen: HashMap<[u8; 32], SentMsg>,
-    send_period_ms: u64,
+    onionboxen: ExpiringMap<[u8; 32], SentMsg>,
+    timing: Timing,
+    /// How reliably and quickly each relay has passed our packets
+    /// along, see `quality`.
+    quality: HashMap<crypto::PublicKey, Quality>,
+    route_by_quality: bool,
+    /// Which peers answer our greetings, shared with the transport.
+    reach: Arc<Reachability>,
+    /// How much work a routing key needs before we will use it.
+    required_key_difficulty: u32,
+    /// Whether each hop of a route must be in a different `Subnet`.
+    distinct_networks: bool,
+    /// The family (numbered in the order they were declared) of each
+    /// node whose operator runs several, see `NodeConfig::families`.
+    families: HashMap<crypto::PublicKey, usize>,
+    /// When we started using `my_key`, as wall-clock `epoch_time`.
+    key_created: u32,
+    key_lifetime_secs: Option<u32>,
+    key_grace_secs: u32,
+    /// Keys we used to have, which we still accept packets for until
+    /// the given `epoch_time`.
+    old_keys: Vec<(crypto::KeyPair, u32)>,
+    /// The old keys of nodes that have handed over to new ones.
+    /// Others may still tell us about them for a while, and we would
+    /// rather not take them for new nodes.
+    retired: ExpiringMap<crypto::PublicKey, crypto::PublicKey>,
+    /// How many packets we have sent, and how many of those were
+    /// maintenance packets sent because nothing else was due.
+    packets_sent: usize,
+    maintenance_sent: usize,
+    clock: Arc<Clock>,
+    /// The relays we started out knowing about.  There is always at
+    /// least one.
+    bootstrap: Vec<RoutingGift>,
 }
 
 trait WithLock {
@@ -473,41 +953,412 @@ impl WithLock for Arc<Mutex<DHT>> {
 }
 
 impl DHT {
-    fn new(myself: &crypto::KeyPair, send_period_ms: u64,) -> Arc<Mutex<DHT>> {
+    fn new(myself: &crypto::KeyPair, config: &NodeConfig, clock: Arc<Clock>,
+           bootstrap: Vec<RoutingGift>) -> Arc<Mutex<DHT>> {
+        // The public ways of starting a node check these first.
+        assert!(bootstrap.len() > 0, "we need at least one relay to bootstrap from");
+        let timing = config.timing;
+        assert!(timing.check().is_ok(), "bad hop count range in {:?}", timing);
         let dht = Arc::new(Mutex::new(DHT {
             newbies: HashSet::new(),
+            table: RoutingTable::new(&myself.public),
             addresses: HashMap::new(),
             pubkeys: HashMap::new(),
-            onionboxen: HashMap::new(),
-            to_forward: HashMap::new(),
-            to_pickup: HashMap::new(),
+            onionboxen: ExpiringMap::new(SENT_EXPIRY_MS, MAX_SENT),
+            to_forward: ExpiringMap::new(PICKUP_EXPIRY_MS, MAX_PICKUPS_WAITING),
+            to_pickup: HeldMessages::new(),
             liveness: HashMap::new(),
             old_liveness: HashMap::new(),
+            last_heard: HashMap::new(),
             my_key: *myself,
-            timer: [None; TIMER_WINDOW],
-            send_period_ms: send_period_ms,
+            scheduler: Scheduler::new(MAX_SCHEDULED, timing.send_period_ms),
+            timing: timing,
+            quality: HashMap::new(),
+            route_by_quality: config.route_by_quality,
+            reach: Reachability::new(&clock),
+            required_key_difficulty: config.required_key_difficulty,
+            distinct_networks: config.distinct_networks,
+            families: config.families.iter().enumerate()
+                .flat_map(|(i, f)| f.iter().map(move |k| (*k, i))).collect(),
+            key_created: clock.epoch_time(),
+            key_lifetime_secs: config.key_lifetime_secs,
+            key_grace_secs: config.key_grace_secs,
+            old_keys: Vec::new(),
+            retired: ExpiringMap::new(config.key_grace_secs as u64*1000, MAX_RETIRED_KEYS),
+            packets_sent: 0,
+            maintenance_sent: 0,
+            clock: clock,
+            bootstrap: bootstrap,
         }));
         // initialize a the mappings!
-        dht.with_lock(|dht| { dht.accept_single_gift(&bingley()) });
-        dht.with_lock(|dht| { dht.accept_single_gift(&knightley()) });
-        dht.with_lock(|dht| { dht.accept_single_gift(&wentworth()) });
+        dht.with_lock(|dht| {
+            for g in dht.bootstrap.clone() {
+                dht.accept_single_gift(&g);
+            }
+        });
         dht
     }
     fn construct_gift(&mut self) -> [RoutingGift; NUM_IN_RESPONSE] {
-        let mut out = [bingley(); NUM_IN_RESPONSE];
+        let mut out = [self.bootstrap[0]; NUM_IN_RESPONSE];
         for i in 0..NUM_IN_RESPONSE {
-            out[i] = self.random_live_gift();
+            if let Some(g) = self.random_live_gift() {
+                out[i] = g;
+            }
         }
         out
     }
+    /// The bootstrap relays stay in our routing table whether or not
+    /// they seem to be up, since they are what we fall back on.
+    fn is_pinned(&self, k: &crypto::PublicKey) -> bool {
+        self.bootstrap.iter().any(|g| g.key == *k)
+    }
     fn accept_single_gift(&mut self, g: &RoutingGift) {
-        if !self.addresses.contains_key(&g.key) {
+        if self.retired.contains_key(&g.key) {
+            return;
+        }
+        if !self.addresses.contains_key(&g.key) && self.add_to_table(&g.key) {
             self.addresses.insert(g.key, g.addr);
             self.pubkeys.insert(g.addr, g.key);
             self.newbies.insert(g.key);
+            let now = self.clock.epoch_time();
+            self.last_heard.entry(g.key).or_insert(now);
             self.print("got gift");
         }
     }
+    /// Make room for `k` in our routing table, forgetting a dead node
+    /// if need be.  Returns false if there is no room, or if `k` does
+    /// not have enough work behind it, in which case we should not
+    /// keep track of `k` at all.
+    fn add_to_table(&mut self, k: &crypto::PublicKey) -> bool {
+        if *k == self.my_key.public {
+            return true;
+        }
+        if !work::has_enough_work(k, self.required_key_difficulty) && !self.is_pinned(k) {
+            return false;
+        }
+        let now = self.clock.monotonic_ms();
+        let insertion = {
+            let liveness = &self.liveness;
+            let bootstrap = &self.bootstrap;
+            self.table.insert(k, now, |k| {
+                liveness.contains_key(k) || bootstrap.iter().any(|g| g.key == *k)
+            })
+        };
+        match insertion {
+            Insertion::Added => true,
+            Insertion::Evicted(dead) => {
+                self.forget(&dead);
+                true
+            },
+            Insertion::Full => false,
+        }
+    }
+    /// Forget everything we know about `k`.
+    fn forget(&mut self, k: &crypto::PublicKey) {
+        if let Some(addr) = self.addresses.remove(k) {
+            if self.pubkeys.get(&addr) == Some(k) {
+                self.pubkeys.remove(&addr);
+            }
+        }
+        self.table.remove(k);
+        self.newbies.remove(k);
+        self.liveness.remove(k);
+        self.last_heard.remove(k);
+        self.quality.remove(k);
+    }
+    /// Pick up where we left off with our keys after a restart: `mine`
+    /// was made at `created`, and if we handed over to it from `old`
+    /// recently enough, we still answer to that too.
+    fn restore_keys(&mut self, created: u32, old: Option<crypto::KeyPair>) {
+        self.key_created = created;
+        let until = created.saturating_add(self.key_grace_secs);
+        if let Some(old) = old {
+            if until > self.clock.epoch_time() && old.public != self.my_key.public {
+                info!("Still answering to our old key {} for a while",
+                      codename(&old.public.0));
+                self.old_keys.push((old, until));
+            }
+        }
+    }
+    /// Keep track of `sm`, so that we recognize the response to it.
+    /// Every relay along its route is on the hook for it until the
+    /// response comes back, see `route_answered`.
+    fn expect_response(&mut self, mut sm: SentMsg) {
+        let now = self.clock.monotonic_ms();
+        sm.sent_ms = now;
+        if sm.rates_relays {
+            for k in sm.who_relayed.iter() {
+                if *k != self.my_key.public && self.addresses.contains_key(k) {
+                    self.quality.entry(*k).or_insert(Quality::default()).sent();
+                }
+            }
+        }
+        self.onionboxen.insert(sm.ob.return_magic(), sm, now);
+    }
+    /// The response to `sm` has come back, so every relay along its
+    /// route did its job.
+    fn route_answered(&mut self, sm: &SentMsg) {
+        if !sm.rates_relays {
+            return;
+        }
+        let rtt_ms = self.clock.monotonic_ms() - sm.sent_ms;
+        for k in sm.who_relayed.iter() {
+            if *k != self.my_key.public {
+                if let Some(q) = self.quality.get_mut(k) {
+                    q.answered(rtt_ms);
+                }
+            }
+        }
+    }
+    /// Forget whatever we have been keeping for too long.
+    fn expire_state(&mut self) {
+        let now = self.clock.monotonic_ms();
+        self.onionboxen.expire(now);
+        self.to_forward.expire(now);
+        self.to_pickup.expire_all(now);
+        self.retired.expire(now);
+        let epoch = self.clock.epoch_time();
+        if self.old_keys.iter().any(|&(_, until)| until <= epoch) {
+            info!("Our grace period is over, so we no longer answer to our old key");
+            self.old_keys.retain(|&(_, until)| until > epoch);
+        }
+    }
+    /// Our current key, followed by any old ones that are still in
+    /// their grace period.
+    fn keys(&self) -> Vec<crypto::KeyPair> {
+        let mut keys = vec![self.my_key];
+        keys.extend(self.old_keys.iter().map(|&(k, _)| k));
+        keys
+    }
+    /// Whether it is time to replace our key.  We wait until the grace
+    /// period of the last one is over, and a bootstrap relay never
+    /// replaces its key.
+    fn rotation_due(&self) -> bool {
+        match self.key_lifetime_secs {
+            None => false,
+            Some(lifetime) => {
+                // A key with less work than our peers require is no
+                // use to them, so it is due however new it is.
+                self.old_keys.is_empty()
+                    && (self.clock.epoch_time() >= self.key_created.saturating_add(lifetime)
+                        || !work::has_enough_work(&self.my_key.public,
+                                                  self.required_key_difficulty))
+                    && !self.bootstrap.iter().any(|g| g.key == self.my_key.public)
+            },
+        }
+    }
+    /// Start using `new` as our key.  The old one is still accepted
+    /// for `key_grace_secs`, during which we tell our peers about the
+    /// new one (see `greet`).
+    fn rotate_key(&mut self, new: &crypto::KeyPair) {
+        let old = self.my_key;
+        let now = self.clock.epoch_time();
+        self.old_keys.push((old, now + self.key_grace_secs));
+        self.my_key = *new;
+        self.key_created = now;
+        if let Some(addr) = self.addresses.remove(&old.public) {
+            self.addresses.insert(new.public, addr);
+            self.pubkeys.insert(addr, new.public);
+        }
+        if let Some(l) = self.liveness.remove(&old.public) {
+            self.liveness.insert(new.public, l);
+        }
+        if let Some(t) = self.last_heard.remove(&old.public) {
+            self.last_heard.insert(new.public, t);
+        }
+        // The table is arranged by distance from our own key, so it
+        // must be rebuilt.  We put the bootstrap relays and then the
+        // live nodes back first, so that it is the dead ones we lose if
+        // some part of it is now crowded.
+        let mut known: Vec<crypto::PublicKey> = self.addresses.keys()
+            .filter(|k| **k != new.public).cloned().collect();
+        known.sort_by_key(|k| (!self.is_pinned(k), !self.liveness.contains_key(k)));
+        self.table = RoutingTable::new(&new.public);
+        for k in known {
+            if self.addresses.contains_key(&k) && !self.add_to_table(&k) {
+                self.forget(&k);
+            }
+        }
+    }
+    /// The node we know as `old` tells us (so the message was sent
+    /// with the old key) that it is now `new`, along with a
+    /// `handover_proof` made for us.  If all is in order, we move
+    /// everything we know about it over to the new key.  Returns
+    /// whether we did.
+    fn accept_handover(&mut self, old: &crypto::PublicKey, new: &crypto::PublicKey,
+                       proof: &[u8; 48], me: &crypto::KeyPair) -> bool {
+        if self.retired.get(old) == Some(new) {
+            return true; // we have already heard
+        }
+        if !check_handover(old, new, proof, me) {
+            return false;
+        }
+        let addr = match self.addresses.get(old) {
+            Some(&addr) => addr,
+            None => return false,
+        };
+        let liveness = self.liveness.get(old).cloned();
+        let last_heard = self.last_heard.get(old).cloned();
+        let quality = self.quality.get(old).cloned();
+        // If there is no room for the new key, we had better keep the
+        // old one, which still works for a while.
+        if !self.record_address(&RoutingGift { addr: addr, key: *new }) {
+            return false;
+        }
+        self.forget(old);
+        if let Some(l) = liveness {
+            self.liveness.insert(*new, l);
+            self.newbies.remove(new);
+        }
+        if let Some(t) = last_heard {
+            self.last_heard.insert(*new, t);
+        }
+        if let Some(q) = quality {
+            self.quality.insert(*new, q);
+        }
+        let now = self.clock.monotonic_ms();
+        self.retired.insert(*old, *new, now);
+        true
+    }
+    fn relay_state(&self) -> RelayState {
+        RelayState {
+            awaiting_response: self.onionboxen.len(),
+            pickups_waiting: self.to_forward.len(),
+            messages_held: self.to_pickup.total,
+            expired: self.onionboxen.expired + self.to_forward.expired + self.to_pickup.expired,
+            evicted: self.onionboxen.evicted + self.to_forward.evicted
+                + self.to_pickup.evicted,
+            refused: self.to_pickup.refused,
+            scheduled: self.scheduler.len(),
+            overdue: self.scheduler.overdue(self.clock.monotonic_ms()),
+            dropped_sends: self.scheduler.dropped_full + self.scheduler.dropped_late,
+        }
+    }
+    /// What we know, for the curious.  The packets we have received
+    /// are counted by the listener, so those counts are left at zero.
+    fn stats(&self) -> NodeStats {
+        let mut peers: Vec<PeerStats> = self.addresses.iter()
+            .filter(|&(k, _)| *k != self.my_key.public)
+            .map(|(k, a)| PeerStats {
+                key: *k,
+                addr: *a,
+                liveness: self.liveness.get(k).cloned().unwrap_or(0),
+                newbie: self.newbies.contains(k),
+                quality: self.quality.get(k).cloned().unwrap_or(Quality::default()),
+            }).collect();
+        let me = self.my_key.public;
+        peers.sort_by(|a, b| routing::distance(&a.key, &me).cmp(&routing::distance(&b.key, &me)));
+        NodeStats {
+            key: me,
+            external_address: self.addresses.get(&me).cloned(),
+            peers: peers,
+            relay: self.relay_state(),
+            scheduled_capacity: MAX_SCHEDULED,
+            packets_sent: self.packets_sent,
+            maintenance_sent: self.maintenance_sent,
+            packets_accepted: 0,
+            packets_dropped: 0,
+        }
+    }
+    /// We have heard back from `k`, so it is as live as can be.
+    fn mark_live(&mut self, k: &crypto::PublicKey) {
+        self.liveness.insert(*k, MAX_LIVENESS);
+        self.newbies.remove(k);
+        self.last_heard.insert(*k, self.clock.epoch_time());
+        self.table.touch(k, self.clock.monotonic_ms());
+    }
+    /// The `n` nodes that should hold messages for `k`, which are the
+    /// ones whose keys are closest to `k` of all the nodes we know,
+    /// possibly including ourselves.  The closest comes first.
+    fn rendezvous(&self, k: &crypto::PublicKey, n: usize) -> Vec<crypto::PublicKey> {
+        let mut out = self.table.closest(k, n);
+        let me = self.my_key.public;
+        if self.addresses.contains_key(&me) {
+            out.push(me);
+            out.sort_by(|a, b| routing::distance(a, k).cmp(&routing::distance(b, k)));
+            out.truncate(n);
+        }
+        if out.len() == 0 {
+            out.push(self.bootstrap[0].key);
+        }
+        out
+    }
+    /// Save our routing table to `path`, so that we need not start
+    /// from scratch when we are restarted.  We write to a temporary
+    /// file first, so that a crash never leaves us with half a table.
+    fn save_table(&self, path: &std::path::Path) -> Result<(), Error> {
+        use std::io::Write;
+        let mut data = Vec::from(&TABLE_MAGIC[..]);
+        for (k, addr) in self.addresses.iter() {
+            if *k == self.my_key.public {
+                // Our own address is relearned soon enough, and may
+                // well have changed by the time we restart.
+                continue;
+            }
+            let mut record = [0; TABLE_RECORD_LENGTH];
+            {
+                let (rk, ra, rl, rh) = mut_array_refs!(&mut record, 32, 18, 1, 4);
+                k.bytes(rk);
+                addr.bytes(ra);
+                rl[0] = *self.liveness.get(k).unwrap_or(&0);
+                self.last_heard.get(k).cloned().unwrap_or(0).bytes(rh);
+            }
+            data.extend(record.iter());
+        }
+        let tmp = path.with_extension("tmp");
+        {
+            let mut f = try!(std::fs::File::create(&tmp));
+            try!(f.write_all(&data));
+        }
+        std::fs::rename(&tmp, path)
+    }
+    /// Load a routing table saved by `save_table`, forgetting nodes
+    /// that we have not heard from in too long, and reducing the
+    /// liveness of the rest according to how long it has been.
+    fn load_table(&mut self, path: &std::path::Path) -> Result<(), Error> {
+        use std::io::Read;
+        let mut data = Vec::new();
+        try!(try!(std::fs::File::open(path)).read_to_end(&mut data));
+        if data.len() < TABLE_MAGIC.len() || &data[..TABLE_MAGIC.len()] != &TABLE_MAGIC[..]
+            || (data.len() - TABLE_MAGIC.len()) % TABLE_RECORD_LENGTH != 0 {
+            return Err(Error::new(std::io::ErrorKind::InvalidData,
+                                  format!("{:?} is not a routing table", path)));
+        }
+        let now = self.clock.epoch_time();
+        let mut loaded = 0;
+        for chunk in data[TABLE_MAGIC.len()..].chunks(TABLE_RECORD_LENGTH) {
+            let (rk, ra, rl, rh) = array_refs!(array_ref![chunk, 0, TABLE_RECORD_LENGTH],
+                                               32, 18, 1, 4);
+            let k = crypto::PublicKey::from_bytes(rk);
+            let heard = u32::from_bytes(rh);
+            let age = if now > heard { now - heard } else { 0 };
+            if k == self.my_key.public || age > MAX_TABLE_AGE {
+                continue;
+            }
+            let addr = match SocketAddr::decode(ra) {
+                Ok(a) => a,
+                Err(e) => {
+                    info!("Skipping {} in {:?}: {}", codename(&k.0), path, e);
+                    continue;
+                },
+            };
+            if !self.record_address(&RoutingGift { addr: addr, key: k }) {
+                continue;
+            }
+            self.last_heard.insert(k, heard);
+            let decay = std::cmp::min(age/LIVENESS_DECAY_SECS, MAX_LIVENESS as u32) as u8;
+            if rl[0] > decay {
+                self.liveness.insert(k, rl[0] - decay);
+                self.newbies.remove(&k);
+            } else {
+                self.liveness.remove(&k);
+                self.newbies.insert(k);
+            }
+            loaded += 1;
+        }
+        info!("Loaded {} nodes from {:?}", loaded, path);
+        Ok(())
+    }
     fn accept_gift(&mut self, gift: &[RoutingGift; NUM_IN_RESPONSE]) {
         for g in gift {
             self.accept_single_gift(g);
@@ -518,25 +1369,32 @@ impl DHT {
         let mut keys = self.addresses.keys();
         *keys.nth(i).unwrap()
     }
-    fn random_live_key(&mut self) -> crypto::PublicKey {
+    /// A random live key whose address we know.  Failing that, we
+    /// make do with our own key, or a bootstrap relay's.
+    fn random_live_key(&mut self) -> Option<crypto::PublicKey> {
         let len = self.liveness.len();
-        if len == 0 {
-            if self.addresses.contains_key(&self.my_key.public) {
-                return self.my_key.public;
+        if len > 0 {
+            let i = self.random_usize() % len;
+            let k = *self.liveness.keys().nth(i).unwrap();
+            if self.addresses.contains_key(&k) {
+                return Some(k);
             }
-            return bingley().key;
         }
-        let i = self.random_usize() % len;
-        let mut keys = self.liveness.keys();
-        *keys.nth(i).unwrap()
+        if self.addresses.contains_key(&self.my_key.public) {
+            return Some(self.my_key.public);
+        }
+        self.bootstrap.iter().map(|g| g.key).find(|k| self.addresses.contains_key(k))
     }
     fn random_gift(&mut self) -> RoutingGift {
         let k = self.random_key();
         RoutingGift { key: k, addr: self.addresses[&k] }
     }
-    fn random_live_gift(&mut self) -> RoutingGift {
-        let k = self.random_live_key();
-        RoutingGift { key: k, addr: self.addresses[&k] }
+    fn random_live_gift(&mut self) -> Option<RoutingGift> {
+        let k = match self.random_live_key() {
+            Some(k) => k,
+            None => return None,
+        };
+        self.addresses.get(&k).map(|&a| RoutingGift { key: k, addr: a })
     }
     fn random_usize(&mut self) -> usize {
         self.random_u32() as usize
@@ -544,75 +1402,128 @@ impl DHT {
     fn random_u64(&mut self) -> u64 {
         self.random_u32() as u64
     }
+    /// A random number in [0, 1).  Note that `random_u32` only gives
+    /// us 24 random bits, which is plenty for choosing relays.
+    fn random_fraction(&mut self) -> f64 {
+        self.random_u32() as f64 / (1 << 24) as f64
+    }
     fn random_u32(&mut self) -> u32 {
         // The following is a really stupid way of getting a random
         // usize so that we will send to a random node each time.
         let r = crypto::random_nonce().0;
         r[0] as u32 + ((r[1] as u32)<<8) + ((r[2] as u32)<<16)
     }
-    fn pick_live_route(&mut self) -> Vec<RoutingGift> {
-        let len = self.liveness.len();
-        assert!(len > 2);
-        let mut out = Vec::new();
-        for _ in 0 .. 3 + (self.random_usize() % 4) {
-            let mut new_gift = self.random_live_gift();
-            if out.len() > 1 && (out.contains(&new_gift) || new_gift.key == self.my_key.public) {
-                // Let's not create a loop that loops back on itself.
-                return out;
-            }
-            while new_gift.key == self.my_key.public || out.contains(&new_gift) {
-                new_gift = self.random_live_gift();
-            }
-            out.push(new_gift);
+    /// How many hops the next route should have, given that there
+    /// are `available` nodes to choose from.
+    fn route_length(&mut self, available: usize) -> usize {
+        let range = self.timing.max_hops - self.timing.min_hops + 1;
+        let wanted = self.timing.min_hops + self.random_usize() % range;
+        std::cmp::min(wanted, available)
+    }
+    /// Whether `a` and `b` might well be run by the same people, in
+    /// which case they should not both be in one route, or they could
+    /// follow a message the whole way.
+    fn related(&self, a: &RoutingGift, b: &RoutingGift) -> bool {
+        if self.distinct_networks && Subnet::of(&a.addr) == Subnet::of(&b.addr) {
+            return true;
+        }
+        match (self.families.get(&a.key), self.families.get(&b.key)) {
+            (Some(fa), Some(fb)) => fa == fb,
+            _ => false,
         }
-        out
     }
-    fn pick_route(&mut self) -> Vec<RoutingGift> {
-        assert!(self.addresses.len() > 2);
-        let mut out = Vec::new();
-        for _ in 0 .. 3 + (self.random_usize() % 4) {
-            let mut new_gift = self.random_gift();
-            if out.len() > 1 && (out.contains(&new_gift) || new_gift.key == self.my_key.public) {
-                // Let's not create a loop that loops back on itself.
-                return out;
-            }
-            while new_gift.key == self.my_key.public || out.contains(&new_gift) {
-                new_gift = self.random_gift();
+    /// Pick a route at random from `candidates`, which goes through
+    /// `through` if it is given.  No two hops are `related`, so if we
+    /// know too few unrelated nodes we settle for a shorter route, but
+    /// never one shorter than `Timing::min_hops`, in which case we
+    /// give up and the caller will have to try again once we know
+    /// more nodes.  If `weighted`, each relay is picked in proportion
+    /// to its `Quality::weight`, and otherwise they are all equally
+    /// likely.
+    fn pick_route_among(&mut self, mut candidates: Vec<RoutingGift>,
+                        through: Option<RoutingGift>, weighted: bool)
+                        -> Option<Vec<RoutingGift>> {
+        let me = self.my_key.public;
+        candidates.retain(|g| g.key != me && through.map(|t| t.key != g.key).unwrap_or(true));
+        let hops = self.route_length(candidates.len() + through.iter().count());
+        let mut weights: Vec<f64> = if weighted {
+            let typical = quality::typical_rtt_ms(self.quality.values());
+            candidates.iter().map(|g| {
+                self.quality.get(&g.key).cloned().unwrap_or(Quality::default()).weight(typical)
+            }).collect()
+        } else {
+            vec![1.0; candidates.len()]
+        };
+        let mut out: Vec<RoutingGift> = through.into_iter().collect();
+        while out.len() < hops && candidates.len() > 0 {
+            let r = self.random_fraction();
+            let i = quality::pick_weighted(&weights, r);
+            weights.swap_remove(i);
+            let g = candidates.swap_remove(i);
+            if !out.iter().any(|h| self.related(h, &g)) {
+                out.push(g);
             }
-            out.push(new_gift);
         }
-        out
+        if out.len() < self.timing.min_hops {
+            return None;
+        }
+        if through.is_some() {
+            // The hop we had to go through may be anywhere along the
+            // route.
+            let i = self.random_usize() % out.len();
+            out.swap(0, i);
+        }
+        Some(out)
+    }
+    /// A route through live nodes, and through `through` if given.
+    fn pick_live_route(&mut self, through: Option<RoutingGift>) -> Option<Vec<RoutingGift>> {
+        let candidates = self.liveness.keys()
+            .filter_map(|k| self.addresses.get(k).map(|a| RoutingGift { key: *k, addr: *a }))
+            .collect();
+        let weighted = self.route_by_quality;
+        self.pick_route_among(candidates, through, weighted)
+    }
+    fn pick_route(&mut self) -> Option<Vec<RoutingGift>> {
+        let candidates = self.addresses.iter()
+            .map(|(k, a)| RoutingGift { key: *k, addr: *a }).collect();
+        // Greetings are how we find out about new relays, so here we
+        // play no favourites.
+        self.pick_route_among(candidates, None, false)
     }
     fn schedule_if_convenient(&mut self, eta: u32, msg: &udp::RawEncryptedMessage) {
-        self.schedule_internal(eta, msg, 1); // this number is an arbitrary sloppiness
+        self.schedule_internal(eta, msg, Priority::Convenient);
     }
     fn schedule(&mut self, eta: u32, msg: &udp::RawEncryptedMessage) {
-        self.schedule_internal(eta, msg, TIMER_WINDOW as u64);
-    }
-    fn schedule_internal(&mut self, eta: u32, msg: &udp::RawEncryptedMessage, steadfastness: u64) {
-        let eta = eta as u64 * 1000; // convert to ms!
-        let n = udp::now_ms();
-        let mut idx = (n+1)/self.send_period_ms + 1;
-        if (eta as i64 - n as i64)/self.send_period_ms as i64 > 0 {
-            idx += self.random_u64() % ((eta-n)/self.send_period_ms);
-        }
-        for offset in 0..steadfastness {
-            if self.timer[(idx + offset) as usize % TIMER_WINDOW].is_none() {
-                idx += offset;
-                self.timer[idx as usize % TIMER_WINDOW] =
-                    Some(ScheduledTransmission { eta: eta,
-                                                 msg: *msg});
-                return;
-            }
+        self.schedule_internal(eta, msg, Priority::Steadfast);
+    }
+    fn schedule_internal(&mut self, eta: u32, msg: &udp::RawEncryptedMessage, priority: Priority) {
+        // The eta came over the network, so it is in wall-clock time.
+        // We turn it into a delay, and schedule on our monotonic
+        // clock from there.
+        let delay_ms = (eta as i64 - self.clock.epoch_time() as i64)*1000;
+        // We never send before the next tick, and packets that may
+        // wait longer are spread randomly over the time they have.
+        let period = self.timing.send_period_ms;
+        let mut due = self.clock.monotonic_ms() + period;
+        if delay_ms/period as i64 > 0 {
+            due += period*(self.random_u64() % (delay_ms as u64/period));
         }
+        self.scheduler.schedule(due, priority, msg);
     }
-    fn msg(&mut self, idx: usize) -> udp::RawEncryptedMessage {
-        match self.timer[idx % TIMER_WINDOW] {
-            Some(sch) => {
-                self.timer[idx % TIMER_WINDOW] = None;
-                sch.msg
-            },
+    /// Take all of the transmissions that are scheduled, in the order
+    /// in which they were due to be sent.
+    fn drain_scheduled(&mut self) -> Vec<udp::RawEncryptedMessage> {
+        self.scheduler.drain()
+    }
+    /// The packet to send this tick: the earliest one that is due, or
+    /// else a maintenance packet.
+    fn msg(&mut self) -> udp::RawEncryptedMessage {
+        let now = self.clock.monotonic_ms();
+        self.packets_sent += 1;
+        match self.scheduler.pop(now) {
+            Some(msg) => msg,
             None => {
+                self.maintenance_sent += 1;
                 let (addr,sm) = self.maintenance();
                 let msg = udp::RawEncryptedMessage {
                     ip: addr,
@@ -637,21 +1548,40 @@ impl DHT {
                 self.print("changed liveness");
                 // The following enables us to easily check for a response
                 // to this message.
-                self.onionboxen.insert(sm.ob.return_magic(), sm);
+                self.expect_response(sm);
                 msg
             }
         }
     }
-    fn greet(&mut self) -> (SocketAddr, SentMsg) {
-        let mut payload = [0; PAYLOAD_LENGTH];
-        Message::Greetings(self.construct_gift()).bytes(&mut payload);
-
-        let route = self.pick_route();
+    /// A greeting sent along a random route, or `None` if we do not
+    /// yet know enough nodes to make one.
+    fn greet(&mut self) -> Option<(SocketAddr, SentMsg)> {
+        let route = match self.pick_route() {
+            Some(r) => r,
+            None => return None,
+        };
         let mut recipient = self.random_usize() % route.len();
         // avoid sending greetings to myself!
         while route[recipient].key == self.my_key.public {
             recipient = self.random_usize() % route.len();
         }
+
+        // While we are handing over to a new key, half of our
+        // greetings come from the old key, and introduce the new one.
+        let mut payload = [0; PAYLOAD_LENGTH];
+        let from = match self.old_keys.last().cloned() {
+            Some((old, _)) if self.random_usize() % 2 == 0 => {
+                Message::Handover {
+                    new_key: self.my_key.public,
+                    proof: handover_proof(&old.public, &self.my_key, &route[recipient].key),
+                }.bytes(&mut payload);
+                old
+            },
+            _ => {
+                Message::Greetings(self.construct_gift()).bytes(&mut payload);
+                self.my_key
+            },
+        };
         // info!("Sending a nice greeting loop of length {}", route.len());
         let mut keys_and_routes = Vec::new();
         let mut delay_time = 0;
@@ -663,18 +1593,19 @@ impl DHT {
                 route[i+1].addr
             } else {
                 // the following delivers the response back to us, or
-                // to bingley (uselessly) if we do not yet know our
-                // own address.
-                *self.addresses.get(&self.my_key.public).unwrap_or(&bingley().addr)
+                // to a bootstrap relay (uselessly) if we do not yet
+                // know our own address.
+                *self.addresses.get(&self.my_key.public).unwrap_or(&self.bootstrap[0].addr)
             };
             // if i == recipient {
             //     info!(" => {}", route[i].addr);
             // } else {
             //     info!("    {}", route[i].addr);
             // }
-            let delay_ms = self.send_period_ms + self.random_u64() % (6*self.send_period_ms);
+            let spread_ms = std::cmp::max(self.timing.greeting_delay_spread_ms, 1);
+            let delay_ms = self.timing.send_period_ms + self.random_u64() % spread_ms;
             delay_time += ((delay_ms+999)/1000) as u32;
-            let mut ri = RoutingInfo::new(next_addr, delay_time);
+            let mut ri = RoutingInfo::new(next_addr, self.clock.epoch_time(), delay_time);
             ri.is_for_me = i == recipient;
             ri.who_am_i = false;
             ri.bytes(&mut k_and_r.1);
@@ -682,29 +1613,28 @@ impl DHT {
         }
 
         let mut ob = onionbox(&keys_and_routes, recipient).unwrap();
-        ob.add_payload(self.my_key, &payload);
+        ob.add_payload(from, &payload);
         // info!("greeting: {} -> ... -> {}",
         //       codename(&ob.packet()), codename(&ob.return_magic()));
-        (route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed })
+        Some((route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed, pickup_for: None,
+                                       sent_ms: 0, greeted: None, punch_for: None,
+                                       rates_relays: true }))
     }
     fn send_ciphertext(&mut self, rendezvous: crypto::PublicKey,
-                       ciphertext: [u8;PAYLOAD_LENGTH], total_delay_ms: u64)
+                       ciphertext: [u8;PAYLOAD_LENGTH])
                        -> Option<(SocketAddr, SentMsg)> {
         if self.liveness.len() < 3 {
             return None;
         }
-        let mut route = self.pick_live_route();
-        let mut recipient = self.random_usize() % route.len();
-        for i in 0 .. route.len() {
-            if route[i].key == rendezvous {
-                recipient = i;
-                break;
-            }
-        }
-        if route[recipient].key != rendezvous {
-            route[recipient].key = rendezvous;
-            route[recipient].addr = self.addresses[&rendezvous];
-        }
+        let through = match self.addresses.get(&rendezvous) {
+            Some(&addr) => RoutingGift { key: rendezvous, addr: addr },
+            None => return None,
+        };
+        let route = match self.pick_live_route(Some(through)) {
+            Some(r) => r,
+            None => return None,
+        };
+        let recipient = route.iter().position(|g| g.key == rendezvous).unwrap();
         // info!("Sending a nice message loop of length {}", route.len());
         let mut keys_and_routes = Vec::new();
         let mut delay_time = 0;
@@ -716,18 +1646,19 @@ impl DHT {
                 route[i+1].addr
             } else {
                 // the following delivers the response back to us, or
-                // to bingley (uselessly) if we do not yet know our
-                // own address.
-                *self.addresses.get(&self.my_key.public).unwrap_or(&bingley().addr)
+                // to a bootstrap relay (uselessly) if we do not yet
+                // know our own address.
+                *self.addresses.get(&self.my_key.public).unwrap_or(&self.bootstrap[0].addr)
             };
             // if i == recipient {
             //     info!(" => {}", route[i].addr);
             // } else {
             //     info!("    {}", route[i].addr);
             // }
-            let delay_ms = self.send_period_ms + self.random_u64() % total_delay_ms;
+            let spread_ms = std::cmp::max(self.timing.message_delay_spread_ms, 1);
+            let delay_ms = self.timing.send_period_ms + self.random_u64() % spread_ms;
             delay_time += ((delay_ms+999)/1000) as u32;
-            let mut ri = RoutingInfo::new(next_addr, delay_time);
+            let mut ri = RoutingInfo::new(next_addr, self.clock.epoch_time(), delay_time);
             ri.is_for_me = i == recipient;
             ri.who_am_i = false;
             ri.bytes(&mut k_and_r.1);
@@ -737,30 +1668,116 @@ impl DHT {
         ob.add_payload(self.my_key, &ciphertext);
         // info!("sending something: {} -> ... -> {}",
         //       codename(&ob.packet()), codename(&ob.return_magic()));
-        Some((route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed }))
+        Some((route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed, pickup_for: None,
+                                       sent_ms: 0, greeted: None, punch_for: None,
+                                       rates_relays: false }))
     }
     fn whoami(&mut self, who: &RoutingGift) -> (SocketAddr, SentMsg) {
         let mut hello_payload = [0; PAYLOAD_LENGTH];
         Message::Greetings([*who; NUM_IN_RESPONSE]).bytes(&mut hello_payload);
-
+        let (addr, mut sm) = self.one_hop(who, &hello_payload, true);
+        // This tells the transport whether udp gets through to `who`.
+        sm.greeted = Some(addr);
+        sm.rates_relays = true;
+        self.reach.greeted(addr);
+        if REPORT_WHOAMIS {
+            info!("whoami: {} -> {} -> {}\n",
+                  codename(&sm.ob.packet()), who.addr,
+                  codename(&sm.ob.return_magic()));
+        }
+        (addr, sm)
+    }
+    /// Construct a packet carrying `payload` directly to `who`, which
+    /// will respond straight back to us.
+    fn one_hop(&mut self, who: &RoutingGift, payload: &[u8; PAYLOAD_LENGTH],
+               who_am_i: bool) -> (SocketAddr, SentMsg) {
         let mut keys_and_routes = [(who.key, [0; ROUTING_LENGTH])];
         // Always ask for essentially no delay in responding to
         // whoami.  This prevents whoami responses from being
         // scheduled in the future, which relies on whoami responses
         // being dropped rather than delayed.
-        let mut ri = RoutingInfo::new(who.addr, 1);
+        let mut ri = RoutingInfo::new(who.addr, self.clock.epoch_time(), 1);
         ri.is_for_me = true;
-        ri.who_am_i = true;
+        ri.who_am_i = who_am_i;
         ri.bytes(&mut keys_and_routes[0].1);
 
         let mut ob = onionbox(&keys_and_routes, 0).unwrap();
-        ob.add_payload(self.my_key, &hello_payload);
-        if REPORT_WHOAMIS {
-            info!("whoami: {} -> {} -> {}\n",
-                  codename(&ob.packet()), who.addr,
-                  codename(&ob.return_magic()));
+        ob.add_payload(self.my_key, payload);
+        (who.addr, SentMsg { ob: ob, who_relayed: [self.my_key.public; ROUTE_COUNT],
+                             pickup_for: None, sent_ms: 0, greeted: None, punch_for: None,
+                             rates_relays: false })
+    }
+    /// Send `sm` out as soon as we can, and keep track of it so that
+    /// we will recognize the response.
+    fn send_soon(&mut self, addr: SocketAddr, sm: SentMsg) {
+        let msg = udp::RawEncryptedMessage { ip: addr, data: sm.ob.packet() };
+        self.expect_response(sm);
+        let now = self.clock.epoch_time();
+        self.schedule(now, &msg);
+    }
+    /// Record that `g.key` is at `g.addr`, replacing whatever address
+    /// we had for it before.  Unlike `accept_single_gift`, we trust
+    /// this address, since it was observed by a relay.
+    /// Returns false if there was no room for it in our routing table.
+    fn record_address(&mut self, g: &RoutingGift) -> bool {
+        if !self.addresses.contains_key(&g.key) && !self.add_to_table(&g.key) {
+            return false;
+        }
+        if let Some(old) = self.addresses.insert(g.key, g.addr) {
+            if old != g.addr && self.pubkeys.get(&old) == Some(&g.key) {
+                self.pubkeys.remove(&old);
+            }
+        }
+        self.pubkeys.insert(g.addr, g.key);
+        if !self.liveness.contains_key(&g.key) {
+            self.newbies.insert(g.key);
+        }
+        true
+    }
+    /// Ask the relay `via` to introduce us to `target`, so that we can
+    /// both start sending at the same time, and open up our NATs for
+    /// one another.  Returns false if we do not know where `via` is.
+    fn request_punch(&mut self, target: &crypto::PublicKey, via: &crypto::PublicKey) -> bool {
+        let relay = match self.addresses.get(via) {
+            Some(&addr) => RoutingGift { addr: addr, key: *via },
+            None => return false,
+        };
+        let mut payload = [0; PAYLOAD_LENGTH];
+        Message::PunchPlease(*target).bytes(&mut payload);
+        let (addr, mut sm) = self.one_hop(&relay, &payload, false);
+        sm.punch_for = Some(*target);
+        self.send_soon(addr, sm);
+        true
+    }
+    /// Tell `to` that `peer` wants to punch through to it.  We expect
+    /// no response.
+    fn introduce(&mut self, to: &RoutingGift, peer: &RoutingGift) {
+        let mut payload = [0; PAYLOAD_LENGTH];
+        Message::PunchAt(*peer).bytes(&mut payload);
+        let (addr, sm) = self.one_hop(to, &payload, false);
+        let now = self.clock.epoch_time();
+        self.schedule(now, &udp::RawEncryptedMessage { ip: addr, data: sm.ob.packet() });
+    }
+    /// A relay has told us where `peer` is, so we greet it there.  If
+    /// it is greeting us at the same time, our NATs will let its
+    /// packets through.  Only if we `asked` for this (with
+    /// `request_punch`) do we believe the relay over whatever address
+    /// we already had for `peer`, since otherwise anyone could send
+    /// us off to the wrong address for any node we know.
+    fn punch(&mut self, peer: &RoutingGift, asked: bool) {
+        info!("Punching through to {} at {}", codename(&peer.key.0), peer.addr);
+        if asked {
+            self.record_address(peer);
+        } else {
+            self.accept_single_gift(peer);
         }
-        (who.addr, SentMsg { ob: ob, who_relayed: [self.my_key.public; ROUTE_COUNT] })
+        let (addr, mut sm) = self.whoami(peer);
+        if self.addresses.get(&peer.key) == Some(&peer.addr) {
+            // A response means the hole is open, so the peer counts
+            // as live.
+            sm.who_relayed[0] = peer.key;
+        }
+        self.send_soon(addr, sm);
     }
 
     fn maintenance(&mut self) -> (SocketAddr, SentMsg) {
@@ -768,10 +1785,26 @@ impl DHT {
         // expensive in terms of use of the network, and the most
         // safely ignored by our recipients.
         if !self.addresses.contains_key(&self.my_key.public) || self.addresses.len() < 3 || self.random_usize() % ROUTE_COUNT != 0 {
+            // We check up on parts of our routing table that have gone
+            // quiet, so that dead nodes get noticed and replaced.
+            let now = self.clock.monotonic_ms();
+            if let Some(k) = self.table.stale(now, BUCKET_REFRESH_MS) {
+                let (addr, mut sm) = self.whoami(&RoutingGift { key: k, addr: self.addresses[&k] });
+                // As with a punch, hearing back tells us it is alive,
+                // and not hearing back counts against it.
+                sm.who_relayed[0] = k;
+                return (addr, sm);
+            }
             let gift = self.random_gift();
             return self.whoami(&gift);
         }
-        self.greet()
+        match self.greet() {
+            Some(greeting) => greeting,
+            None => {
+                let gift = self.random_gift();
+                self.whoami(&gift)
+            },
+        }
     }
     fn print(&mut self, _note: &str) {
         if self.old_liveness != self.liveness {
@@ -793,311 +1826,888 @@ impl DHT {
 
 /// Start relaying messages with a static public key (i.e. one that
 /// does not change).
-pub fn start_static_node(the_dir: &std::path::PathBuf)
-                         -> Result<(SyncSender<crypto::PublicKey>,
-                                    Receiver<crypto::PublicKey>,
-                                    Sender<EncryptedMessage>,
-                                    Receiver<UserMessage>), Error> {
-    let my_key = {
+pub fn start_static_node(the_dir: &std::path::PathBuf, config: &NodeConfig)
+                         -> Result<Node, Error> {
+    // Like the key, the routing table is per host, in case several
+    // computers share a home directory.
+    let routing_file = |ext: &str| {
         let mut name = the_dir.clone();
         match gethostname() {
             Err(_) => {
-                name.push("routing.key");
+                name.push(format!("routing.{}", ext));
             },
             Ok(hostname) => {
-                name.push(format!("routing-{}.key", hostname));
+                name.push(format!("routing-{}.{}", hostname, ext));
             },
         };
-        read_or_generate_keypair(name).unwrap()
+        name
+    };
+    // A bootstrap file in our directory is used unless one was given
+    // explicitly, so that clients can join a private network too.
+    try!(config.timing.check());
+    let mut config = config.clone();
+    if config.bootstrap.is_none() && the_dir.join("bootstrap").is_file() {
+        config.bootstrap = Some(the_dir.join("bootstrap"));
+    }
+    let bootstrap = try!(bootstrap_nodes(&config));
+    let clock = SystemClock::new();
+    let (my_key, key_created) = if config.replay.is_some() {
+        // A replay must leave the real node alone, so we only read its
+        // key, and never generate, rewrite or rotate one.
+        config.key_lifetime_secs = None;
+        let (kp, created) = try!(read_routing_keypair(&routing_file("key")));
+        (kp, created.unwrap_or(clock.epoch_time()))
+    } else {
+        try!(read_or_generate_routing_keypair(&routing_file("key"),
+                                              config.key_difficulty,
+                                              clock.epoch_time()))
     };
 
-    let send_period_ms = 1000*10;
-    let dht = DHT::new(&my_key, send_period_ms);
+    let halt = udp::Halt::new();
+    let listener = try!(udp::listen(&config, config.timing.send_period_ms, &clock, &halt));
+    let table = Table {
+        path: routing_file("table"),
+        key: routing_file("key"),
+        key_created: key_created,
+        // When replaying a capture, we would rather not overwrite the
+        // table of the real node.
+        save: config.replay.is_none(),
+    };
+    Ok(start_node(my_key, &config, listener, clock, halt, bootstrap, Some(table)))
+}
 
-    let (send, get) = try!(udp::listen(send_period_ms));
+/// Start relaying messages over an arbitrary `Transport`, with the
+/// given routing key.  This is how we run nodes that are not on the
+/// real network, e.g. on a `loopback::LoopbackNetwork`.  The address
+/// settings in `config` are ignored, since the transport is already
+/// bound.  All timing is done with `clock`, which lets tests use a
+/// `clock::FakeClock`.  This fails if `config` names a bootstrap file
+/// that we cannot use, or its timing makes no sense.
+pub fn start_node_on<T: udp::Transport>(my_key: crypto::KeyPair, transport: T,
+                                        config: &NodeConfig, clock: Arc<Clock>)
+                                        -> Result<Node, Error> {
+    let bootstrap = try!(bootstrap_nodes(config));
+    start_node_among(my_key, transport, config, clock, bootstrap)
+}
 
-    {
-        // Here we set up the thread that sends out requests for
-        // routing information.  This thread should wake up no more
-        // than once every 10 seconds (until I increase the
-        // communication frequency), and should ensure that we're
-        // always ready to send *something* out.
-        let dht = dht.clone(); // a separate copy for sending
+/// Like `start_node_on`, but bootstrapping from the given relays
+/// rather than those named in `config`.  The simulator uses this to
+/// build networks that exist only in memory.  There must be at least
+/// one relay to bootstrap from.
+pub fn start_node_among<T: udp::Transport>(my_key: crypto::KeyPair, transport: T,
+                                           config: &NodeConfig, clock: Arc<Clock>,
+                                           bootstrap: Vec<RoutingGift>) -> Result<Node, Error> {
+    try!(config.timing.check());
+    if bootstrap.len() == 0 {
+        return Err(Error::new(std::io::ErrorKind::InvalidInput,
+                              "we need at least one relay to bootstrap from"));
+    }
+    let halt = udp::Halt::new();
+    let listener = udp::listen_on(transport, config.timing.send_period_ms, config.flood_limits,
+                                  &clock, &halt);
+    Ok(start_node(my_key, config, listener, clock, halt, bootstrap, None))
+}
+
+/// How much a node is keeping track of, mostly on behalf of others,
+/// and how much it has had to let go of.  All of this is bounded, so
+/// that a relay running for months does not grow without limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RelayState {
+    /// Packets we sent, whose responses we are waiting for.
+    pub awaiting_response: usize,
+    /// Pickup requests waiting for a message to arrive.
+    pub pickups_waiting: usize,
+    /// Messages held for their destinations to pick up.
+    pub messages_held: usize,
+    /// How many of the above we have forgotten for being too old.
+    pub expired: usize,
+    /// How many we have forgotten to make room for newer ones.
+    pub evicted: usize,
+    /// How many messages we refused to hold because their destination
+    /// already had its share.
+    pub refused: usize,
+    /// Packets waiting to be sent, and how many of those are already
+    /// due, i.e. how many ticks behind we are.
+    pub scheduled: usize,
+    pub overdue: usize,
+    /// Packets we gave up on sending, see `scheduler`.
+    pub dropped_sends: usize,
+}
+
+/// What a node knows about one of its peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerStats {
+    pub key: crypto::PublicKey,
+    pub addr: SocketAddr,
+    /// How many more maintenance packets through this node may go
+    /// unanswered before we stop using it.  Zero means it is not live.
+    pub liveness: u8,
+    /// Whether we have yet to hear back from it at all.
+    pub newbie: bool,
+    /// How well it has been passing our packets along.
+    pub quality: Quality,
+}
+
+/// A snapshot of what a node knows and is doing, for figuring out why
+/// messages are not getting through.
+#[derive(Clone, Debug)]
+pub struct NodeStats {
+    pub key: crypto::PublicKey,
+    /// Our address as the rest of the network sees it, once someone
+    /// has told us.
+    pub external_address: Option<SocketAddr>,
+    /// Every node in our routing table, closest to us first.
+    pub peers: Vec<PeerStats>,
+    pub relay: RelayState,
+    /// How many packets may be waiting to be sent at once.
+    pub scheduled_capacity: usize,
+    pub packets_sent: usize,
+    pub maintenance_sent: usize,
+    pub packets_accepted: usize,
+    pub packets_dropped: usize,
+}
+
+impl fmt::Display for NodeStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(writeln!(f, "node {} at {}", codename(&self.key.0),
+                      match self.external_address {
+                          Some(a) => format!("{}", a),
+                          None => "an unknown address".to_string(),
+                      }));
+        try!(writeln!(f, "sent {} packets ({} maintenance), accepted {}, dropped {}",
+                      self.packets_sent, self.maintenance_sent,
+                      self.packets_accepted, self.packets_dropped));
+        try!(writeln!(f, "scheduled {}/{} ({} overdue, {} dropped)",
+                      self.relay.scheduled, self.scheduled_capacity,
+                      self.relay.overdue, self.relay.dropped_sends));
+        try!(writeln!(f, "awaiting {} responses, {} pickups, holding {} messages",
+                      self.relay.awaiting_response, self.relay.pickups_waiting,
+                      self.relay.messages_held));
+        try!(writeln!(f, "expired {}, evicted {}, refused {}",
+                      self.relay.expired, self.relay.evicted, self.relay.refused));
+        for p in self.peers.iter() {
+            try!(write!(f, " {} -> {} [{}]{}", codename(&p.key.0), p.addr, p.liveness,
+                        if p.newbie { " N" } else { "" }));
+            if p.quality.sent > 0 {
+                try!(write!(f, " answered {}/{}", p.quality.answered, p.quality.sent));
+            }
+            if let Some(rtt) = p.quality.rtt_ms {
+                try!(write!(f, " rtt {:.1}s", rtt as f64/1000.0));
+            }
+            try!(writeln!(f, ""));
+        }
+        Ok(())
+    }
+}
+
+/// The part of the address space that one operator can easily get
+/// lots of addresses in: an IPv4 /16 or an IPv6 /48.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Subnet {
+    V4([u8; 2]),
+    V6([u16; 3]),
+}
+
+impl Subnet {
+    fn of(addr: &SocketAddr) -> Subnet {
+        let v4 = match *addr {
+            SocketAddr::V4(a) => *a.ip(),
+            SocketAddr::V6(a) => match a.ip().to_ipv4() {
+                Some(ip) => ip,
+                None => {
+                    let s = a.ip().segments();
+                    return Subnet::V6([s[0], s[1], s[2]]);
+                },
+            },
+        };
+        let o = v4.octets();
+        Subnet::V4([o[0], o[1]])
+    }
+}
+
+/// Where a node keeps its routing table and its key between runs.
+struct Table {
+    path: std::path::PathBuf,
+    key: std::path::PathBuf,
+    /// When we started using the key in `key`.
+    key_created: u32,
+    /// Whether we write to them, or only read them at startup.
+    save: bool,
+}
+
+impl Table {
+    fn save(&self, dht: &Arc<Mutex<DHT>>) {
+        if self.save {
+            if let Err(e) = dht.with_lock(|dht| { dht.save_table(&self.path) }) {
+                error!("Unable to save routing table {:?}: {}", self.path, e);
+            }
+        }
+    }
+    /// Save our new key, made at `created`, keeping the old one
+    /// alongside.
+    fn save_key(&self, kp: &crypto::KeyPair, created: u32) {
+        if self.save {
+            let old = old_key_path(&self.key);
+            if let Err(e) = std::fs::rename(&self.key, &old)
+                .and_then(|_| write_routing_keypair(&self.key, kp, created)) {
+                error!("Unable to save new routing key {:?}: {}", self.key, e);
+            }
+        }
+    }
+}
+
+/// The requests that a `Node` passes on to the thread that sends
+/// things out.
+enum Outgoing {
+    Message(EncryptedMessage),
+    Punch { target: crypto::PublicKey, via: crypto::PublicKey },
+}
+
+/// A handle on a running node.  Dropping it shuts the node down, and
+/// waits for its threads to finish.
+pub struct Node {
+    ask_rendezvous: Option<SyncSender<crypto::PublicKey>>,
+    hear_rendezvous: Receiver<Vec<crypto::PublicKey>>,
+    message_sender: Option<Sender<Outgoing>>,
+    message_receiver: Receiver<UserMessage>,
+    halt: Arc<udp::Halt>,
+    flood: Arc<FloodCounters>,
+    dht: Arc<Mutex<DHT>>,
+    /// Our threads, in the order in which they stop.
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Node {
+    /// Find the rendezvous node for the user with key `k`.
+    pub fn rendezvous(&self, k: &crypto::PublicKey) -> crypto::PublicKey {
+        self.rendezvous_replicas(k)[0]
+    }
+    /// Find the `RENDEZVOUS_REPLICAS` nodes (or fewer, if we know few
+    /// nodes) that should all hold messages for the user with key `k`,
+    /// closest first.
+    pub fn rendezvous_replicas(&self, k: &crypto::PublicKey) -> Vec<crypto::PublicKey> {
+        self.ask_rendezvous.as_ref().expect("node has been shut down").send(*k).unwrap();
+        self.hear_rendezvous.recv().unwrap()
+    }
+    /// Send an encrypted message out onto the network.
+    pub fn send(&self, m: EncryptedMessage) {
+        self.message_sender.as_ref().expect("node has been shut down")
+            .send(Outgoing::Message(m)).unwrap();
+    }
+    /// Ask the relay `via` to introduce us to `target`, so that we
+    /// can reach one another even if we are both behind NATs.
+    pub fn punch(&self, target: &crypto::PublicKey, via: &crypto::PublicKey) {
+        self.message_sender.as_ref().expect("node has been shut down")
+            .send(Outgoing::Punch { target: *target, via: *via }).unwrap();
+    }
+    /// Check whether a message has arrived for us.
+    pub fn try_receive(&self) -> Option<UserMessage> {
+        self.message_receiver.try_recv().ok()
+    }
+    /// How many packets we have accepted, and how many we have
+    /// dropped because they arrived too fast.
+    pub fn flood_counters(&self) -> &FloodCounters {
+        &self.flood
+    }
+    /// How much state we are holding, and how much we have dropped.
+    pub fn relay_state(&self) -> RelayState {
+        self.dht.with_lock(|dht| { dht.relay_state() })
+    }
+    /// Everything we know, see `NodeStats`.
+    pub fn stats(&self) -> NodeStats {
+        let mut stats = self.dht.with_lock(|dht| { dht.stats() });
+        stats.packets_accepted = self.flood.accepted.load(Ordering::Relaxed);
+        stats.packets_dropped = self.flood.dropped();
+        stats
+    }
+    /// Ask the node to shut down, and return without waiting for it.
+    /// We first stop taking requests and receiving packets.  Once
+    /// nothing more can be scheduled, the transmissions that are
+    /// already scheduled are sent out without further delay, after
+    /// which the transport is released.
+    pub fn shutdown(&mut self) {
+        self.ask_rendezvous = None;
+        self.message_sender = None;
+        self.halt.receiving.store(true, Ordering::SeqCst);
+    }
+    /// Wait for all of the node's threads to exit, which they only do
+    /// after `shutdown` has been called.
+    pub fn join(mut self) {
+        self.join_threads();
+    }
+    fn join_threads(&mut self) {
+        for t in self.threads.drain(..) {
+            if t.join().is_err() {
+                error!("A node thread panicked!");
+            }
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shutdown();
+        self.join_threads();
+    }
+}
+
+fn start_node(my_key: crypto::KeyPair, config: &NodeConfig,
+              listener: udp::Listener, clock: Arc<Clock>, halt: Arc<udp::Halt>,
+              bootstrap: Vec<RoutingGift>, table: Option<Table>) -> Node {
+    let dht = DHT::new(&my_key, config, clock.clone(), bootstrap);
+    if let Some(ref table) = table {
+        match dht.with_lock(|dht| { dht.load_table(&table.path) }) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No routing table yet at {:?}", table.path);
+            },
+            Err(e) => error!("Unable to load routing table {:?}: {}", table.path, e),
+        }
+        // If we restarted during a handover, we must keep answering
+        // to the old key until it is over.
+        let old = match read_keypair(&old_key_path(&table.key)) {
+            Ok(kp) => Some(kp),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                error!("Unable to read our old routing key: {}", e);
+                None
+            },
+        };
+        dht.with_lock(|dht| { dht.restore_keys(table.key_created, old) });
+    }
+    let key_difficulty = config.key_difficulty;
+    let udp::Listener { send, get, sender, receiver, flood, reach } = listener;
+    dht.with_lock(|dht| { dht.reach = reach.clone() });
+
+    // Every thread that may schedule transmissions holds a copy of
+    // `still_scheduling`, so that when shutting down we know when it
+    // is safe to flush out what is left.
+    let (still_scheduling, scheduling_done) = channel::<()>();
+
+    let scheduler = {
+        // Here we set up the thread that sends out requests for
+        // routing information.  This thread should wake up no more
+        // than once every 10 seconds (until I increase the
+        // communication frequency), and should ensure that we're
+        // always ready to send *something* out.
+        let dht = dht.clone(); // a separate copy for sending
                                // maintenance requests.
+        let halt = halt.clone();
+        let ms_period = config.timing.send_period_ms;
         std::thread::spawn(move|| {
-            let ms_period = send_period_ms;
             let buffer_ms = 100; // 100 ms seems enough...
-            let mut next_time = udp::now_ms()/ms_period*ms_period - buffer_ms;
+            // A clock may start anywhere, even at zero.
+            let mut next_time = (clock.monotonic_ms()/ms_period*ms_period).saturating_sub(buffer_ms);
+            let mut next_save = clock.monotonic_ms() + TABLE_SAVE_PERIOD_MS;
             loop {
-                let idx = (next_time/ms_period) as usize;
-                if !udp::sleep_until(next_time) {
+                if halt.receiving.load(Ordering::SeqCst) {
+                    // We are shutting down, so we stop sending
+                    // maintenance messages, and wait until nobody else
+                    // is able to schedule a transmission.
+                    if let Err(TryRecvError::Disconnected) = scheduling_done.try_recv() {
+                        break;
+                    }
+                    std::thread::sleep_ms(udp::POLL_MS as u32);
+                    continue;
+                }
+                if !clock.sleep_until_unless(next_time, &halt.receiving) {
                     // We are behind, so try to catch up by sleeping extra
                     // long this time.
                     next_time += ms_period;
                 }
                 next_time += ms_period;
-                send.send(dht.name_lock("send", |dht| {dht.msg(idx)})).unwrap();
+                if halt.receiving.load(Ordering::SeqCst) {
+                    continue;
+                }
+                dht.with_lock(|dht| { dht.expire_state() });
+                if dht.with_lock(|dht| { dht.rotation_due() }) {
+                    // This may take a few seconds, so we do it without
+                    // holding the lock.
+                    let new = work::generate_keypair(key_difficulty);
+                    info!("Replacing our routing key with {}", codename(&new.public.0));
+                    if let Some(ref table) = table {
+                        table.save_key(&new, clock.epoch_time());
+                    }
+                    dht.with_lock(|dht| { dht.rotate_key(&new) });
+                }
+                if send.send(dht.name_lock("send", |dht| {dht.msg()})).is_err() {
+                    return;
+                }
+                if let Some(ref table) = table {
+                    if clock.monotonic_ms() >= next_save {
+                        table.save(&dht);
+                        next_save += TABLE_SAVE_PERIOD_MS;
+                    }
+                }
             }
-        });
-    }
+            halt.flushing.store(true, Ordering::SeqCst);
+            if let Some(ref table) = table {
+                table.save(&dht);
+            }
+            for m in dht.with_lock(|dht| { dht.drain_scheduled() }) {
+                if send.send(m).is_err() {
+                    return;
+                }
+            }
+        })
+    };
 
-    let (sender1, receiver1): (Sender<EncryptedMessage>,
-                               Receiver<EncryptedMessage>) = channel(); // for sending messages from this node
+    let (sender1, receiver1): (Sender<Outgoing>,
+                               Receiver<Outgoing>) = channel(); // for sending messages from this node
     let (sender2, receiver2) = channel(); // for delivering messages to this node
 
     let (send_rendezvous_query, receive_rendezvous_query) = sync_channel(0); // asking for
     let (send_rendezvous_location, receive_rendezvous_location) = sync_channel(0); // asking for
 
-    {
+    let rendezvous = {
         // a separate copy for locating rendezvous nodes
         let dht = dht.clone();
         std::thread::spawn(move|| {
             for recipient in receive_rendezvous_query.iter() {
-                let dht = dht.lock().unwrap();
-                let mut best = bingley().key;
-                let mut best_distance = key_distance(&best, &recipient);
-                for k in dht.addresses.keys() {
-                    let k_distance = key_distance(k, &recipient);
-                    if k_distance < best_distance {
-                        best_distance = k_distance;
-                        best = *k;
-                    }
+                let best = dht.lock().unwrap().rendezvous(&recipient, RENDEZVOUS_REPLICAS);
+                if send_rendezvous_location.send(best).is_err() {
+                    return;
                 }
-                send_rendezvous_location.send(best).unwrap();
             }
-        });
-    }
+        })
+    };
 
-    {
+    let outgoing = {
         // a separate copy for sending out user messages.
         let dht = dht.clone();
+        let still_scheduling = still_scheduling.clone();
         std::thread::spawn(move|| {
-            for encrypted_message in receiver1.iter() {
+            let _still_scheduling = still_scheduling;
+            for outgoing in receiver1.iter() {
+                let encrypted_message = match outgoing {
+                    Outgoing::Message(m) => m,
+                    Outgoing::Punch { target, via } => {
+                        if !dht.with_lock(|dht| { dht.request_punch(&target, &via) }) {
+                            info!("Unable to punch through to {}, since {} is unknown",
+                                  codename(&target.0), codename(&via.0));
+                        }
+                        continue;
+                    },
+                };
+                let pickup_for = match Message::decode(&encrypted_message.contents) {
+                    Ok(Message::PickUp { destination, .. }) => {
+                        Some((destination, encrypted_message.rendezvous))
+                    },
+                    _ => None,
+                };
                 dht.with_lock(|dht|{
-                    if let Some((ip,sm)) = dht.send_ciphertext(encrypted_message.rendezvous,
-                                                               encrypted_message.contents, 600) {
+                    if let Some((ip,mut sm)) = dht.send_ciphertext(encrypted_message.rendezvous,
+                                                                   encrypted_message.contents) {
+                        sm.pickup_for = pickup_for;
                         dht.schedule(60, &udp::RawEncryptedMessage{
                             ip: ip,
                             data: sm.ob.packet(),
                         });
                         // The following allows us to read the response
                         // when it comes back!
-                        dht.onionboxen.insert(sm.ob.return_magic(), sm);
+                        dht.expect_response(sm);
                     } else {
+                        // The message will be sent again if it goes
+                        // unacknowledged, by which time we may know
+                        // enough nodes.
                         info!("Unready to send out message with {} live nodes",
                               dht.liveness.len());
                     }
                 });
             }
-        });
-    }
+        })
+    };
 
-    std::thread::spawn(move|| {
+    let node_dht = dht.clone();
+    let handler = std::thread::spawn(move|| {
+        let _still_scheduling = still_scheduling;
         for packet in get.iter() {
-            match onionbox_open(&packet.data, &my_key.secret) {
-                Ok(mut oob) => {
-                    let routing = RoutingInfo::from_bytes(&oob.routing());
-                    if routing.is_for_me {
-                        match oob.payload(&my_key) {
-                            Err(e) => {
-                                info!("Unable to read message! {:?}", e);
-                            },
-                            Ok(payload) => {
-                                if routing.who_am_i {
-                                    let mut you_are = [0; PAYLOAD_LENGTH];
-                                    let mut gift = dht.name_lock("gift", |dht|{dht.construct_gift()});
-                                    gift[0] = RoutingGift{ addr: packet.ip,
-                                                           key: oob.key() };
-                                    // add the sender to our database of routers
-                                    dht.name_lock("accept", |dht|{dht.accept_single_gift(&gift[0])});
-                                    Message::Response(gift).bytes(&mut you_are);
-                                    oob.respond(&my_key, &you_are);
-                                    dht.name_lock("schedule",
-                                                  |dht|{dht.schedule_if_convenient(routing.eta,
-                                                                                   &udp::RawEncryptedMessage{
-                                                                                       ip: packet.ip,
-                                                                                       data: oob.packet(),
-                                                                                   })});
-                                } else {
-                                    match Message::from_bytes(&payload) {
-                                        Message::Greetings(gs) => {
-                                            dht.with_lock(|dht|{dht.accept_gift(&gs)});
-
-                                            let mut response = [0; PAYLOAD_LENGTH];
-                                            let gift = dht.with_lock(|dht|{dht.construct_gift()});
-                                            Message::Response(gift).bytes(&mut response);
-                                            oob.respond(&my_key, &response);
-                                            // info!("Relaying {} {} -> {} {}",
-                                            //          codename(&packet.data), packet.ip,
-                                            //          codename(&oob.packet()), routing.ip);
-                                            dht.with_lock(|dht|{dht.schedule(routing.eta,
-                                                                             &udp::RawEncryptedMessage{
-                                                                                 ip: routing.ip,
-                                                                                 data: oob.packet(),
-                                                                             })});
-                                        },
-                                        Message::PickUp { destination, message } => {
-                                            // info!("   ═══ Pickup request!!! ═══ {}", my_key.public);
-                                            if let Ok((pk, _, _)) = double_unbox(&message, &my_key.secret) {
-                                                if pk != destination {
-                                                    info!("Invalid pickup request: {}",
-                                                          codename(&packet.data));
-                                                    continue;
-                                                }
-                                            } else {
-                                                info!("Bad pickup request: {}",
+            handle_packet(&dht, packet, &sender2);
+        }
+    });
+    Node {
+        ask_rendezvous: Some(send_rendezvous_query),
+        hear_rendezvous: receive_rendezvous_location,
+        message_sender: Some(sender1),
+        message_receiver: receiver2,
+        halt: halt,
+        flood: flood,
+        dht: node_dht,
+        threads: vec![rendezvous, outgoing, receiver, handler, scheduler, sender],
+    }
+}
+
+/// Answer a greeting with some of the nodes we know, and send the
+/// onion on its way.
+fn respond_with_gift(dht: &Arc<Mutex<DHT>>, oob: &mut onionsalt::OpenedOnionBox,
+                     my_key: &crypto::KeyPair, routing: &RoutingInfo) {
+    let mut response = [0; PAYLOAD_LENGTH];
+    let gift = dht.with_lock(|dht|{dht.construct_gift()});
+    Message::Response(gift).bytes(&mut response);
+    oob.respond(my_key, &response);
+    // info!("Relaying {} -> {} {}",
+    //          codename(&oob.packet()), routing.ip);
+    dht.with_lock(|dht|{dht.schedule(routing.eta,
+                                     &udp::RawEncryptedMessage{
+                                         ip: routing.ip,
+                                         data: oob.packet(),
+                                     })});
+}
+
+/// Handle a single packet that has arrived from the network.  Any user
+/// message that has arrived for us is given to `deliver`.
+fn handle_packet(dht: &Arc<Mutex<DHT>>, packet: udp::RawEncryptedMessage,
+                 deliver: &Sender<UserMessage>) {
+    // The packet may be on its way to an old key of ours, which we
+    // still answer to for a while after replacing it.
+    let keys = dht.with_lock(|dht| { dht.keys() });
+    let opened = keys.iter().filter_map(|k| {
+        onionbox_open(&packet.data, &k.secret).ok().map(|oob| (*k, oob))
+    }).next();
+    match opened {
+        Some((my_key, mut oob)) => {
+            let routing = match RoutingInfo::decode(&oob.routing()) {
+                Ok(r) => r,
+                Err(e) => {
+                    info!("Bad routing info ({}) from {}: {}", e, packet.ip,
+                          codename(&packet.data));
+                    return;
+                },
+            };
+            if routing.is_for_me {
+                match oob.payload(&my_key) {
+                    Err(e) => {
+                        info!("Unable to read message! {:?}", e);
+                    },
+                    Ok(payload) => {
+                        if routing.who_am_i {
+                            let mut you_are = [0; PAYLOAD_LENGTH];
+                            let mut gift = dht.name_lock("gift", |dht|{dht.construct_gift()});
+                            gift[0] = RoutingGift{ addr: packet.ip,
+                                                   key: oob.key() };
+                            // add the sender to our database of routers
+                            dht.name_lock("accept", |dht|{dht.accept_single_gift(&gift[0])});
+                            Message::Response(gift).bytes(&mut you_are);
+                            oob.respond(&my_key, &you_are);
+                            dht.name_lock("schedule",
+                                          |dht|{dht.schedule_if_convenient(routing.eta,
+                                                                           &udp::RawEncryptedMessage{
+                                                                               ip: packet.ip,
+                                                                               data: oob.packet(),
+                                                                           })});
+                        } else {
+                            let m = match Message::decode(&payload) {
+                                Ok(m) => m,
+                                Err(e) => {
+                                    info!("Bad message ({}) from {}: {}", e, packet.ip,
+                                          codename(&packet.data));
+                                    return;
+                                },
+                            };
+                            match m {
+                                Message::Greetings(gs) => {
+                                    dht.with_lock(|dht|{dht.accept_gift(&gs)});
+                                    respond_with_gift(dht, &mut oob, &my_key, &routing);
+                                },
+                                Message::Handover { new_key, proof } => {
+                                    let old = oob.key();
+                                    if dht.with_lock(|dht| { dht.accept_handover(&old, &new_key, &proof, &my_key) }) {
+                                        info!("{} is now known as {}",
+                                              codename(&old.0), codename(&new_key.0));
+                                    } else {
+                                        info!("Ignoring handover from {} to {}",
+                                              codename(&old.0), codename(&new_key.0));
+                                    }
+                                    respond_with_gift(dht, &mut oob, &my_key, &routing);
+                                },
+                                Message::PickUp { destination, message } => {
+                                    // info!("   ═══ Pickup request!!! ═══ {}", my_key.public);
+                                    let wants_delivery = match double_unbox(&message, &my_key.secret) {
+                                        Ok((pk, _, contents)) => {
+                                            if pk != destination {
+                                                info!("Invalid pickup request: {}",
                                                       codename(&packet.data));
-                                                continue;
+                                                return;
                                             }
-                                            // info!("   ═══ Pickup request: {} for {} ═══",
-                                            //       codename(&packet.data),
-                                            //       codename(&destination.0));
-                                            let mut dht = dht.lock().unwrap();
-                                            let ready_to_pickup = dht.to_pickup.contains_key(&destination);
-                                            if ready_to_pickup {
-                                                let mut buffer = [0;544];
-                                                dht.to_pickup[&destination].bytes(&mut buffer);
-                                                oob.respond(&my_key, &buffer);
-                                                info!("Forwarding {} {} -> {} {}",
-                                                      codename(&destination.0), codename(&buffer),
-                                                      codename(&oob.packet()), routing.ip);
-                                                dht.schedule(routing.eta,
-                                                             &udp::RawEncryptedMessage{
-                                                                 ip: routing.ip,
-                                                                 data: oob.packet(),
-                                                             });
-                                            } else {
-                                                // info!("Eventually I will deliver {} to {} {}",
-                                                //       codename(&destination.0),
-                                                //       codename(&oob.packet()), routing.ip);
-                                                dht.to_forward.insert(destination, oob);
-                                            }
-                                            dht.to_pickup.remove(&destination);
+                                            contents[0] == PICKUP_WANTS_DELIVERY
+                                        },
+                                        Err(_) => {
+                                            info!("Bad pickup request: {}",
+                                                  codename(&packet.data));
+                                            return;
                                         },
-                                        Message::ForwardPlease { destination, message } => {
-                                            // info!("Forward request: {}", codename(&packet.data));
-                                            let mut dht = dht.lock().unwrap();
-                                            let ready_to_forward = dht.to_forward.contains_key(&destination);
-                                            if ready_to_forward {
-                                                let mut buffer = [0;544];
-                                                Message::ForwardPlease{destination: destination,
-                                                                       message: message}.bytes(&mut buffer);
-                                                let (routing, packet) = {
-                                                    let ref mut foob = dht.to_forward.get_mut(&destination).unwrap();
-                                                    foob.respond(&my_key, &buffer);
-                                                    let routing = RoutingInfo::from_bytes(&foob.routing());
-                                                    // info!("Forwarding {} {} -> {} {}",
-                                                    //          codename(&destination.0), codename(&buffer),
-                                                    //          codename(&foob.packet()),
-                                                    //          routing.ip);
-                                                    (routing, foob.packet())
-                                                };
+                                    };
+                                    // info!("   ═══ Pickup request: {} for {} ═══",
+                                    //       codename(&packet.data),
+                                    //       codename(&destination.0));
+                                    let mut dht = dht.lock().unwrap();
+                                    let now = dht.clock.monotonic_ms();
+                                    if let Some((message, remaining)) = dht.to_pickup.pop(&destination, now) {
+                                        let buffer = delivery(&destination, &message, remaining,
+                                                              wants_delivery);
+                                        oob.respond(&my_key, &buffer);
+                                        info!("Forwarding {} {} -> {} {} ({} left)",
+                                              codename(&destination.0), codename(&buffer),
+                                              codename(&oob.packet()), routing.ip, remaining);
+                                        dht.schedule(routing.eta,
+                                                     &udp::RawEncryptedMessage{
+                                                         ip: routing.ip,
+                                                         data: oob.packet(),
+                                                     });
+                                    } else {
+                                        // info!("Eventually I will deliver {} to {} {}",
+                                        //       codename(&destination.0),
+                                        //       codename(&oob.packet()), routing.ip);
+                                        let now = dht.clock.monotonic_ms();
+                                        dht.to_forward.insert(destination, (oob, wants_delivery), now);
+                                    }
+                                },
+                                Message::ForwardPlease { destination, message } => {
+                                    // info!("Forward request: {}", codename(&packet.data));
+                                    let mut dht = dht.lock().unwrap();
+                                    let ready_to_forward = dht.to_forward.contains_key(&destination);
+                                    // We already decoded the routing info of a
+                                    // waiting pickup when it came in, but if it
+                                    // has somehow gone bad since, we drop the
+                                    // pickup and hold the message for the next.
+                                    let forwarded = if ready_to_forward {
+                                        // Since a pickup was waiting, we were
+                                        // holding nothing else for it.
+                                        let (routing, packet) = {
+                                            let &mut (ref mut foob, wants_delivery) =
+                                                dht.to_forward.get_mut(&destination).unwrap();
+                                            let buffer = delivery(&destination, &message, 0,
+                                                                  wants_delivery);
+                                            foob.respond(&my_key, &buffer);
+                                            // info!("Forwarding {} {} -> {}",
+                                            //          codename(&destination.0), codename(&buffer),
+                                            //          codename(&foob.packet()));
+                                            (RoutingInfo::decode(&foob.routing()), foob.packet())
+                                        };
+                                        dht.to_forward.remove(&destination);
+                                        match routing {
+                                            Ok(routing) => {
                                                 dht.schedule(routing.eta,
                                                              &udp::RawEncryptedMessage{
                                                                  ip: routing.ip,
                                                                  data: packet,
                                                              });
-                                                dht.to_forward.remove(&destination);
-                                            } else {
-                                                // info!("Saving message for pick up by {}!", codename(&destination.0));
-                                                dht.to_pickup.insert(destination,
-                                                                     Message::ForwardPlease{
-                                                                         destination: destination,
-                                                                         message: message });
-                                            }
-                                        },
-                                        _ => {
-                                            info!("Something else for me!\n\n");
+                                                true
+                                            },
+                                            Err(e) => {
+                                                info!("Dropping pickup for {} with bad routing info: {:?}",
+                                                      codename(&destination.0), e);
+                                                false
+                                            },
+                                        }
+                                    } else {
+                                        false
+                                    };
+                                    if !forwarded {
+                                        // info!("Saving message for pick up by {}!", codename(&destination.0));
+                                        let now = dht.clock.monotonic_ms();
+                                        if !dht.to_pickup.push(&destination, &message, now) {
+                                            info!("Mailbox for {} is full, dropping {}",
+                                                  codename(&destination.0), codename(&message));
+                                        }
+                                    }
+                                },
+                                Message::PunchPlease(target) => {
+                                    // This must come directly from the requester,
+                                    // so that packet.ip is the address its NAT
+                                    // gave it.  If it came from a node we know
+                                    // by another key, it came the long way round.
+                                    let requester = RoutingGift { addr: packet.ip, key: oob.key() };
+                                    let mut dht = dht.lock().unwrap();
+                                    match dht.pubkeys.get(&packet.ip) {
+                                        Some(k) if *k != requester.key => {
+                                            info!("Punch request for {} relayed by {}, ignoring",
+                                                  codename(&requester.key.0), packet.ip);
+                                            return;
                                         },
+                                        _ => (),
                                     }
-                                }
+                                    dht.accept_single_gift(&requester);
+                                    let target = match dht.addresses.get(&target) {
+                                        Some(&addr) => RoutingGift { addr: addr, key: target },
+                                        None => {
+                                            info!("Unable to introduce {} to unknown {}",
+                                                  codename(&requester.key.0), codename(&target.0));
+                                            return;
+                                        },
+                                    };
+                                    let mut response = [0; PAYLOAD_LENGTH];
+                                    Message::PunchAt(target).bytes(&mut response);
+                                    oob.respond(&my_key, &response);
+                                    dht.schedule(routing.eta, &udp::RawEncryptedMessage {
+                                        ip: packet.ip,
+                                        data: oob.packet(),
+                                    });
+                                    dht.introduce(&target, &requester);
+                                },
+                                Message::PunchAt(peer) => {
+                                    // Nobody asked for this, but a relay we know
+                                    // may introduce someone who asked for us.
+                                    dht.with_lock(|dht| {
+                                        if dht.pubkeys.get(&packet.ip) == Some(&oob.key()) {
+                                            dht.punch(&peer, false);
+                                        } else {
+                                            info!("Introduction from a stranger at {}, ignoring",
+                                                  packet.ip);
+                                        }
+                                    });
+                                },
+                                _ => {
+                                    info!("Something else for me!\n\n");
+                                },
                             }
                         }
-                    } else {
-                        // This is a packet that we should relay along.
-                        // info!("Relaying {} {} -> {} {}",
-                        //       codename(&packet.data), packet.ip,
-                        //       codename(&oob.packet()), routing.ip);
-                        dht.with_lock(|dht|{dht.schedule(routing.eta, &udp::RawEncryptedMessage{
-                            ip: routing.ip,
-                            data: oob.packet(),
-                        })});
                     }
-                },
-                _ => {
-                    let maybe_msg = match dht.lock().unwrap().onionboxen.get(array_ref![packet.data,0,32]) {
-                        Some(sm) =>
-                            match sm.ob.read_return(my_key, &packet.data) {
-                                Ok(msg) => {
-                                    Some((sm.clone(),Message::from_bytes(&msg)))
-                                },
-                                _ => {
-                                    info!("Message illegible!");
+                }
+            } else {
+                // This is a packet that we should relay along.
+                // info!("Relaying {} {} -> {} {}",
+                //       codename(&packet.data), packet.ip,
+                //       codename(&oob.packet()), routing.ip);
+                dht.with_lock(|dht|{dht.schedule(routing.eta, &udp::RawEncryptedMessage{
+                    ip: routing.ip,
+                    data: oob.packet(),
+                })});
+            }
+        },
+        None => {
+            let maybe_msg = match dht.lock().unwrap().onionboxen.get(array_ref![packet.data,0,32]) {
+                Some(sm) =>
+                    match keys.iter().filter_map(|k| sm.ob.read_return(*k, &packet.data).ok()).next() {
+                        Some(msg) => {
+                            match Message::decode(&msg) {
+                                Ok(m) => Some((sm.clone(), m)),
+                                Err(e) => {
+                                    info!("Bad response ({}): {}", e, codename(&packet.data));
                                     None
                                 },
-                            },
+                            }
+                        },
                         None => {
-                            info!("Not sure what that was! ({} from {})",
-                                  codename(&packet.data), packet.ip);
+                            info!("Message illegible!");
                             None
                         },
-                    };
-                    if maybe_msg.is_some() {
-                        dht.with_lock(|dht|{dht.onionboxen.remove(array_ref![packet.data,0,32])});
+                    },
+                None => {
+                    info!("Not sure what that was! ({} from {})",
+                          codename(&packet.data), packet.ip);
+                    None
+                },
+            };
+            if let Some((ref sm, _)) = maybe_msg {
+                dht.with_lock(|dht| {
+                    dht.onionboxen.remove(array_ref![packet.data,0,32]);
+                    dht.route_answered(sm);
+                    if let Some(a) = sm.greeted {
+                        dht.reach.answered(a);
                     }
-                    match maybe_msg {
-                        None => (),
-                        Some((_,Message::Greetings(_))) => {
-                            info!("Greetings not a valid response: {}",
-                                  codename(&packet.data));
-                        },
-                        Some((sm,Message::Response(rgs))) => {
-                            dht.with_lock(|dht|{dht.accept_gift(&rgs)});
-                            for i in 0 .. ROUTE_COUNT {
-                                if sm.who_relayed[i] != my_key.public {
-                                    // println!("Increasing liveness for {}!", sm.who_relayed[i]);
-                                    dht.with_lock(|dht| {
-                                        dht.liveness.insert(sm.who_relayed[i],
-                                                            MAX_LIVENESS);
-                                        dht.newbies.remove(&sm.who_relayed[i]);
-                                    });
-                                }
-                            }
-                            // if REPORT_WHOAMIS || sm.who_relayed[1] != my_key.public {
-                            //     info!("Response received: {}", codename(&packet.data));
-                            // }
-                            dht.with_lock(|dht|{dht.print("routing worked")});
-                            if rgs[0].key == my_key.public {
-                                // println!("My address is {}", rgs[0].addr);
-                                dht.with_lock(|dht| {
-                                    dht.liveness.insert(my_key.public, MAX_LIVENESS);
-                                    dht.newbies.remove(&my_key.public);
-                                });
-                            }
-                        },
-                        Some((_,Message::PickUp { destination, .. })) => {
-                            info!("Invalid pickup request for {}: {}",
-                                  codename(&destination.0), codename(&packet.data));
-                        },
-                        Some((_,Message::ForwardPlease { destination, message})) => {
-                            // info!("Forward request: {} for {}",
-                            //       codename(&packet.data), codename(&destination.0));
-                            sender2.send(UserMessage {
-                                destination: destination,
-                                message: message,
-                            }).unwrap();
+                });
+            }
+            match maybe_msg {
+                None => (),
+                Some((_,Message::Greetings(_))) => {
+                    info!("Greetings not a valid response: {}",
+                          codename(&packet.data));
+                },
+                Some((_,Message::Handover { .. })) => {
+                    info!("Handover not a valid response: {}",
+                          codename(&packet.data));
+                },
+                Some((sm,Message::Response(rgs))) => {
+                    dht.with_lock(|dht|{dht.accept_gift(&rgs)});
+                    for i in 0 .. ROUTE_COUNT {
+                        if !keys.iter().any(|k| k.public == sm.who_relayed[i]) {
+                            // println!("Increasing liveness for {}!", sm.who_relayed[i]);
+                            dht.with_lock(|dht| { dht.mark_live(&sm.who_relayed[i]) });
+                        }
+                    }
+                    // if REPORT_WHOAMIS || sm.who_relayed[1] != my_key.public {
+                    //     info!("Response received: {}", codename(&packet.data));
+                    // }
+                    dht.with_lock(|dht|{dht.print("routing worked")});
+                    if rgs[0].key == keys[0].public {
+                        // println!("My address is {}", rgs[0].addr);
+                        dht.with_lock(|dht| { dht.mark_live(&keys[0].public) });
+                    }
+                },
+                Some((sm,Message::PunchAt(peer))) => {
+                    if sm.punch_for == Some(peer.key) {
+                        dht.with_lock(|dht| { dht.punch(&peer, true) });
+                    } else {
+                        info!("Introduction to {} we never asked for: {}",
+                              codename(&peer.key.0), codename(&packet.data));
+                    }
+                },
+                Some((_,Message::PunchPlease(_))) => {
+                    info!("Punch request not a valid response: {}",
+                          codename(&packet.data));
+                },
+                Some((_,Message::PickUp { destination, .. })) => {
+                    info!("Invalid pickup request for {}: {}",
+                          codename(&destination.0), codename(&packet.data));
+                },
+                Some((sm,Message::ForwardPlease { destination, message})) => {
+                    // This is how relays that predate `Delivery`
+                    // answer a pickup.
+                    // info!("Forward request: {} for {}",
+                    //       codename(&packet.data), codename(&destination.0));
+                    if deliver.send(UserMessage {
+                        destination: destination,
+                        message: message,
+                        remaining: 0,
+                        rendezvous: sm.pickup_for.map(|p| p.1),
+                    }).is_err() {
+                        info!("Nobody is listening for messages!");
+                    }
+                },
+                Some((sm,Message::Delivery { remaining, message })) => {
+                    let (destination, rendezvous) = match sm.pickup_for {
+                        Some(p) => p,
+                        None => {
+                            info!("Delivery we never asked for: {}", codename(&packet.data));
+                            return;
                         },
+                    };
+                    if deliver.send(UserMessage {
+                        destination: destination,
+                        message: message,
+                        remaining: remaining,
+                        rendezvous: Some(rendezvous),
+                    }).is_err() {
+                        info!("Nobody is listening for messages!");
                     }
                 },
             }
-        }
-    });
-    Ok((send_rendezvous_query, receive_rendezvous_location, sender1, receiver2))
+        },
+    }
 }
 
 pub struct UserMessage {
     pub destination: crypto::PublicKey,
     pub message: [u8; USER_MESSAGE_LENGTH],
+    /// How many more messages the rendezvous node is holding for
+    /// `destination`.
+    pub remaining: u16,
+    /// The rendezvous node it came from, if we know.
+    pub rendezvous: Option<crypto::PublicKey>,
+}
+
+/// The answer to a pickup for `destination`, in whichever form the
+/// client that asked for it can read.
+fn delivery(destination: &crypto::PublicKey, message: &[u8; USER_MESSAGE_LENGTH],
+            remaining: u16, wants_delivery: bool) -> [u8; PAYLOAD_LENGTH] {
+    let mut buffer = [0; PAYLOAD_LENGTH];
+    if wants_delivery {
+        Message::Delivery { remaining: remaining, message: *message }.bytes(&mut buffer);
+    } else {
+        Message::ForwardPlease { destination: *destination, message: *message }.bytes(&mut buffer);
+    }
+    buffer
 }
 
 impl Debug for UserMessage {
@@ -1177,3 +2787,806 @@ fn test_double_box() {
     assert_eq!(silly[5], stupid[5]);
     assert_eq!(silly[NEW_LENGTH-3], stupid[NEW_LENGTH-3]);
 }
+
+#[test]
+fn garbage_is_not_fatal() {
+    let mut payload = [0; PAYLOAD_LENGTH];
+    payload[0] = b'z';
+    assert_eq!(Message::decode(&payload).err(), Some(DecodeError::UnknownTag(b'z')));
+
+    // A greeting that carries one bad address is rejected as a whole.
+    let gift = RoutingGift {
+        addr: SocketAddr::from_str("10.0.0.1:54321").unwrap(),
+        key: crypto::box_keypair().public,
+    };
+    Message::Greetings([gift; NUM_IN_RESPONSE]).bytes(&mut payload);
+    assert!(Message::decode(&payload).is_ok());
+    payload[1 + 3*50 + 12] = 1;
+    assert_eq!(Message::decode(&payload).err(), Some(DecodeError::BadAddress));
+
+    let mut routing = [0; ROUTING_LENGTH];
+    RoutingInfo { ip: gift.addr, eta: 7, is_for_me: true, who_am_i: false }.bytes(&mut routing);
+    assert_eq!(RoutingInfo::decode(&routing).map(|r| r.eta), Ok(7));
+    routing[0] |= 0x80;
+    assert_eq!(RoutingInfo::decode(&routing).err(), Some(DecodeError::UnknownFlags(0x81)));
+}
+
+#[cfg(test)]
+fn config(send_period_ms: u64) -> NodeConfig {
+    NodeConfig {
+        timing: Timing {
+            send_period_ms: send_period_ms,
+            greeting_delay_spread_ms: 6*send_period_ms,
+            .. Timing::default()
+        },
+        // Our test keys are made without any work, and our test nodes
+        // are all on one network.
+        key_difficulty: 0,
+        required_key_difficulty: 0,
+        distinct_networks: false,
+        .. NodeConfig::default()
+    }
+}
+
+#[test]
+fn node_starts_and_stops() {
+    use std::str::FromStr;
+    let net = ::loopback::LoopbackNetwork::new();
+    let addr = SocketAddr::from_str("10.0.0.1:54321").unwrap();
+    for _ in 0..3 {
+        let mut node = start_node_on(crypto::box_keypair(), net.bind(addr).unwrap(),
+                                     &NodeConfig::default(), SystemClock::new()).unwrap();
+        node.shutdown();
+        node.join();
+    }
+    // Once the node is gone, so is its transport.
+    assert!(net.bind(addr).is_ok());
+}
+
+#[test]
+fn node_paced_by_clock() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    use udp::Transport;
+    let clock = FakeClock::new(1000*1000);
+    let net = ::loopback::LoopbackNetwork::new();
+    // Our node starts out knowing only the bootstrap relays, so
+    // everything it sends goes to one of them.
+    let relays: Vec<_> = [bingley(), knightley(), wentworth()].iter()
+        .map(|g| net.bind(g.addr).unwrap()).collect();
+    let heard = || relays.iter().filter(|r| r.recv_packet().is_ok()).count();
+    let me = net.bind(SocketAddr::from_str("10.0.0.1:54321").unwrap()).unwrap();
+    let _node = start_node_on(crypto::box_keypair(), me, &NodeConfig::default(), clock.clone())
+        .unwrap();
+
+    // We start out behind, so there is a packet right away...
+    let mut total = 0;
+    for _ in 0..20 {
+        total += heard();
+        if total > 0 { break; }
+    }
+    assert_eq!(total, 1);
+    // ... but nothing more until time moves on, no matter how long
+    // we wait in real time.
+    assert_eq!(heard(), 0);
+    clock.advance(10*1000);
+    assert_eq!(heard(), 0);
+    clock.advance(10*1000);
+    let mut total = 0;
+    for _ in 0..20 {
+        total += heard();
+        if total > 0 { break; }
+    }
+    assert_eq!(total, 1);
+}
+
+#[test]
+fn nonsense_settings_are_refused() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    let clock = FakeClock::new(1000*1000);
+    let net = ::loopback::LoopbackNetwork::new();
+    let bind = |i: u8| net.bind(SocketAddr::from_str(&format!("10.0.0.{}:54321", i)).unwrap())
+        .unwrap();
+    // Each attempt binds the same address, which only works if the
+    // last one let go of it rather than starting a node.
+    for &(min, max) in [(0, 3), (1, 1), (4, 3), (3, ROUTE_COUNT + 1)].iter() {
+        let mut config = NodeConfig::default();
+        config.timing.min_hops = min;
+        config.timing.max_hops = max;
+        assert!(config.timing.check().is_err());
+        assert!(start_node_on(crypto::box_keypair(), bind(1), &config, clock.clone()).is_err());
+        assert!(start_static_node(&std::env::temp_dir(), &config).is_err());
+    }
+    assert!(start_node_among(crypto::box_keypair(), bind(2), &NodeConfig::default(),
+                             clock.clone(), Vec::new()).is_err());
+    assert!(start_node_among(crypto::box_keypair(), bind(3), &NodeConfig::default(),
+                             clock.clone(), vec![bingley()]).is_ok());
+}
+
+#[test]
+fn node_starts_at_time_zero() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    use udp::Transport;
+    let clock = FakeClock::new(0);
+    let net = ::loopback::LoopbackNetwork::new();
+    let relays: Vec<_> = [bingley(), knightley(), wentworth()].iter()
+        .map(|g| net.bind(g.addr).unwrap()).collect();
+    let me = net.bind(SocketAddr::from_str("10.0.0.1:54321").unwrap()).unwrap();
+    let _node = start_node_on(crypto::box_keypair(), me, &NodeConfig::default(), clock.clone())
+        .unwrap();
+    let mut total = 0;
+    for _ in 0..20 {
+        clock.advance(10*1000);
+        total += relays.iter().filter(|r| r.recv_packet().is_ok()).count();
+        if total > 0 { break; }
+    }
+    assert!(total > 0);
+}
+
+#[test]
+fn punch_through_nat() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    use udp::Transport;
+    let clock = FakeClock::new(1000*1000);
+    let net = ::loopback::LoopbackNetwork::new();
+    let (ka, kb, kr) = (crypto::box_keypair(), crypto::box_keypair(), crypto::box_keypair());
+    let a_pub = SocketAddr::from_str("10.0.0.1:40000").unwrap();
+    let b_pub = SocketAddr::from_str("10.0.0.2:50000").unwrap();
+    let relay = RoutingGift { addr: SocketAddr::from_str("10.0.0.3:54321").unwrap(),
+                              key: kr.public };
+    // Everyone bootstraps from the relay.
+    let dht = |k| DHT::new(&k, &config(100), clock.clone(), vec![relay]);
+    let nodes = [(ka, dht(ka), net.bind_behind_nat(a_pub).unwrap()),
+                 (kb, dht(kb), net.bind_behind_nat(b_pub).unwrap()),
+                 (kr, dht(kr), net.bind(relay.addr).unwrap())];
+    let (deliver, _) = channel();
+    // Send everything that is scheduled, and then handle everything
+    // that arrives.
+    let step = || {
+        for &(_, ref dht, ref t) in nodes.iter() {
+            for p in dht.with_lock(|dht| dht.drain_scheduled()) {
+                t.send_packet(&p).unwrap();
+            }
+        }
+        for &(_, ref dht, ref t) in nodes.iter() {
+            while let Ok(p) = t.recv_packet() {
+                handle_packet(dht, p, &deliver);
+            }
+        }
+    };
+    let (da, db) = (&nodes[0].1, &nodes[1].1);
+
+    // Neither NAT lets in packets from strangers.
+    nodes[0].2.send_packet(&udp::RawEncryptedMessage { ip: b_pub, data: [0; udp::PACKET_LENGTH] })
+        .unwrap();
+    assert!(udp::is_timeout(&nodes[1].2.recv_packet().unwrap_err()));
+
+    // b has been talking to the relay, which thus knows where it is.
+    db.with_lock(|dht| {
+        let (addr, sm) = dht.whoami(&relay);
+        dht.send_soon(addr, sm);
+    });
+    step();
+    assert!(da.with_lock(|dht| dht.request_punch(&kb.public, &kr.public)));
+    for _ in 0..4 {
+        step();
+    }
+    assert_eq!(da.with_lock(|dht| dht.addresses.get(&kb.public).cloned()), Some(b_pub));
+    assert_eq!(db.with_lock(|dht| dht.addresses.get(&ka.public).cloned()), Some(a_pub));
+    assert!(db.with_lock(|dht| dht.liveness.contains_key(&ka.public)));
+
+    // Nobody can move a node we know by introducing it elsewhere,
+    // not even a relay we trust.
+    let elsewhere = RoutingGift { addr: SocketAddr::from_str("10.0.0.9:54321").unwrap(),
+                                  key: kb.public };
+    nodes[2].1.with_lock(|dht| {
+        dht.introduce(&RoutingGift { addr: a_pub, key: ka.public }, &elsewhere)
+    });
+    step();
+    assert_eq!(da.with_lock(|dht| dht.addresses.get(&kb.public).cloned()), Some(b_pub));
+}
+
+#[test]
+fn routing_table_ages() {
+    use clock::FakeClock;
+    let path = std::env::temp_dir().join(format!("pmail-table-{}", crypto::random_u32()));
+    let clock = FakeClock::new(1000*1000*1000);
+    let me = crypto::box_keypair();
+    let old = RoutingGift { addr: SocketAddr::from_str("10.0.0.1:54321").unwrap(),
+                            key: crypto::box_keypair().public };
+    let new = RoutingGift { addr: SocketAddr::from_str("10.0.0.2:54321").unwrap(),
+                            key: crypto::box_keypair().public };
+    let dht = DHT::new(&me, &config(1000), clock.clone(), vec![bingley()]);
+    dht.with_lock(|dht| {
+        dht.accept_single_gift(&old);
+        dht.mark_live(&old.key);
+    });
+    clock.advance(8*24*60*60*1000);
+    dht.with_lock(|dht| {
+        dht.accept_single_gift(&new);
+        dht.mark_live(&new.key);
+        dht.save_table(&path).unwrap();
+    });
+
+    // The node we have not heard from in a week is forgotten, while
+    // the other one loses liveness as time goes on.
+    clock.advance(3*LIVENESS_DECAY_SECS as u64*1000);
+    let dht = DHT::new(&me, &config(1000), clock.clone(), vec![bingley()]);
+    dht.with_lock(|dht| dht.load_table(&path).unwrap());
+    dht.with_lock(|dht| {
+        assert_eq!(dht.addresses.get(&new.key), Some(&new.addr));
+        assert_eq!(dht.pubkeys.get(&new.addr), Some(&new.key));
+        assert_eq!(dht.liveness.get(&new.key), Some(&(MAX_LIVENESS - 3)));
+        assert!(!dht.addresses.contains_key(&old.key));
+    });
+    clock.advance(MAX_LIVENESS as u64*LIVENESS_DECAY_SECS as u64*1000);
+    let dht = DHT::new(&me, &config(1000), clock.clone(), vec![bingley()]);
+    dht.with_lock(|dht| dht.load_table(&path).unwrap());
+    dht.with_lock(|dht| {
+        assert!(dht.liveness.is_empty());
+        assert!(dht.newbies.contains(&new.key));
+    });
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bootstrap_file() {
+    use std::io::Write;
+    let path = std::env::temp_dir().join(format!("pmail-bootstrap-{}", crypto::random_u32()));
+    let key = crypto::box_keypair().public;
+    let hex: String = key.0.iter().map(|b| format!("{:02x}", b)).collect();
+    {
+        let mut f = std::fs::File::create(&path).unwrap();
+        write!(f, "# our private network\n\n10.0.0.1:54321 {}  # alice\n", hex).unwrap();
+    }
+    let config = NodeConfig { bootstrap: Some(path.clone()), .. NodeConfig::default() };
+    let gs = bootstrap_nodes(&config).unwrap();
+    assert_eq!(gs, vec![RoutingGift { addr: SocketAddr::from_str("10.0.0.1:54321").unwrap(),
+                                      key: key }]);
+
+    // A broken, empty or missing file is an error, and never leaves
+    // us on the real network.
+    {
+        let mut f = std::fs::File::create(&path).unwrap();
+        write!(f, "10.0.0.1:54321 {}\n", &hex[1..]).unwrap();
+    }
+    assert!(read_bootstrap(&path).is_err());
+    assert!(bootstrap_nodes(&config).is_err());
+    {
+        let mut f = std::fs::File::create(&path).unwrap();
+        write!(f, "# nobody here\n").unwrap();
+    }
+    assert!(bootstrap_nodes(&config).is_err());
+    std::fs::remove_file(&path).unwrap();
+    assert!(bootstrap_nodes(&config).is_err());
+    assert_eq!(bootstrap_nodes(&NodeConfig::default()).unwrap()[0], bingley());
+}
+
+#[test]
+fn bootstrap_relays_are_pinned() {
+    use clock::FakeClock;
+    // We want bingley in the first bucket, so we can crowd it.
+    let mut me = crypto::box_keypair();
+    while (me.public.0[0] ^ bingley().key.0[0]) & 0x80 == 0 {
+        me = crypto::box_keypair();
+    }
+    let dht = DHT::new(&me, &config(1000), FakeClock::new(0), vec![bingley()]);
+    dht.with_lock(|dht| {
+        let mut added = 0;
+        while added < 2*routing::BUCKET_SIZE {
+            let k = crypto::box_keypair().public;
+            if (k.0[0] ^ me.public.0[0]) & 0x80 != 0 {
+                let addr = SocketAddr::from_str(&format!("10.0.{}.1:54321", added)).unwrap();
+                dht.accept_single_gift(&RoutingGift { addr: addr, key: k });
+                dht.mark_live(&k);
+                added += 1;
+            }
+        }
+        assert!(dht.table.contains(&bingley().key));
+        assert_eq!(dht.addresses.get(&bingley().key), Some(&bingley().addr));
+
+        // Even knowing nobody at all, we have something to give.
+        dht.liveness.clear();
+        dht.forget(&bingley().key);
+        assert_eq!(dht.construct_gift()[0], bingley());
+    });
+}
+
+#[test]
+fn rendezvous_uses_full_key() {
+    use clock::FakeClock;
+    let me = crypto::box_keypair();
+    let dht = DHT::new(&me, &config(1000), FakeClock::new(0), vec![bingley()]);
+    // These keys agree in their first eight bytes, which is all that
+    // we used to look at.
+    let target = crypto::box_keypair().public;
+    let mut near = target;
+    near.0[31] ^= 1;
+    let mut far = target;
+    far.0[8] ^= 1;
+    dht.with_lock(|dht| {
+        for (i, k) in [far, near].iter().enumerate() {
+            let addr = SocketAddr::from_str(&format!("10.0.0.{}:54321", i + 1)).unwrap();
+            dht.accept_single_gift(&RoutingGift { addr: addr, key: *k });
+        }
+        assert_eq!(dht.rendezvous(&target, 2), vec![near, far]);
+        dht.forget(&near);
+        assert_eq!(dht.rendezvous(&target, 1), vec![far]);
+    });
+}
+
+#[test]
+fn held_messages_queue_and_expire() {
+    let dest = crypto::box_keypair().public;
+    let mut held = HeldMessages::new();
+    assert!(held.pop(&dest, 0).is_none());
+    for i in 0..MAX_HELD_PER_DESTINATION {
+        assert!(held.push(&dest, &[i as u8; USER_MESSAGE_LENGTH], i as u64));
+    }
+    assert!(!held.push(&dest, &[99; USER_MESSAGE_LENGTH], 100));
+    // A second message no longer overwrites the first.
+    let (m, remaining) = held.pop(&dest, 100).unwrap();
+    assert_eq!((m[0], remaining), (0, MAX_HELD_PER_DESTINATION as u16 - 1));
+    let (m, _) = held.pop(&dest, 100).unwrap();
+    assert_eq!(m[0], 1);
+    // Messages expire, oldest first.
+    let (m, remaining) = held.pop(&dest, HELD_MESSAGE_EXPIRY_MS + 3).unwrap();
+    assert_eq!((m[0], remaining), (4, MAX_HELD_PER_DESTINATION as u16 - 5));
+    assert!(held.pop(&dest, 2*HELD_MESSAGE_EXPIRY_MS).is_none());
+    assert!(held.queues.is_empty());
+}
+
+#[test]
+fn held_messages_make_room_fairly() {
+    let mut held = HeldMessages::new();
+    let fakes: Vec<_> = (0..MAX_HELD_MESSAGES/MAX_HELD_PER_DESTINATION).map(|i| {
+        let mut k = [0; 32];
+        k[0] = i as u8;
+        k[1] = (i >> 8) as u8;
+        crypto::PublicKey(k)
+    }).collect();
+    for f in fakes.iter() {
+        for i in 0..MAX_HELD_PER_DESTINATION {
+            assert!(held.push(f, &[i as u8; USER_MESSAGE_LENGTH], 0));
+        }
+    }
+    assert_eq!(held.total, MAX_HELD_MESSAGES);
+    // A real destination still gets its messages held, at the expense
+    // of whoever we were holding the most for.
+    let dest = crypto::PublicKey([0xff; 32]);
+    for i in 0..3 {
+        assert!(held.push(&dest, &[i; USER_MESSAGE_LENGTH], 1));
+    }
+    assert_eq!((held.total, held.evicted, held.refused), (MAX_HELD_MESSAGES, 3, 0));
+    assert_eq!(held.by_length[MAX_HELD_PER_DESTINATION - 1].len(), 3);
+    let shortened = held.by_length[MAX_HELD_PER_DESTINATION - 1].iter().next().cloned().unwrap();
+    assert_eq!(held.pop(&shortened, 2).unwrap().0[0], 1);
+    assert_eq!(held.pop(&dest, 2).unwrap(), ([0; USER_MESSAGE_LENGTH], 2));
+}
+
+#[test]
+fn old_clients_get_forward_please() {
+    let dest = crypto::box_keypair().public;
+    let msg = [7; USER_MESSAGE_LENGTH];
+    match Message::decode(&delivery(&dest, &msg, 3, true)) {
+        Ok(Message::Delivery { remaining: 3, message }) => assert_eq!(message[0], 7),
+        _ => panic!("expected a delivery"),
+    }
+    match Message::decode(&delivery(&dest, &msg, 3, false)) {
+        Ok(Message::ForwardPlease { destination, message }) => {
+            assert_eq!((destination, message[0]), (dest, 7));
+        },
+        _ => panic!("expected a forward please"),
+    }
+}
+
+#[test]
+fn relay_state_expires() {
+    use clock::FakeClock;
+    let clock = FakeClock::new(1000);
+    let me = crypto::box_keypair();
+    let dht = DHT::new(&me, &config(1000), clock.clone(), vec![bingley()]);
+    dht.with_lock(|dht| {
+        for _ in 0..3 {
+            let (addr, sm) = dht.whoami(&bingley());
+            dht.send_soon(addr, sm);
+        }
+        // The transport will hear that they went unanswered.
+        assert_eq!(dht.reach.unanswered(bingley().addr), 3);
+        let now = dht.clock.monotonic_ms();
+        dht.to_pickup.push(&me.public, &[1; USER_MESSAGE_LENGTH], now);
+    });
+    assert_eq!(dht.with_lock(|dht| dht.relay_state()),
+               RelayState { awaiting_response: 3, messages_held: 1, scheduled: 3,
+                            .. RelayState::default() });
+    dht.with_lock(|dht| dht.drain_scheduled());
+    clock.advance(SENT_EXPIRY_MS);
+    dht.with_lock(|dht| dht.expire_state());
+    assert_eq!(dht.with_lock(|dht| dht.relay_state()),
+               RelayState { messages_held: 1, expired: 3, .. RelayState::default() });
+    clock.advance(HELD_MESSAGE_EXPIRY_MS);
+    dht.with_lock(|dht| dht.expire_state());
+    assert_eq!(dht.with_lock(|dht| dht.relay_state()),
+               RelayState { expired: 4, .. RelayState::default() });
+}
+
+#[test]
+fn routes_follow_profile() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    for name in ["lan-test", "default", "paranoid"].iter() {
+        let timing = Timing::profile(name).unwrap();
+        let me = crypto::box_keypair();
+        let dht = DHT::new(&me, &NodeConfig { timing: timing, .. config(1000) }, FakeClock::new(0), vec![bingley()]);
+        dht.with_lock(|dht| {
+            for i in 0..20 {
+                let g = RoutingGift {
+                    addr: SocketAddr::from_str(&format!("10.0.0.{}:54321", i + 1)).unwrap(),
+                    key: crypto::box_keypair().public,
+                };
+                dht.accept_single_gift(&g);
+                dht.mark_live(&g.key);
+            }
+            for _ in 0..50 {
+                let n = dht.pick_live_route(None).unwrap().len();
+                assert!(timing.min_hops <= n && n <= timing.max_hops, "{} hops for {}", n, name);
+            }
+        });
+    }
+    assert!(Timing::profile("sloppy").is_none());
+}
+
+#[test]
+fn routes_are_never_too_short() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    let me = crypto::box_keypair();
+    let dht = DHT::new(&me, &config(1000), FakeClock::new(0), vec![bingley()]);
+    dht.with_lock(|dht| {
+        dht.timing.min_hops = 4;
+        dht.timing.max_hops = 4;
+        let peers: Vec<_> = (0..3).map(|i| RoutingGift {
+            addr: SocketAddr::from_str(&format!("10.0.0.{}:54321", i + 1)).unwrap(),
+            key: crypto::box_keypair().public,
+        }).collect();
+        for g in peers.iter() {
+            dht.accept_single_gift(g);
+            dht.mark_live(&g.key);
+        }
+        // We know only three live nodes, which is not enough.
+        assert!(dht.pick_live_route(None).is_none());
+        assert!(dht.send_ciphertext(peers[0].key, [0; PAYLOAD_LENGTH]).is_none());
+        // Nor do we send by way of a node we have no address for.
+        dht.timing.min_hops = 3;
+        assert!(dht.pick_live_route(None).is_some());
+        let unknown = crypto::box_keypair().public;
+        assert!(dht.send_ciphertext(unknown, [0; PAYLOAD_LENGTH]).is_none());
+    });
+}
+
+#[test]
+fn stats_report_peers() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    let me = crypto::box_keypair();
+    let dht = DHT::new(&me, &config(1000), FakeClock::new(0), vec![bingley()]);
+    let peer = RoutingGift { addr: SocketAddr::from_str("10.0.0.1:54321").unwrap(),
+                             key: crypto::box_keypair().public };
+    let outside = SocketAddr::from_str("10.0.0.2:54321").unwrap();
+    dht.with_lock(|dht| {
+        dht.accept_single_gift(&peer);
+        dht.mark_live(&peer.key);
+    });
+    let stats = dht.with_lock(|dht| dht.stats());
+    assert_eq!(stats.external_address, None);
+    assert_eq!(stats.peers.len(), 2);
+    let p = stats.peers.iter().find(|p| p.key == peer.key).unwrap();
+    assert_eq!((p.addr, p.liveness, p.newbie), (peer.addr, MAX_LIVENESS, false));
+    let b = stats.peers.iter().find(|p| p.key == bingley().key).unwrap();
+    assert_eq!(b.liveness, 0);
+
+    dht.with_lock(|dht| {
+        dht.record_address(&RoutingGift { addr: outside, key: me.public });
+        dht.msg();
+    });
+    let stats = dht.with_lock(|dht| dht.stats());
+    assert_eq!(stats.external_address, Some(outside));
+    assert_eq!(stats.peers.len(), 2);
+    assert_eq!((stats.packets_sent, stats.maintenance_sent), (1, 1));
+    assert!(format!("{}", stats).contains("10.0.0.2:54321"));
+}
+
+#[test]
+fn routes_favour_reliable_relays() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    let clock = FakeClock::new(1000*1000);
+    let me = crypto::box_keypair();
+    let dht = DHT::new(&me, &config(1000), clock.clone(), vec![bingley()]);
+    let peers: Vec<_> = (0..12).map(|i| RoutingGift {
+        addr: SocketAddr::from_str(&format!("10.0.0.{}:54321", i + 1)).unwrap(),
+        key: crypto::box_keypair().public,
+    }).collect();
+    // A third of our peers drop everything they are given.
+    let bad: Vec<_> = peers[..4].iter().map(|g| g.key).collect();
+    let share_of_bad = |dht: &mut DHT| {
+        let mut bad_hops = 0;
+        let mut hops = 0;
+        for _ in 0..300 {
+            let route = dht.pick_live_route(None).unwrap();
+            hops += route.len();
+            bad_hops += route.iter().filter(|g| bad.contains(&g.key)).count();
+        }
+        bad_hops as f64 / hops as f64
+    };
+    dht.with_lock(|dht| {
+        // Long routes would use most of our peers whatever we thought
+        // of them.
+        dht.timing.min_hops = 3;
+        dht.timing.max_hops = 3;
+        for g in peers.iter() {
+            dht.accept_single_gift(g);
+            dht.mark_live(&g.key);
+        }
+        for _ in 0..300 {
+            let (_, sm) = dht.greet().unwrap();
+            let magic = sm.ob.return_magic();
+            dht.expect_response(sm);
+            clock.advance(2000);
+            let sm = dht.onionboxen.remove(&magic).unwrap();
+            if !sm.who_relayed.iter().any(|k| bad.contains(k)) {
+                dht.route_answered(&sm);
+            }
+        }
+        // A message is answered whenever its rendezvous relay sees fit,
+        // so it says nothing about the relays along the way.
+        let before = dht.quality.clone();
+        let (_, sm) = dht.send_ciphertext(peers[9].key, [0; PAYLOAD_LENGTH]).unwrap();
+        let magic = sm.ob.return_magic();
+        dht.expect_response(sm);
+        let sm = dht.onionboxen.remove(&magic).unwrap();
+        dht.route_answered(&sm);
+        assert_eq!(dht.quality, before);
+        let q = |k: &crypto::PublicKey| dht.quality[k];
+        assert!(q(&bad[0]).sent > 10 && q(&bad[0]).answered == 0);
+        assert!(q(&peers[9].key).answered > 0);
+        assert_eq!(q(&peers[9].key).rtt_ms, Some(2000));
+        assert!(format!("{}", dht.stats()).contains("rtt 2.0s"));
+
+        // Without weighting, a third of all hops are through bad
+        // relays, but with it far fewer are.
+        dht.route_by_quality = false;
+        assert!(share_of_bad(dht) > 0.25);
+        dht.route_by_quality = true;
+        assert!(share_of_bad(dht) < 0.2);
+    });
+}
+
+#[test]
+fn keys_need_work() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    let lazy = || {
+        let mut kp = crypto::box_keypair();
+        while work::key_work(&kp.public) >= 4 {
+            kp = crypto::box_keypair();
+        }
+        kp
+    };
+    let gift = |i: u8, k: crypto::PublicKey| {
+        RoutingGift { addr: SocketAddr::from_str(&format!("10.0.0.{}:54321", i)).unwrap(), key: k }
+    };
+    let me = crypto::box_keypair();
+    let strict = NodeConfig { key_difficulty: 4, required_key_difficulty: 4, .. config(1000) };
+    let dht = DHT::new(&me, &strict, FakeClock::new(0), vec![bingley()]);
+    let cheap = gift(1, lazy().public);
+    let costly = gift(2, work::generate_keypair(4).public);
+    dht.with_lock(|dht| {
+        dht.accept_single_gift(&cheap);
+        dht.accept_single_gift(&costly);
+        assert!(!dht.record_address(&cheap));
+        assert!(!dht.addresses.contains_key(&cheap.key));
+        assert!(dht.addresses.contains_key(&costly.key));
+        // Our bootstrap relays are admitted however little work they
+        // did.
+        assert!(dht.addresses.contains_key(&bingley().key));
+    });
+    // Our own key is no use to anyone if it has too little work, so
+    // we replace it at once, but only where work is required.
+    let lazy_me = lazy();
+    let strict = NodeConfig { key_lifetime_secs: Some(100), .. strict };
+    assert!(DHT::new(&lazy_me, &strict, FakeClock::new(0), vec![bingley()])
+            .with_lock(|dht| dht.rotation_due()));
+    let lenient = NodeConfig { required_key_difficulty: 0, .. strict };
+    assert!(!DHT::new(&lazy_me, &lenient, FakeClock::new(0), vec![bingley()])
+            .with_lock(|dht| dht.rotation_due()));
+
+    // A key we already have is kept however little work it has, but
+    // new ones are made with work.  An old key file that does not say
+    // when the key was made is counted from when we first read it.
+    let path = std::env::temp_dir().join(format!("pmail-key-{}", crypto::random_u32()));
+    let old = lazy();
+    write_keypair(&path, &old).unwrap();
+    let (kp, created) = read_or_generate_routing_keypair(&path, 4, 77).unwrap();
+    assert_eq!((kp.public, created), (old.public, 77));
+    assert_eq!(read_or_generate_routing_keypair(&path, 4, 99).unwrap().1, 77);
+    std::fs::remove_file(&path).unwrap();
+    let (new, created) = read_or_generate_routing_keypair(&path, 4, 99).unwrap();
+    assert!(work::key_work(&new.public) >= 4 && created == 99);
+    let (kp, created) = read_or_generate_routing_keypair(&path, 4, 123).unwrap();
+    assert_eq!((kp.public, created), (new.public, 99));
+    assert_eq!(read_keypair(&path).unwrap().public, new.public);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn replay_leaves_the_key_alone() {
+    let dir = std::env::temp_dir().join(format!("pmail-replay-{}", crypto::random_u32()));
+    std::fs::create_dir(&dir).unwrap();
+    let config = NodeConfig { replay: Some(dir.join("capture")), key_difficulty: 0,
+                              .. NodeConfig::default() };
+    // Without a key there is nothing to replay with, and we must not
+    // make one up.
+    assert!(start_static_node(&dir, &config).is_err());
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    // An old key file is not brought up to date either.  (The capture
+    // is missing, so the node never gets going.)
+    let name = match gethostname() {
+        Ok(hostname) => format!("routing-{}.key", hostname),
+        Err(_) => "routing.key".to_string(),
+    };
+    write_keypair(&dir.join(&name), &crypto::box_keypair()).unwrap();
+    assert!(start_static_node(&dir, &config).is_err());
+    assert_eq!(std::fs::metadata(dir.join(&name)).unwrap().len(), 64);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn routes_are_diverse() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    let families: Vec<Vec<crypto::PublicKey>> = (0..3).map(|_| {
+        (0..4).map(|_| crypto::box_keypair().public).collect()
+    }).collect();
+    let config = NodeConfig { distinct_networks: true, families: families.clone(), .. config(1000) };
+    let me = crypto::box_keypair();
+    let dht = DHT::new(&me, &config, FakeClock::new(0), vec![bingley()]);
+    // A synthetic table in which most relays crowd into a few
+    // networks, and a dozen relays belong to three families spread
+    // across the address space.
+    let mut gifts = Vec::new();
+    for i in 0..60 {
+        let addr = if i % 3 == 0 {
+            format!("[2001:db8:{:x}:{:x}::1]:54321", i % 4, i)
+        } else {
+            format!("10.{}.{}.{}:54321", i % 5, i, i % 7)
+        };
+        gifts.push(RoutingGift { addr: SocketAddr::from_str(&addr).unwrap(),
+                                 key: crypto::box_keypair().public });
+    }
+    for (i, k) in families.iter().flat_map(|f| f.iter()).enumerate() {
+        gifts.push(RoutingGift { addr: SocketAddr::from_str(&format!("{}.1.2.3:54321", 20 + i)).unwrap(),
+                                 key: *k });
+    }
+    dht.with_lock(|dht| {
+        for g in gifts.iter() {
+            dht.accept_single_gift(g);
+            dht.mark_live(&g.key);
+        }
+        let known: Vec<RoutingGift> = gifts.iter()
+            .filter(|g| dht.addresses.contains_key(&g.key)).cloned().collect();
+        for n in 0..200 {
+            let (through, route) = match n % 3 {
+                0 => (None, dht.pick_route()),
+                1 => (None, dht.pick_live_route(None)),
+                _ => {
+                    let t = known[n % known.len()];
+                    (Some(t), dht.pick_live_route(Some(t)))
+                },
+            };
+            let route = route.unwrap();
+            assert!(route.len() >= 3 && route.len() <= ROUTE_COUNT);
+            if let Some(t) = through {
+                assert!(route.contains(&t));
+            }
+            for (i, a) in route.iter().enumerate() {
+                assert!(a.key != me.public);
+                for b in route[i+1..].iter() {
+                    assert!(Subnet::of(&a.addr) != Subnet::of(&b.addr), "{} and {}", a.addr, b.addr);
+                    assert!(!families.iter().any(|f| f.contains(&a.key) && f.contains(&b.key)));
+                }
+            }
+        }
+    });
+    assert_eq!(Subnet::of(&SocketAddr::from_str("[::ffff:10.1.2.3]:1").unwrap()),
+               Subnet::of(&SocketAddr::from_str("10.1.9.9:1").unwrap()));
+}
+
+#[test]
+fn key_lifetime_may_be_endless() {
+    use clock::FakeClock;
+    let clock = FakeClock::new(1000*1000);
+    let config = NodeConfig { key_lifetime_secs: Some(u32::MAX), .. config(1000) };
+    let dht = DHT::new(&crypto::box_keypair(), &config, clock.clone(), vec![bingley()]);
+    assert!(!dht.with_lock(|dht| dht.rotation_due()));
+    clock.advance(100*365*24*3600*1000);
+    assert!(!dht.with_lock(|dht| dht.rotation_due()));
+}
+
+#[test]
+fn key_rotation_hands_over() {
+    use std::str::FromStr;
+    use clock::FakeClock;
+    let clock = FakeClock::new(1000*1000);
+    let config = NodeConfig { key_lifetime_secs: Some(100), key_grace_secs: 50, .. config(1000) };
+    let (ka, kr) = (crypto::box_keypair(), crypto::box_keypair());
+    let a_addr = SocketAddr::from_str("10.0.0.1:54321").unwrap();
+    let a = DHT::new(&ka, &config, clock.clone(), vec![bingley()]);
+    let r = DHT::new(&kr, &config, clock.clone(), vec![bingley()]);
+    r.with_lock(|r| {
+        r.accept_single_gift(&RoutingGift { addr: a_addr, key: ka.public });
+        r.mark_live(&ka.public);
+    });
+
+    assert!(!a.with_lock(|a| a.rotation_due()));
+    clock.advance(100*1000);
+    assert!(a.with_lock(|a| a.rotation_due()));
+    let new = crypto::box_keypair();
+    a.with_lock(|a| a.rotate_key(&new));
+    assert_eq!(a.with_lock(|a| a.keys().iter().map(|k| k.public).collect::<Vec<_>>()),
+               vec![new.public, ka.public]);
+    assert!(!a.with_lock(|a| a.rotation_due()));
+
+    // The handover survives being sent as a message.
+    let mut payload = [0; PAYLOAD_LENGTH];
+    Message::Handover {
+        new_key: new.public,
+        proof: handover_proof(&ka.public, &new, &kr.public),
+    }.bytes(&mut payload);
+    let proof = match Message::decode(&payload) {
+        Ok(Message::Handover { new_key, proof }) => {
+            assert_eq!(new_key, new.public);
+            proof
+        },
+        _ => panic!("not a handover"),
+    };
+    r.with_lock(|r| {
+        // A proof not made by the holder of the new key, or not made
+        // for us, is no good.  That the old key agrees is shown by
+        // the handover coming from it.
+        let mallory = crypto::box_keypair();
+        let forged = handover_proof(&ka.public, &mallory, &kr.public);
+        assert!(!r.accept_handover(&ka.public, &new.public, &forged, &kr));
+        let misdirected = handover_proof(&ka.public, &new, &mallory.public);
+        assert!(!r.accept_handover(&ka.public, &new.public, &misdirected, &kr));
+        assert!(r.addresses.contains_key(&ka.public));
+
+        assert!(r.accept_handover(&ka.public, &new.public, &proof, &kr));
+        assert_eq!(r.addresses.get(&new.public), Some(&a_addr));
+        assert_eq!(r.pubkeys.get(&a_addr), Some(&new.public));
+        assert_eq!(r.liveness.get(&new.public), Some(&MAX_LIVENESS));
+        assert!(!r.addresses.contains_key(&ka.public) && !r.liveness.contains_key(&ka.public));
+        // Gossip about the old key is ignored.
+        r.accept_single_gift(&RoutingGift { addr: a_addr, key: ka.public });
+        assert!(!r.addresses.contains_key(&ka.public));
+    });
+
+    // Had it restarted during the handover, it would still answer to
+    // its old key, but only until the handover was over.
+    let rotated = clock.epoch_time();
+    let restarted = || {
+        let b = DHT::new(&new, &config, clock.clone(), vec![bingley()]);
+        b.with_lock(|b| b.restore_keys(rotated, Some(ka)));
+        b.with_lock(|b| b.keys().iter().map(|k| k.public).collect::<Vec<_>>())
+    };
+    assert_eq!(restarted(), vec![new.public, ka.public]);
+
+    clock.advance(50*1000);
+    a.with_lock(|a| a.expire_state());
+    assert_eq!(a.with_lock(|a| a.keys().len()), 1);
+    assert_eq!(restarted(), vec![new.public]);
+}
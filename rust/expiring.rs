@@ -0,0 +1,117 @@
+//! A map that forgets its entries after a while, and never holds more
+//! than a fixed number of them.  A relay keeps state on behalf of
+//! strangers (the onion boxes it is waiting to hear back about, and
+//! the pickups it is waiting to answer), and must not let that state
+//! grow without bound just because the responses never come.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+pub struct ExpiringMap<K, V> {
+    /// Each value, along with when it was inserted on our monotonic
+    /// clock.
+    entries: HashMap<K, (u64, V)>,
+    /// The keys in the order they were inserted.  This may also hold
+    /// keys that have since been removed (or inserted again), which we
+    /// recognize by their insertion time not matching `entries`.
+    order: VecDeque<(u64, K)>,
+    max_age_ms: u64,
+    capacity: usize,
+    /// How many entries we have forgotten because they were too old.
+    pub expired: usize,
+    /// How many entries we have forgotten to make room for new ones.
+    pub evicted: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> ExpiringMap<K, V> {
+    pub fn new(max_age_ms: u64, capacity: usize) -> ExpiringMap<K, V> {
+        ExpiringMap {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            max_age_ms: max_age_ms,
+            capacity: capacity,
+            expired: 0,
+            evicted: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.entries.contains_key(k)
+    }
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.entries.get(k).map(|e| &e.1)
+    }
+    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        self.entries.get_mut(k).map(|e| &mut e.1)
+    }
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.entries.remove(k).map(|e| e.1)
+    }
+    /// Insert `v`, replacing any old value for `k`.  If we are full,
+    /// the oldest entry is evicted.
+    pub fn insert(&mut self, k: K, v: V, now_ms: u64) {
+        self.expire(now_ms);
+        if !self.entries.contains_key(&k) && self.entries.len() >= self.capacity {
+            if let Some(old) = self.pop_oldest() {
+                self.entries.remove(&old);
+                self.evicted += 1;
+            }
+        }
+        self.entries.insert(k.clone(), (now_ms, v));
+        self.order.push_back((now_ms, k));
+        if self.order.len() > 2*self.capacity + 16 {
+            // Lots of keys have been removed or replaced, so we
+            // rebuild `order` rather than let it grow.
+            let mut order: Vec<_> = self.entries.iter().map(|(k, e)| (e.0, k.clone())).collect();
+            order.sort_by(|a, b| a.0.cmp(&b.0));
+            self.order = order.into_iter().collect();
+        }
+    }
+    /// Forget every entry that is older than our maximum age.
+    pub fn expire(&mut self, now_ms: u64) {
+        while let Some(&(t, _)) = self.order.front() {
+            if t + self.max_age_ms > now_ms {
+                break;
+            }
+            let (_, k) = self.order.pop_front().unwrap();
+            let current = self.entries.get(&k).map(|e| e.0 == t).unwrap_or(false);
+            if current {
+                self.entries.remove(&k);
+                self.expired += 1;
+            }
+        }
+    }
+    /// The key of the oldest entry in `order` which is still current,
+    /// if any, which is removed from `order`.
+    fn pop_oldest(&mut self) -> Option<K> {
+        while let Some((t, k)) = self.order.pop_front() {
+            if self.entries.get(&k).map(|e| e.0 == t).unwrap_or(false) {
+                return Some(k);
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn expires_and_evicts() {
+    let mut m = ExpiringMap::new(100, 3);
+    m.insert(1, "one", 0);
+    m.insert(2, "two", 10);
+    m.insert(3, "three", 20);
+    // Replacing a value makes it new again.
+    m.insert(1, "uno", 30);
+    assert_eq!(m.len(), 3);
+    m.insert(4, "four", 40);
+    assert_eq!(m.evicted, 1);
+    assert!(!m.contains_key(&2));
+    assert_eq!(m.get(&1), Some(&"uno"));
+    m.expire(125);
+    assert_eq!(m.expired, 1);
+    assert!(!m.contains_key(&3));
+    assert_eq!(m.remove(&1), Some("uno"));
+    m.expire(1000);
+    assert_eq!((m.len(), m.expired), (0, 2));
+}
@@ -0,0 +1,256 @@
+//! Protection against being flooded with packets.  Every packet that
+//! reaches the DHT costs us an `onionbox_open`, so a single host
+//! sending us datagrams as fast as it can could otherwise pin our CPU.
+//! We therefore put each packet through a token bucket for its source
+//! address, and then through a global token bucket, before doing any
+//! crypto work on it.
+//!
+//! An ipv6 host typically has a whole /64 to itself, so we treat all
+//! of a /64 as a single source.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many distinct sources we are willing to keep track of.  When
+/// we exceed this, we forget the one we heard from longest ago.  A
+/// source we have forgotten starts over with a full bucket, so a
+/// flood of spoofed addresses can never lock out a newcomer, and the
+/// global bucket still limits what such a flood costs us.
+const MAX_SOURCES: usize = 4096;
+
+/// The limits on how fast packets may arrive.  Rates are in packets
+/// per second, and bursts are the number of packets that may arrive
+/// at once after a quiet period.
+#[derive(Clone, Copy, Debug)]
+pub struct FloodLimits {
+    pub per_source_rate: u32,
+    pub per_source_burst: u32,
+    pub global_rate: u32,
+    pub global_burst: u32,
+}
+
+impl Default for FloodLimits {
+    fn default() -> FloodLimits {
+        // An honest node sends us one packet per send period (10
+        // seconds) at most, so these limits are very generous to
+        // honest peers and to nodes sharing a NAT.
+        FloodLimits {
+            per_source_rate: 20,
+            per_source_burst: 100,
+            global_rate: 2000,
+            global_burst: 4000,
+        }
+    }
+}
+
+/// Counts of what the flood guard did with the packets it saw.  These
+/// may be read at any time by anyone holding a reference, so that
+/// operators can see when they are under attack.
+#[derive(Debug)]
+pub struct FloodCounters {
+    pub accepted: AtomicUsize,
+    /// Packets dropped because their source was sending too fast.
+    pub dropped_per_source: AtomicUsize,
+    /// Packets dropped because we were receiving too much overall.
+    pub dropped_global: AtomicUsize,
+}
+
+impl FloodCounters {
+    pub fn new() -> FloodCounters {
+        FloodCounters {
+            accepted: AtomicUsize::new(0),
+            dropped_per_source: AtomicUsize::new(0),
+            dropped_global: AtomicUsize::new(0),
+        }
+    }
+    pub fn dropped(&self) -> usize {
+        self.dropped_per_source.load(Ordering::Relaxed)
+            + self.dropped_global.load(Ordering::Relaxed)
+    }
+}
+
+/// A token bucket.  We keep tokens in thousandths so that we can
+/// refill with millisecond resolution using integer arithmetic.
+struct Bucket {
+    millitokens: u64,
+    last_ms: u64,
+}
+
+impl Bucket {
+    fn full(burst: u32, now_ms: u64) -> Bucket {
+        Bucket { millitokens: burst as u64*1000, last_ms: now_ms }
+    }
+    fn refill(&mut self, rate: u32, burst: u32, now_ms: u64) {
+        if now_ms > self.last_ms {
+            let added = (now_ms - self.last_ms)*rate as u64;
+            self.millitokens = ::std::cmp::min(self.millitokens + added, burst as u64*1000);
+            self.last_ms = now_ms;
+        }
+    }
+    fn take(&mut self) -> bool {
+        if self.millitokens >= 1000 {
+            self.millitokens -= 1000;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The state of our flood protection.  This is owned by the receiver
+/// thread, so it needs no locking.
+pub struct FloodGuard {
+    limits: FloodLimits,
+    /// The bucket of each source, along with when we last heard from
+    /// it, as a count of the packets we had seen by then...
+    sources: HashMap<IpAddr, (Bucket, u64)>,
+    /// ... and the sources by that count, oldest first.
+    by_age: BTreeMap<u64, IpAddr>,
+    seen: u64,
+    global: Bucket,
+}
+
+/// The source we hold `ip` to account as.
+fn source_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.to_ipv4().is_none() => {
+            let s = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        },
+        _ => ip,
+    }
+}
+
+impl FloodGuard {
+    pub fn new(limits: FloodLimits, now_ms: u64) -> FloodGuard {
+        FloodGuard {
+            limits: limits,
+            sources: HashMap::new(),
+            by_age: BTreeMap::new(),
+            seen: 0,
+            global: Bucket::full(limits.global_burst, now_ms),
+        }
+    }
+
+    /// Decide whether to accept a packet from `source` arriving at
+    /// `now_ms`, and record the decision in `counters`.
+    pub fn allow(&mut self, source: IpAddr, now_ms: u64, counters: &FloodCounters) -> bool {
+        let l = self.limits;
+        let source = source_of(source);
+        if !self.sources.contains_key(&source) && self.sources.len() >= MAX_SOURCES {
+            let oldest = self.by_age.iter().next().map(|(&age, &a)| (age, a));
+            if let Some((age, a)) = oldest {
+                self.by_age.remove(&age);
+                self.sources.remove(&a);
+            }
+        }
+        self.seen += 1;
+        let seen = self.seen;
+        let ok = {
+            let entry = self.sources.entry(source)
+                .or_insert_with(|| (Bucket::full(l.per_source_burst, now_ms), seen));
+            self.by_age.remove(&entry.1);
+            entry.1 = seen;
+            entry.0.refill(l.per_source_rate, l.per_source_burst, now_ms);
+            entry.0.take()
+        };
+        self.by_age.insert(seen, source);
+        if !ok {
+            counters.dropped_per_source.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.global.refill(l.global_rate, l.global_burst, now_ms);
+        if !self.global.take() {
+            counters.dropped_global.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        counters.accepted.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+#[cfg(test)]
+fn ip(s: &str) -> IpAddr {
+    use std::str::FromStr;
+    IpAddr::from_str(s).unwrap()
+}
+
+#[test]
+fn per_source_limit() {
+    let limits = FloodLimits {
+        per_source_rate: 10, per_source_burst: 5,
+        global_rate: 1000, global_burst: 1000,
+    };
+    let counters = FloodCounters::new();
+    let mut g = FloodGuard::new(limits, 0);
+    for _ in 0..5 {
+        assert!(g.allow(ip("10.0.0.1"), 0, &counters));
+    }
+    assert!(!g.allow(ip("10.0.0.1"), 0, &counters));
+    // Someone else is not affected by the flood.
+    assert!(g.allow(ip("10.0.0.2"), 0, &counters));
+    // After 100 ms, we have earned one more packet.
+    assert!(g.allow(ip("10.0.0.1"), 100, &counters));
+    assert!(!g.allow(ip("10.0.0.1"), 100, &counters));
+    assert_eq!(counters.accepted.load(Ordering::Relaxed), 7);
+    assert_eq!(counters.dropped_per_source.load(Ordering::Relaxed), 2);
+    assert_eq!(counters.dropped_global.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn global_limit() {
+    let limits = FloodLimits {
+        per_source_rate: 10, per_source_burst: 5,
+        global_rate: 10, global_burst: 3,
+    };
+    let counters = FloodCounters::new();
+    let mut g = FloodGuard::new(limits, 0);
+    for i in 0..3 {
+        assert!(g.allow(ip(&format!("10.0.0.{}", i)), 0, &counters));
+    }
+    assert!(!g.allow(ip("10.0.0.9"), 0, &counters));
+    assert_eq!(counters.dropped_global.load(Ordering::Relaxed), 1);
+    assert!(g.allow(ip("10.0.0.9"), 100, &counters));
+}
+
+#[test]
+fn spoofed_sources_do_not_lock_out_newcomers() {
+    let limits = FloodLimits::default();
+    let counters = FloodCounters::new();
+    let mut g = FloodGuard::new(limits, 0);
+    // Somebody drains the buckets of as many spoofed addresses as we
+    // keep track of, and can then keep them all busy with a trickle.
+    let spoofed = |i: usize| IpAddr::V4(::std::net::Ipv4Addr::from(0x0a000000 + i as u32));
+    for i in 0..MAX_SOURCES {
+        for _ in 0..limits.per_source_burst {
+            g.allow(spoofed(i), 0, &counters);
+        }
+    }
+    assert_eq!(g.sources.len(), MAX_SOURCES);
+    // Newcomers still get through, at the expense of the sources we
+    // heard from longest ago.
+    assert!(g.allow(ip("192.168.1.1"), 1000, &counters));
+    assert!(g.allow(ip("192.168.1.2"), 1001, &counters));
+    assert_eq!(g.sources.len(), MAX_SOURCES);
+    assert!(!g.sources.contains_key(&spoofed(0)));
+    assert!(g.sources.contains_key(&spoofed(MAX_SOURCES - 1)));
+}
+
+#[test]
+fn ipv6_hosts_count_by_64() {
+    let limits = FloodLimits {
+        per_source_rate: 10, per_source_burst: 5,
+        global_rate: 1000, global_burst: 1000,
+    };
+    let counters = FloodCounters::new();
+    let mut g = FloodGuard::new(limits, 0);
+    for i in 0..5 {
+        assert!(g.allow(ip(&format!("2001:db8:0:1::{}", i + 1)), 0, &counters));
+    }
+    // Moving around within the same /64 does not earn a new bucket...
+    assert!(!g.allow(ip("2001:db8:0:1:ffff::1"), 0, &counters));
+    // ... but a different /64 is someone else.
+    assert!(g.allow(ip("2001:db8:0:2::1"), 0, &counters));
+    assert_eq!(g.sources.len(), 2);
+}
@@ -11,12 +11,24 @@ extern crate serde;
 extern crate serde_json;
 extern crate tempfile;
 
+pub mod clock;
+pub mod config;
 pub mod udp;
+pub mod tcp;
+pub mod capture;
+pub mod flood;
+pub mod expiring;
+pub mod loopback;
+pub mod routing;
+pub mod quality;
+pub mod work;
+pub mod scheduler;
 pub mod dht;
 pub mod pmail;
 pub mod str255;
 pub mod message;
 pub mod mailbox;
 pub mod format;
+pub mod sim;
 
 pub use udp::{PACKET_LENGTH};
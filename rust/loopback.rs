@@ -0,0 +1,316 @@
+//! An in-memory network, so that many nodes can talk to one another
+//! within a single process without touching a real socket.  This is
+//! mostly useful for testing routing, pickup and forwarding.
+//!
+//! An endpoint may also be put behind a simulated NAT, which (like
+//! most real ones) only lets in packets from addresses that the
+//! endpoint has already sent packets to.  The network itself may be
+//! given `Conditions`, under which packets are delayed and lost.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::time::Duration;
+
+use onionsalt::crypto;
+
+use clock::{Clock, SystemClock};
+use udp::{RawEncryptedMessage, Transport, POLL_MS};
+
+/// How badly a `LoopbackNetwork` treats the packets sent over it.
+/// The default is a perfect network, on which every packet arrives
+/// at once.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conditions {
+    /// How long every packet takes to arrive.
+    pub latency_ms: u64,
+    /// Up to this much more, chosen at random for each packet, so
+    /// packets may arrive out of order.
+    pub jitter_ms: u64,
+    /// The fraction of packets that never arrive at all.
+    pub loss: f64,
+}
+
+/// A `LoopbackNetwork` is a shared switchboard that connects every
+/// `Loopback` bound on it.  Cloning it gives another handle on the
+/// same network.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    nodes: Arc<Mutex<HashMap<SocketAddr, Endpoint>>>,
+    conditions: Conditions,
+    /// The clock by which packets are delayed.
+    clock: Arc<Clock>,
+    /// Packets on their way, with when they arrive and who sent them.
+    in_flight: Arc<Mutex<Vec<(u64, SocketAddr, RawEncryptedMessage)>>>,
+    /// Packets that have arrived, but that their endpoints have not
+    /// yet picked up.
+    queued: Arc<AtomicUsize>,
+}
+
+struct Endpoint {
+    incoming: Sender<RawEncryptedMessage>,
+    /// If the endpoint is behind a NAT, these are the addresses it has
+    /// sent to, which are the only ones allowed to reach it.
+    nat: Option<Arc<Mutex<HashSet<SocketAddr>>>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> LoopbackNetwork {
+        LoopbackNetwork::with_conditions(Conditions::default(), SystemClock::new())
+    }
+    /// A network that delays and loses packets according to
+    /// `conditions`, with the delays measured on `clock`.
+    pub fn with_conditions(conditions: Conditions, clock: Arc<Clock>) -> LoopbackNetwork {
+        LoopbackNetwork {
+            nodes: Arc::new(Mutex::new(HashMap::new())),
+            conditions: conditions,
+            clock: clock,
+            in_flight: Arc::new(Mutex::new(Vec::new())),
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    /// Attach a new endpoint to the network with address `addr`.
+    /// Like a real socket, this fails if the address is already taken.
+    pub fn bind(&self, addr: SocketAddr) -> Result<Loopback, Error> {
+        self.bind_endpoint(addr, None)
+    }
+    /// Attach a new endpoint that sits behind a NAT whose public
+    /// address is `public`.  Its packets appear to come from `public`,
+    /// and packets sent to `public` only get through if they come from
+    /// an address that the endpoint has sent to.
+    pub fn bind_behind_nat(&self, public: SocketAddr) -> Result<Loopback, Error> {
+        self.bind_endpoint(public, Some(Arc::new(Mutex::new(HashSet::new()))))
+    }
+    fn bind_endpoint(&self, addr: SocketAddr, nat: Option<Arc<Mutex<HashSet<SocketAddr>>>>)
+                     -> Result<Loopback, Error> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&addr) {
+            return Err(Error::new(ErrorKind::AddrInUse,
+                                  format!("loopback address {} in use", addr)));
+        }
+        let (tx, rx) = channel();
+        nodes.insert(addr, Endpoint { incoming: tx, nat: nat.clone() });
+        Ok(Loopback {
+            addr: addr,
+            network: self.clone(),
+            incoming: Mutex::new(rx),
+            nat: nat,
+        })
+    }
+    /// Send `msg` on its way from `from`, unless our conditions say
+    /// that it is lost.
+    fn send(&self, from: SocketAddr, msg: &RawEncryptedMessage) {
+        let c = self.conditions;
+        if c.loss > 0.0 && (crypto::random_u32() as f64) < c.loss*(::std::u32::MAX as f64) {
+            return;
+        }
+        if c.latency_ms == 0 && c.jitter_ms == 0 {
+            self.deliver(from, msg);
+            return;
+        }
+        let delay = c.latency_ms + crypto::random_u64() % (c.jitter_ms + 1);
+        let due = self.clock.monotonic_ms() + delay;
+        self.in_flight.lock().unwrap().push((due, from, *msg));
+    }
+    /// Deliver every packet whose time has come.  This happens
+    /// whenever an endpoint checks for packets, but someone moving a
+    /// fake clock along may want to call it right away.
+    pub fn deliver_due(&self) {
+        let now = self.clock.monotonic_ms();
+        let mut due: Vec<_> = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if !in_flight.iter().any(|p| p.0 <= now) {
+                return;
+            }
+            let all = ::std::mem::replace(&mut *in_flight, Vec::new());
+            let (due, later): (Vec<_>, Vec<_>) = all.into_iter().partition(|p| p.0 <= now);
+            *in_flight = later;
+            due
+        };
+        due.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, from, msg) in due {
+            self.deliver(from, &msg);
+        }
+    }
+    /// Whether every packet that is due has been picked up by its
+    /// endpoint, so that someone moving a fake clock along knows the
+    /// nodes have caught up before moving it again.
+    pub fn is_idle(&self) -> bool {
+        let now = self.clock.monotonic_ms();
+        self.queued.load(Ordering::SeqCst) == 0
+            && !self.in_flight.lock().unwrap().iter().any(|p| p.0 <= now)
+    }
+    /// Deliver `msg` to whoever is bound at `msg.ip`, claiming that it
+    /// came from `from`.  Just like UDP, a packet sent to an address
+    /// that nobody is listening on, or that a NAT refuses, is silently
+    /// dropped.
+    fn deliver(&self, from: SocketAddr, msg: &RawEncryptedMessage) {
+        let nodes = self.nodes.lock().unwrap();
+        if let Some(e) = nodes.get(&msg.ip) {
+            if let Some(ref allowed) = e.nat {
+                if !allowed.lock().unwrap().contains(&from) {
+                    return;
+                }
+            }
+            // We count it first, so that the count never drops below
+            // zero when the endpoint is quick to pick it up.
+            self.queued.fetch_add(1, Ordering::SeqCst);
+            if e.incoming.send(RawEncryptedMessage { ip: from, data: msg.data }).is_err() {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// One endpoint on a `LoopbackNetwork`.
+pub struct Loopback {
+    addr: SocketAddr,
+    network: LoopbackNetwork,
+    incoming: Mutex<Receiver<RawEncryptedMessage>>,
+    nat: Option<Arc<Mutex<HashSet<SocketAddr>>>>,
+}
+
+impl Loopback {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for Loopback {
+    fn send_packet(&self, msg: &RawEncryptedMessage) -> Result<(), Error> {
+        if let Some(ref allowed) = self.nat {
+            // Our NAT will now let in replies from msg.ip.
+            allowed.lock().unwrap().insert(msg.ip);
+        }
+        self.network.send(self.addr, msg);
+        Ok(())
+    }
+    fn recv_packet(&self) -> Result<RawEncryptedMessage, Error> {
+        self.network.deliver_due();
+        match self.incoming.lock().unwrap().recv_timeout(Duration::from_millis(POLL_MS)) {
+            Ok(m) => {
+                self.network.queued.fetch_sub(1, Ordering::SeqCst);
+                Ok(m)
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                Err(Error::new(ErrorKind::TimedOut, "no packet arrived"))
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::new(ErrorKind::NotConnected, "loopback network is gone"))
+            },
+        }
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        self.network.nodes.lock().unwrap().remove(&self.addr);
+        // Nobody will pick up what was left for us.
+        while self.incoming.lock().unwrap().try_recv().is_ok() {
+            self.network.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    use std::str::FromStr;
+    SocketAddr::from_str(s).unwrap()
+}
+
+#[test]
+fn loopback_delivers() {
+    let net = LoopbackNetwork::new();
+    let a = net.bind(addr("10.0.0.1:54321")).unwrap();
+    let b = net.bind(addr("10.0.0.2:54321")).unwrap();
+    assert!(::udp::is_timeout(&b.recv_packet().unwrap_err()));
+    let mut data = [0; ::udp::PACKET_LENGTH];
+    data[7] = 42;
+    a.send_packet(&RawEncryptedMessage { ip: b.local_addr(), data: data }).unwrap();
+    let got = b.recv_packet().unwrap();
+    assert_eq!(got.ip, a.local_addr());
+    assert_eq!(got.data[7], 42);
+}
+
+#[test]
+fn loopback_address_in_use() {
+    let net = LoopbackNetwork::new();
+    let a = net.bind(addr("10.0.0.1:54321")).unwrap();
+    assert!(net.bind(addr("10.0.0.1:54321")).is_err());
+    drop(a);
+    assert!(net.bind(addr("10.0.0.1:54321")).is_ok());
+}
+
+#[test]
+fn loopback_nat() {
+    let net = LoopbackNetwork::new();
+    let a = net.bind_behind_nat(addr("10.0.0.1:54321")).unwrap();
+    let b = net.bind(addr("10.0.0.2:54321")).unwrap();
+    let data = [3; ::udp::PACKET_LENGTH];
+    // The NAT drops packets from strangers...
+    b.send_packet(&RawEncryptedMessage { ip: a.local_addr(), data: data }).unwrap();
+    assert!(::udp::is_timeout(&a.recv_packet().unwrap_err()));
+    // ... but not replies.
+    a.send_packet(&RawEncryptedMessage { ip: b.local_addr(), data: data }).unwrap();
+    assert_eq!(b.recv_packet().unwrap().ip, a.local_addr());
+    b.send_packet(&RawEncryptedMessage { ip: a.local_addr(), data: data }).unwrap();
+    assert_eq!(a.recv_packet().unwrap().ip, b.local_addr());
+}
+
+#[test]
+fn loopback_conditions() {
+    let clock = ::clock::FakeClock::new(1000);
+    let slow = Conditions { latency_ms: 100, jitter_ms: 50, loss: 0.0 };
+    let net = LoopbackNetwork::with_conditions(slow, clock.clone());
+    let a = net.bind(addr("10.0.0.1:54321")).unwrap();
+    let b = net.bind(addr("10.0.0.2:54321")).unwrap();
+    let data = [5; ::udp::PACKET_LENGTH];
+    a.send_packet(&RawEncryptedMessage { ip: b.local_addr(), data: data }).unwrap();
+    assert!(::udp::is_timeout(&b.recv_packet().unwrap_err()));
+    clock.advance(99);
+    assert!(::udp::is_timeout(&b.recv_packet().unwrap_err()));
+    assert!(net.is_idle());
+    clock.advance(51);
+    assert!(!net.is_idle());
+    net.deliver_due();
+    assert!(!net.is_idle());
+    assert_eq!(b.recv_packet().unwrap().ip, a.local_addr());
+    assert!(net.is_idle());
+
+    let lossy = Conditions { loss: 1.0, .. Conditions::default() };
+    let net = LoopbackNetwork::with_conditions(lossy, clock.clone());
+    let a = net.bind(addr("10.0.0.1:54321")).unwrap();
+    let b = net.bind(addr("10.0.0.2:54321")).unwrap();
+    a.send_packet(&RawEncryptedMessage { ip: b.local_addr(), data: data }).unwrap();
+    assert!(::udp::is_timeout(&b.recv_packet().unwrap_err()));
+}
+
+#[test]
+fn loopback_listen_on() {
+    let net = LoopbackNetwork::new();
+    let a_addr = addr("10.0.0.1:54321");
+    let b_addr = addr("10.0.0.2:54321");
+    let halt = ::udp::Halt::new();
+    let limits = ::flood::FloodLimits::default();
+    let clock = ::clock::SystemClock::new();
+    let a = ::udp::listen_on(net.bind(a_addr).unwrap(), 10, limits, &clock, &halt);
+    let b = ::udp::listen_on(net.bind(b_addr).unwrap(), 10, limits, &clock, &halt);
+    b.send.send(RawEncryptedMessage { ip: a_addr, data: [1; ::udp::PACKET_LENGTH] }).unwrap();
+    let got = a.get.recv().unwrap();
+    assert_eq!(got.ip, b_addr);
+    assert_eq!(got.data[0], 1);
+    assert_eq!(a.flood.accepted.load(::std::sync::atomic::Ordering::SeqCst), 1);
+
+    // Once both threads are stopped, the addresses are free again.
+    halt.receiving.store(true, ::std::sync::atomic::Ordering::SeqCst);
+    for l in vec![a, b] {
+        drop(l.send);
+        l.sender.join().unwrap();
+        l.receiver.join().unwrap();
+    }
+    assert!(net.bind(a_addr).is_ok());
+    assert!(net.bind(b_addr).is_ok());
+}
@@ -5,17 +5,18 @@
 
 use onionsalt::crypto;
 use std;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 use dht;
-use dht::{UserMessage, EncryptedMessage,
-          MyBytes, DECRYPTED_USER_MESSAGE_LENGTH, USER_MESSAGE_LENGTH};
+use dht::{EncryptedMessage, DecodeError,
+          MyBytes, WireBytes, DECRYPTED_USER_MESSAGE_LENGTH, USER_MESSAGE_LENGTH};
 use message;
 use onionsalt::{PAYLOAD_LENGTH};
 
-use std::sync::mpsc::{ Receiver, SyncSender,
-                       Sender, };
-
 use str255::{Str255};
+use clock::{Clock, SystemClock};
+use config::{NodeConfig, Timing};
+use flood::FloodCounters;
 use serde;
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
@@ -156,7 +157,7 @@ impl std::fmt::Debug for Message {
         }
     }
 }
-impl MyBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
+impl WireBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
     fn bytes(&self, out: &mut[u8; DECRYPTED_USER_MESSAGE_LENGTH]) {
         match *self {
             Message::UserQuery { ref user } => {
@@ -189,15 +190,15 @@ impl MyBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
             },
         }
     }
-    fn from_bytes(inp: &[u8; DECRYPTED_USER_MESSAGE_LENGTH]) -> Message {
-        match inp[0] {
+    fn decode(inp: &[u8; DECRYPTED_USER_MESSAGE_LENGTH]) -> Result<Message, DecodeError> {
+        Ok(match inp[0] {
             b'q' => Message::UserQuery {
-                user: Str255::from_bytes(array_ref![inp,1,256]),
+                user: try!(Str255::decode(array_ref![inp,1,256])),
             },
             b'r' => {
                 let (_, u, k, _) = array_refs!(inp, 1, 256, 32, 126);
                 Message::UserResponse {
-                    user: Str255::from_bytes(u),
+                    user: try!(Str255::decode(u)),
                     key: crypto::PublicKey::from_bytes(k),
                 }
             },
@@ -217,8 +218,8 @@ impl MyBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
                     msg_id: message::Id::from_bytes(id),
                 }
             },
-            _ => Message::Acknowledge { msg_id: message::Id([0;32]) }
-        }
+            t => return Err(DecodeError::UnknownTag(t)),
+        })
     }
 }
 
@@ -226,7 +227,7 @@ impl MyBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
 fn test_message(m: Message) {
     let mut buf = [0; DECRYPTED_USER_MESSAGE_LENGTH];
     m.bytes(&mut buf);
-    let newm = Message::from_bytes(&buf);
+    let newm = Message::decode(&buf).unwrap();
     let mut buf2 = [0; DECRYPTED_USER_MESSAGE_LENGTH];
     newm.bytes(&mut buf2);
     for i in 0 .. buf.len() {
@@ -266,6 +267,24 @@ fn acknowledge_bytes() {
     });
 }
 
+#[test]
+fn recent_ids_forget_oldest() {
+    let id = |i: usize| {
+        let mut id = [0; 32];
+        id[0] = i as u8;
+        id[1] = (i >> 8) as u8;
+        message::Id(id)
+    };
+    let mut recent = RecentIds::new();
+    assert!(recent.insert(id(0)));
+    assert!(!recent.insert(id(0)));
+    for i in 1..MAX_RECENT_IDS+1 {
+        assert!(recent.insert(id(i)));
+    }
+    assert!(recent.insert(id(0)));
+    assert!(!recent.insert(id(MAX_RECENT_IDS)));
+}
+
 pub fn read_key(name: &std::path::Path) -> Result<crypto::PublicKey, std::io::Error> {
     use std::io::Read;
 
@@ -278,6 +297,34 @@ pub fn read_key(name: &std::path::Path) -> Result<crypto::PublicKey, std::io::Er
     Ok(crypto::PublicKey(*array_ref![data, 0, 32]))
 }
 
+/// How many message ids we remember, in order to drop the duplicates
+/// that we get from having several rendezvous nodes.
+const MAX_RECENT_IDS: usize = 4096;
+
+/// The ids of the messages we have received most recently.
+struct RecentIds {
+    ids: HashSet<message::Id>,
+    order: VecDeque<message::Id>,
+}
+
+impl RecentIds {
+    fn new() -> RecentIds {
+        RecentIds { ids: HashSet::new(), order: VecDeque::new() }
+    }
+    /// Remember `id`, returning false if we already knew it.
+    fn insert(&mut self, id: message::Id) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > MAX_RECENT_IDS {
+            let oldest = self.order.pop_front().unwrap();
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
+
 pub struct AddressBook {
     /// These are keys that we are willing to share with others who
     /// might query regarding them.  i.e. we are unashamed that we
@@ -286,13 +333,18 @@ pub struct AddressBook {
     /// These are keys that we do not want to share.  Secret
     /// identities or alter egos, etc.
     secret_ids: HashMap<String, crypto::PublicKey>,
-    unacknowledged: HashMap<message::Id, (crypto::PublicKey, [u8;USER_MESSAGE_LENGTH])>,
+    /// Messages that we are waiting to hear back about, along with
+    /// when we last sent each of them, on `clock`.
+    unacknowledged: HashMap<message::Id, (crypto::PublicKey, [u8;USER_MESSAGE_LENGTH], u64)>,
+    received: RecentIds,
     myself: crypto::KeyPair,
-    hear_rendezvous: Receiver<crypto::PublicKey>,
-    ask_rendezvous: SyncSender<crypto::PublicKey>,
-    message_sender: Sender<EncryptedMessage>,
-    message_receiver: Receiver<UserMessage>,
-    dir: std::path::PathBuf,
+    /// The node we talk to the network through.  It is shut down
+    /// when the address book is dropped.
+    node: dht::Node,
+    /// Where we keep our keys, if anywhere.
+    dir: Option<std::path::PathBuf>,
+    timing: Timing,
+    clock: Arc<Clock>,
 }
 
 impl AddressBook {
@@ -391,9 +443,25 @@ impl AddressBook {
         Ok((public_dir, secret_dir))
     }
 
+    /// How many packets our node has accepted, and how many it has
+    /// dropped because they arrived too fast.
+    pub fn flood_counters(&self) -> &FloodCounters {
+        self.node.flood_counters()
+    }
+
+    /// How much state our node is holding on behalf of others.
+    pub fn relay_state(&self) -> dht::RelayState {
+        self.node.relay_state()
+    }
+
+    /// What our node knows about the network, for working out why a
+    /// message did not get through.
+    pub fn node_stats(&self) -> dht::NodeStats {
+        self.node.stats()
+    }
+
     pub fn rendezvous(&self, k: &crypto::PublicKey) -> crypto::PublicKey {
-        self.ask_rendezvous.send(*k).unwrap();
-        self.hear_rendezvous.recv().unwrap()
+        self.node.rendezvous(k)
     }
 
     pub fn send(&mut self, who: &crypto::PublicKey, msg: &Message) -> message::Id {
@@ -406,7 +474,8 @@ impl AddressBook {
         self.send_doubleboxed(who, &msg_id, &c);
 
         if msg.needs_acknowledgement() {
-            self.unacknowledged.insert(msg_id, (*who, c));
+            let now = self.clock.monotonic_ms();
+            self.unacknowledged.insert(msg_id, (*who, c, now));
 
             let mut q = String::new();
             for k in self.unacknowledged.keys() {
@@ -417,26 +486,37 @@ impl AddressBook {
         msg_id
     }
     pub fn send_doubleboxed(&mut self, who: &crypto::PublicKey, msg_id: &message::Id, c: &[u8;USER_MESSAGE_LENGTH]) {
-        let ren = self.rendezvous(who);
-
         let mut p = [0; PAYLOAD_LENGTH];
         dht::Message::ForwardPlease {
             destination: *who,
             message: *c,
         }.bytes(&mut p);
 
+        // We leave a copy with each of the rendezvous nodes, so that
+        // the message survives one of them going away.
         info!("Sent message {}", dht::codename(&msg_id.0));
-        self.message_sender.send(EncryptedMessage {
-            rendezvous: ren,
-            contents: p,
-        }).unwrap();
+        for ren in self.node.rendezvous_replicas(who) {
+            self.node.send(EncryptedMessage {
+                rendezvous: ren,
+                contents: p,
+            });
+        }
     }
 
-    pub fn pickup(&mut self) {
-        let ren = self.rendezvous(&self.myself.public);
+    /// Ask each of our rendezvous nodes for a message it is holding
+    /// for us.
+    fn request_pickups(&mut self) {
+        for ren in self.node.rendezvous_replicas(&self.myself.public) {
+            self.request_pickup(&ren);
+        }
+    }
+    /// Ask a single rendezvous node for a message it is holding for
+    /// us.
+    fn request_pickup(&mut self, ren: &crypto::PublicKey) {
         // info!("   ═══ Sending pickup request to {}! ═══", ren);
-        let msg = [0; DECRYPTED_USER_MESSAGE_LENGTH];
-        let (_, c) = dht::double_box(&msg, &ren, &self.myself);
+        let mut msg = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+        msg[0] = dht::PICKUP_WANTS_DELIVERY;
+        let (_, c) = dht::double_box(&msg, ren, &self.myself);
         // info!("  E {} size {}", dht::codename(&c), c.len());
 
         let mut p = [0; PAYLOAD_LENGTH];
@@ -445,20 +525,31 @@ impl AddressBook {
             message: c,
         }.bytes(&mut p);
 
-        self.message_sender.send(EncryptedMessage {
-            rendezvous: ren,
+        self.node.send(EncryptedMessage {
+            rendezvous: *ren,
             contents: p,
-        }).unwrap();
+        });
+    }
 
-        let num_unacknowledged = self.unacknowledged.len();
-        if num_unacknowledged > 0 {
+    pub fn pickup(&mut self) {
+        self.request_pickups();
+
+        // We only retry messages that have had a fair chance to be
+        // acknowledged.
+        let now = self.clock.monotonic_ms();
+        let retry_period_ms = self.timing.retry_period_ms;
+        let num_due = self.unacknowledged.values()
+            .filter(|v| v.2 + retry_period_ms <= now).count();
+        if num_due > 0 {
             // The following is a ridiculous contortion to get around
             // the borrow checker.  This is one trouble with grouping
             // several data structures into an object in rust.  A
             // borrow on any is a borrow on all.  :( To quote the
             // Fullmetal Alchemist: One is all, and all is one.
             let somev: Option<(message::Id, crypto::PublicKey, [u8;USER_MESSAGE_LENGTH])> = {
-                let somev = self.unacknowledged.iter().nth(crypto::random_u32() as usize % num_unacknowledged);
+                let somev = self.unacknowledged.iter()
+                    .filter(|&(_, v)| v.2 + retry_period_ms <= now)
+                    .nth(crypto::random_u32() as usize % num_due);
                 match somev {
                     None => None,
                     Some((a,b)) => Some((a.clone(),b.0.clone(),b.1)),
@@ -467,22 +558,52 @@ impl AddressBook {
             if let Some(v) = somev {
                 info!("I am going to retry...");
                 self.send_doubleboxed(&v.1,&v.0,&v.2);
+                if let Some(u) = self.unacknowledged.get_mut(&v.0) {
+                    u.2 = now;
+                }
             }
         }
     }
 
     pub fn listen(&mut self) -> Option<(crypto::PublicKey, message::Id, Message)> {
-        if let Ok(m) = self.message_receiver.try_recv() {
+        if let Some(m) = self.node.try_receive() {
             if m.destination != self.myself.public {
                 return None;
             }
+            if m.remaining > 0 {
+                // Our rendezvous node has more for us, so there is no
+                // point in waiting for our next regular pickup.  The
+                // other replicas hold their own backlogs, and will say
+                // so themselves.
+                if let Some(ren) = m.rendezvous {
+                    self.request_pickup(&ren);
+                }
+            }
             if let Ok((k, msg_id, data)) = dht::double_unbox(&m.message, &self.myself.secret) {
                 // println!("\r\n ****** \"{}\" ****** {}\r\n", dht::codename(&m.message),
                 //          dht::codename(&m.message[32+24 .. 32+24+6]));
                 // println!("\r\nlisten is decrypted to \"{}\" a.k.a. {:?}\r\n",
                 //          dht::codename(&data), &data[0..7]);
 
-                let m = Message::from_bytes(&data);
+                let m = match Message::decode(&data) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        info!("Dropping bad message {} from {}: {}",
+                              dht::codename(&msg_id.0), dht::codename(&k.0), e);
+                        return None;
+                    },
+                };
+                if !self.received.insert(msg_id) {
+                    // Another rendezvous node already gave us this
+                    // one.  It may be a retry because our
+                    // acknowledgement was lost, so we acknowledge it
+                    // again.
+                    info!("Dropping duplicate of message {}", dht::codename(&msg_id.0));
+                    if m.needs_acknowledgement() {
+                        self.send(&k, &Message::Acknowledge { msg_id: msg_id });
+                    }
+                    return None;
+                }
                 match m {
                     Message::Acknowledge { msg_id } => {
                         if self.unacknowledged.contains_key(&msg_id) {
@@ -506,25 +627,26 @@ impl AddressBook {
         None
     }
 
-    pub fn read(the_dir: &std::path::PathBuf) -> Result<AddressBook, std::io::Error> {
+    pub fn read(the_dir: &std::path::PathBuf, config: &NodeConfig)
+                -> Result<AddressBook, std::io::Error> {
         let my_personal_key = {
             let mut name = the_dir.clone();
             name.push("personal.key");
             dht::read_or_generate_keypair(name).unwrap()
         };
         let (public_dir, secret_dir) = try!(AddressBook::public_secret_dirs(the_dir));
-        let (ask_rendezvous, hear_rendezvous, send, receive) = try!(dht::start_static_node(the_dir));
+        let node = try!(dht::start_static_node(the_dir, config));
 
         let mut ab = AddressBook {
             public_ids: HashMap::new(),
             secret_ids: HashMap::new(),
             unacknowledged: HashMap::new(),
+            received: RecentIds::new(),
             myself: my_personal_key,
-            ask_rendezvous: ask_rendezvous,
-            hear_rendezvous: hear_rendezvous,
-            message_sender: send,
-            message_receiver: receive,
-            dir: the_dir.clone(),
+            node: node,
+            dir: Some(the_dir.clone()),
+            timing: config.timing,
+            clock: SystemClock::new(),
         };
         ab.public_ids.insert("knightley".to_string(),
                              crypto::PublicKey([140, 132, 104, 138, 2, 247, 127, 186, 197, 203, 29,
@@ -572,9 +694,37 @@ impl AddressBook {
 
         Ok(ab)
     }
+    /// An address book that talks to the network through `node`,
+    /// and is kept only in memory.  Its notion of time comes from
+    /// `clock`, which should be the one `node` runs on.
+    pub fn on_node(myself: crypto::KeyPair, node: dht::Node, config: &NodeConfig,
+                   clock: Arc<Clock>) -> AddressBook {
+        let mut ab = AddressBook {
+            public_ids: HashMap::new(),
+            secret_ids: HashMap::new(),
+            unacknowledged: HashMap::new(),
+            received: RecentIds::new(),
+            myself: myself,
+            node: node,
+            dir: None,
+            timing: config.timing,
+            clock: clock,
+        };
+        ab.secret_ids.insert("myself".to_string(), myself.public);
+        ab
+    }
+    /// How many of the messages we sent have not yet been
+    /// acknowledged.
+    pub fn num_unacknowledged(&self) -> usize {
+        self.unacknowledged.len()
+    }
     pub fn write(&self) -> Result<(), std::io::Error> {
         use std::io::Write;
-        let (public_dir, secret_dir) = try!(AddressBook::public_secret_dirs(&self.dir));
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return Ok(()),
+        };
+        let (public_dir, secret_dir) = try!(AddressBook::public_secret_dirs(dir));
         for s in self.public_ids.keys() {
             let mut name = public_dir.clone();
             name.push(s);
@@ -601,6 +751,9 @@ impl AddressBook {
 
 impl Drop for AddressBook {
     fn drop(&mut self) {
+        if self.dir.is_none() {
+            return;
+        }
         if self.write().is_ok() {
             println!("Wrote addressbook successfully!");
         } else {
@@ -0,0 +1,132 @@
+//! How well each relay has been doing at passing our packets along.
+//! Every greeting and maintenance packet we send out along a route
+//! should come back to us, so each hop of the route gets credit when
+//! it does, along with how long the round trip took.  We leave user
+//! messages out of it, since whether and when those are answered is
+//! up to their rendezvous relay, e.g. a pickup waits until there is
+//! something to pick up.  We cannot tell which hop lost a packet that
+//! never comes back, so all of them share the blame, but a relay that
+//! is really at fault will show up on many more failed routes than
+//! one that is merely unlucky.
+//!
+//! Note that a round trip includes the delays we ask each relay to add
+//! (see `Timing`), so round-trip times are only worth comparing
+//! between relays, not with the network latency.
+
+use std;
+
+/// How many packets we remember for each relay, after which old ones
+/// count for less, so that a relay that used to be good (or bad) does
+/// not coast on its history.
+const MAX_REMEMBERED: u32 = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quality {
+    /// How many packets we have sent through this relay, including
+    /// those we are still waiting to hear back about...
+    pub sent: u32,
+    /// ... and how many of them came back.
+    pub answered: u32,
+    /// The smoothed round-trip time of the routes through this relay,
+    /// once we have any.
+    pub rtt_ms: Option<u64>,
+}
+
+impl Quality {
+    pub fn sent(&mut self) {
+        self.sent += 1;
+        if self.sent > MAX_REMEMBERED {
+            self.sent /= 2;
+            self.answered /= 2;
+        }
+    }
+    pub fn answered(&mut self, rtt_ms: u64) {
+        // A response may come back after we stopped counting the
+        // packet it answers.
+        if self.answered < self.sent {
+            self.answered += 1;
+        }
+        // The same smoothing that TCP uses.
+        self.rtt_ms = Some(match self.rtt_ms {
+            None => rtt_ms,
+            Some(old) => (7*old + rtt_ms)/8,
+        });
+    }
+    /// The fraction of packets that come back through this relay.  We
+    /// start from a guess of one half, so that a relay we have tried
+    /// once is not yet judged perfect (or hopeless).
+    pub fn success_ratio(&self) -> f64 {
+        (self.answered as f64 + 1.0)/(self.sent as f64 + 2.0)
+    }
+    /// How much we would like to route through this relay, compared
+    /// with others whose typical round-trip time is `typical_rtt_ms`.
+    /// A relay that is much slower than usual counts for less, but
+    /// no relay is ruled out entirely, so that we keep finding out
+    /// about them.
+    pub fn weight(&self, typical_rtt_ms: Option<u64>) -> f64 {
+        let speed = match (self.rtt_ms, typical_rtt_ms) {
+            (Some(rtt), Some(typical)) if rtt > 2*typical => {
+                2.0*typical as f64/rtt as f64
+            },
+            _ => 1.0,
+        };
+        let w = self.success_ratio()*speed;
+        if w < 0.05 { 0.05 } else { w }
+    }
+}
+
+/// The median round-trip time among `qs`, which is what we compare
+/// each relay's with.
+pub fn typical_rtt_ms<'a, I: Iterator<Item=&'a Quality>>(qs: I) -> Option<u64> {
+    let mut rtts: Vec<u64> = qs.filter_map(|q| q.rtt_ms).collect();
+    if rtts.is_empty() {
+        return None;
+    }
+    rtts.sort();
+    Some(rtts[rtts.len()/2])
+}
+
+/// Choose an index at random, with probability proportional to its
+/// weight, given a random `r` in [0, 1).
+pub fn pick_weighted(weights: &[f64], r: f64) -> usize {
+    let total = weights.iter().fold(0.0, |t, w| t + w);
+    let mut x = r*total;
+    for (i, w) in weights.iter().enumerate() {
+        if x < *w {
+            return i;
+        }
+        x -= *w;
+    }
+    std::cmp::max(weights.len(), 1) - 1
+}
+
+#[test]
+fn quality_tracks_relays() {
+    let mut good = Quality::default();
+    let mut flaky = Quality::default();
+    let mut slow = Quality::default();
+    assert_eq!(good.success_ratio(), 0.5);
+    for i in 0..200 {
+        good.sent();
+        good.answered(1000);
+        flaky.sent();
+        if i % 4 == 0 {
+            flaky.answered(1000);
+        }
+        slow.sent();
+        slow.answered(10000);
+    }
+    assert!(good.sent <= MAX_REMEMBERED);
+    assert!(good.success_ratio() > 0.95);
+    assert!(flaky.success_ratio() < 0.35);
+    assert_eq!(good.rtt_ms, Some(1000));
+    let typical = typical_rtt_ms(vec![good, flaky, slow].iter());
+    assert_eq!(typical, Some(1000));
+    assert!(good.weight(typical) > flaky.weight(typical));
+    assert!(good.weight(typical) > slow.weight(typical));
+    assert!(slow.weight(typical) >= 0.05);
+
+    assert_eq!(pick_weighted(&[1.0, 0.0, 3.0], 0.0), 0);
+    assert_eq!(pick_weighted(&[1.0, 0.0, 3.0], 0.3), 2);
+    assert_eq!(pick_weighted(&[1.0, 0.0, 3.0], 0.999), 2);
+}
@@ -0,0 +1,233 @@
+//! A Kademlia-style routing table.  We measure the distance between
+//! two keys as their XOR, read as a 256-bit big-endian number, and
+//! keep the keys we know about in buckets according to how many
+//! leading bits they share with our own key.  Each bucket holds at
+//! most `BUCKET_SIZE` keys, so we know many nodes near us and a few
+//! nodes in every other part of the key space, which is enough to
+//! find the closest nodes to any key quickly.
+//!
+//! The table only holds keys.  Addresses and liveness stay in the
+//! `DHT`, which tells us which keys are live when a bucket is full.
+
+use std;
+
+use onionsalt::crypto;
+
+/// How many keys each bucket can hold.
+pub const BUCKET_SIZE: usize = 20;
+
+const NUM_BUCKETS: usize = 256;
+
+/// The XOR distance between two keys.  Comparing distances compares
+/// the full 256 bits, most significant byte first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Distance(pub [u8; 32]);
+
+pub fn distance(a: &crypto::PublicKey, b: &crypto::PublicKey) -> Distance {
+    let mut out = [0; 32];
+    for i in 0..32 {
+        out[i] = a.0[i] ^ b.0[i];
+    }
+    Distance(out)
+}
+
+impl Distance {
+    /// The number of leading zero bits.
+    fn leading_zeros(&self) -> usize {
+        for i in 0..32 {
+            if self.0[i] != 0 {
+                return i*8 + self.0[i].leading_zeros() as usize;
+            }
+        }
+        NUM_BUCKETS
+    }
+}
+
+/// What happened when we tried to add a key to the table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Insertion {
+    /// The key is in the table (possibly it already was).
+    Added,
+    /// The key is in the table, and the given dead key was evicted to
+    /// make room for it.
+    Evicted(crypto::PublicKey),
+    /// The key's bucket is full of live keys, so we left it out.
+    Full,
+}
+
+pub struct RoutingTable {
+    me: crypto::PublicKey,
+    /// Bucket `i` holds the keys that share exactly `i` leading bits
+    /// with ours, least recently seen first.
+    buckets: Vec<Vec<crypto::PublicKey>>,
+    /// When each bucket last had a key added or seen, in monotonic
+    /// ms.
+    touched_ms: Vec<u64>,
+}
+
+impl RoutingTable {
+    pub fn new(me: &crypto::PublicKey) -> RoutingTable {
+        RoutingTable {
+            me: *me,
+            buckets: (0..NUM_BUCKETS).map(|_| Vec::new()).collect(),
+            touched_ms: vec![0; NUM_BUCKETS],
+        }
+    }
+    fn bucket_index(&self, k: &crypto::PublicKey) -> usize {
+        distance(&self.me, k).leading_zeros()
+    }
+    pub fn len(&self) -> usize {
+        self.buckets.iter().fold(0, |n, b| n + b.len())
+    }
+    pub fn contains(&self, k: &crypto::PublicKey) -> bool {
+        *k != self.me && self.buckets[self.bucket_index(k)].contains(k)
+    }
+    /// Add `k` to the table.  If its bucket is full, we evict the
+    /// least recently seen key for which `is_live` is false.  If there
+    /// is none, we keep the keys we have, since nodes that have been
+    /// up for a while are the ones most likely to stay up.
+    pub fn insert<F>(&mut self, k: &crypto::PublicKey, now_ms: u64, is_live: F) -> Insertion
+        where F: Fn(&crypto::PublicKey) -> bool
+    {
+        assert!(*k != self.me, "we do not belong in our own routing table");
+        if self.contains(k) {
+            self.touch(k, now_ms);
+            return Insertion::Added;
+        }
+        let i = self.bucket_index(k);
+        self.touched_ms[i] = now_ms;
+        let bucket = &mut self.buckets[i];
+        if bucket.len() < BUCKET_SIZE {
+            bucket.push(*k);
+            return Insertion::Added;
+        }
+        match bucket.iter().position(|b| !is_live(b)) {
+            Some(dead) => {
+                let old = bucket.remove(dead);
+                bucket.push(*k);
+                Insertion::Evicted(old)
+            },
+            None => Insertion::Full,
+        }
+    }
+    pub fn remove(&mut self, k: &crypto::PublicKey) {
+        if *k != self.me {
+            let i = self.bucket_index(k);
+            self.buckets[i].retain(|b| b != k);
+        }
+    }
+    /// Note that we have just heard from `k`.
+    pub fn touch(&mut self, k: &crypto::PublicKey, now_ms: u64) {
+        if *k == self.me {
+            return;
+        }
+        let i = self.bucket_index(k);
+        if let Some(pos) = self.buckets[i].iter().position(|b| b == k) {
+            let k = self.buckets[i].remove(pos);
+            self.buckets[i].push(k);
+            self.touched_ms[i] = now_ms;
+        }
+    }
+    /// Find a bucket that we have not heard anything from in
+    /// `max_age_ms`, and return its least recently seen key, which is
+    /// the one most worth checking up on.  The bucket then counts as
+    /// touched, so we do not keep picking the same one.
+    pub fn stale(&mut self, now_ms: u64, max_age_ms: u64) -> Option<crypto::PublicKey> {
+        for i in 0..NUM_BUCKETS {
+            if self.buckets[i].len() > 0 && self.touched_ms[i] + max_age_ms < now_ms {
+                self.touched_ms[i] = now_ms;
+                return Some(self.buckets[i][0]);
+            }
+        }
+        None
+    }
+    /// The (up to) `n` keys closest to `target`, closest first.
+    pub fn closest(&self, target: &crypto::PublicKey, n: usize) -> Vec<crypto::PublicKey> {
+        let by_distance = |a: &crypto::PublicKey, b: &crypto::PublicKey| {
+            distance(a, target).cmp(&distance(b, target))
+        };
+        let i = std::cmp::min(self.bucket_index(target), NUM_BUCKETS - 1);
+        // The keys in bucket `i` are closer to `target` than any
+        // others, followed by those in the buckets beyond it, all of
+        // which are at about the same distance from `target` as we
+        // are.  Then come buckets `i-1`, `i-2` and so on, each of
+        // which is further away than the one before.
+        let mut out: Vec<crypto::PublicKey> = self.buckets[i..].iter()
+            .flat_map(|b| b.iter().cloned()).collect();
+        out.sort_by(&by_distance);
+        for j in (0..i).rev() {
+            if out.len() >= n {
+                break;
+            }
+            let mut bucket = self.buckets[j].clone();
+            bucket.sort_by(&by_distance);
+            out.extend(bucket);
+        }
+        out.truncate(n);
+        out
+    }
+}
+
+#[cfg(test)]
+fn key_with_prefix(me: &crypto::PublicKey, shared_bits: usize) -> crypto::PublicKey {
+    // A random key sharing exactly `shared_bits` leading bits with
+    // `me`.
+    let mut k = crypto::box_keypair().public;
+    for b in 0..shared_bits + 1 {
+        let mask = 0x80 >> (b % 8);
+        let mine = me.0[b/8] & mask;
+        let flip = if b == shared_bits { mask } else { 0 };
+        k.0[b/8] = (k.0[b/8] & !mask) | (mine ^ flip);
+    }
+    k
+}
+
+#[test]
+fn closest_matches_linear_scan() {
+    let me = crypto::box_keypair().public;
+    let mut table = RoutingTable::new(&me);
+    let mut all = Vec::new();
+    for i in 0..300 {
+        // Most random keys land in the first few buckets, so we help
+        // the deeper ones along.
+        let k = if i % 2 == 0 { crypto::box_keypair().public } else { key_with_prefix(&me, i % 16) };
+        if table.insert(&k, 0, |_| true) != Insertion::Full {
+            all.push(k);
+        }
+    }
+    assert_eq!(table.len(), all.len());
+    for _ in 0..50 {
+        let target = crypto::box_keypair().public;
+        let mut expected = all.clone();
+        expected.sort_by(|a, b| distance(a, &target).cmp(&distance(b, &target)));
+        expected.truncate(BUCKET_SIZE);
+        assert_eq!(table.closest(&target, BUCKET_SIZE), expected);
+    }
+    // Looking for a key that we know finds that very key.
+    assert_eq!(table.closest(&all[7], 1), vec![all[7]]);
+}
+
+#[test]
+fn full_bucket_evicts_dead() {
+    let me = crypto::box_keypair().public;
+    let mut table = RoutingTable::new(&me);
+    let keys: Vec<_> = (0..BUCKET_SIZE).map(|_| key_with_prefix(&me, 3)).collect();
+    for k in keys.iter() {
+        assert_eq!(table.insert(k, 0, |_| true), Insertion::Added);
+    }
+    let newcomer = key_with_prefix(&me, 3);
+    assert_eq!(table.insert(&newcomer, 0, |_| true), Insertion::Full);
+    assert!(!table.contains(&newcomer));
+    // Once keys[0] has been seen, keys[1] is the least recently seen.
+    table.touch(&keys[0], 10);
+    assert_eq!(table.insert(&newcomer, 20, |k| *k == keys[5]), Insertion::Evicted(keys[1]));
+    let newcomer2 = key_with_prefix(&me, 3);
+    assert_eq!(table.insert(&newcomer2, 20, |k| *k != keys[4]), Insertion::Evicted(keys[4]));
+    assert!(table.contains(&newcomer) && table.contains(&newcomer2));
+    assert_eq!(table.len(), BUCKET_SIZE);
+
+    // That bucket is the only one, so it is the one that goes stale.
+    assert_eq!(table.stale(100, 1000), None);
+    assert_eq!(table.stale(1100, 1000), Some(keys[2]));
+    assert_eq!(table.stale(1100, 1000), None);
+}
@@ -0,0 +1,174 @@
+//! The queue of packets waiting to be sent.  We send exactly one packet
+//! per send period, so that an observer learns nothing from when we
+//! send.  Each tick, we send the earliest packet that is due, or else a
+//! maintenance packet, so packets that become due at the same time
+//! simply wait their turn.
+//!
+//! Packets come in two priorities.  A `Steadfast` packet (e.g. one we
+//! are relaying) is sent however late it is.  A `Convenient` packet
+//! (e.g. the response to a whoami) is only worth sending on time, so
+//! it is dropped if it misses its tick by more than `slack_ms`.  If we
+//! ever have more than `capacity` packets waiting, we drop the one that
+//! matters least: a `Convenient` packet if there is one, and otherwise
+//! the one that would be sent last.
+
+use std;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use udp::RawEncryptedMessage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Convenient,
+    Steadfast,
+}
+
+struct Scheduled {
+    /// When this is due, on our monotonic clock.
+    due_ms: u64,
+    /// The order in which packets were scheduled, which breaks ties.
+    seq: u64,
+    priority: Priority,
+    msg: RawEncryptedMessage,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        (self.due_ms, self.seq) == (other.due_ms, other.seq)
+    }
+}
+impl Eq for Scheduled {}
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Scheduled) -> Ordering {
+        // A BinaryHeap gives us the greatest element first, and we
+        // want the earliest.
+        (other.due_ms, other.seq).cmp(&(self.due_ms, self.seq))
+    }
+}
+
+pub struct Scheduler {
+    queue: BinaryHeap<Scheduled>,
+    next_seq: u64,
+    capacity: usize,
+    slack_ms: u64,
+    /// How many packets we dropped because too many were waiting.
+    pub dropped_full: usize,
+    /// How many `Convenient` packets we dropped for being late.
+    pub dropped_late: usize,
+}
+
+impl Scheduler {
+    pub fn new(capacity: usize, slack_ms: u64) -> Scheduler {
+        Scheduler {
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+            capacity: capacity,
+            slack_ms: slack_ms,
+            dropped_full: 0,
+            dropped_late: 0,
+        }
+    }
+    /// How many packets are waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+    /// How many of the waiting packets are already due, which is the
+    /// number of ticks we are behind.
+    pub fn overdue(&self, now_ms: u64) -> usize {
+        self.queue.iter().filter(|s| s.due_ms <= now_ms).count()
+    }
+    pub fn schedule(&mut self, due_ms: u64, priority: Priority, msg: &RawEncryptedMessage) {
+        self.queue.push(Scheduled {
+            due_ms: due_ms,
+            seq: self.next_seq,
+            priority: priority,
+            msg: *msg,
+        });
+        self.next_seq += 1;
+        if self.queue.len() > self.capacity {
+            let mut all = std::mem::replace(&mut self.queue, BinaryHeap::new()).into_vec();
+            let worst = (0..all.len()).max_by_key(|&i| {
+                (all[i].priority == Priority::Convenient, all[i].due_ms, all[i].seq)
+            }).unwrap();
+            all.swap_remove(worst);
+            self.queue = all.into_iter().collect();
+            self.dropped_full += 1;
+        }
+    }
+    /// The packet to send now, if any is due.
+    pub fn pop(&mut self, now_ms: u64) -> Option<RawEncryptedMessage> {
+        loop {
+            match self.queue.peek() {
+                Some(s) if s.due_ms <= now_ms => (),
+                _ => return None,
+            }
+            let s = self.queue.pop().unwrap();
+            if s.priority == Priority::Convenient && s.due_ms + self.slack_ms < now_ms {
+                self.dropped_late += 1;
+                continue;
+            }
+            return Some(s.msg);
+        }
+    }
+    /// Take every waiting packet, earliest first, e.g. when shutting
+    /// down.
+    pub fn drain(&mut self) -> Vec<RawEncryptedMessage> {
+        let mut out = Vec::with_capacity(self.queue.len());
+        while let Some(s) = self.queue.pop() {
+            out.push(s.msg);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+fn packet(n: u8) -> RawEncryptedMessage {
+    use std::str::FromStr;
+    RawEncryptedMessage {
+        ip: std::net::SocketAddr::from_str("10.0.0.1:54321").unwrap(),
+        data: [n; ::udp::PACKET_LENGTH],
+    }
+}
+
+#[test]
+fn one_packet_per_tick() {
+    let mut s = Scheduler::new(10, 1000);
+    s.schedule(2000, Priority::Steadfast, &packet(2));
+    s.schedule(1000, Priority::Steadfast, &packet(1));
+    s.schedule(1000, Priority::Steadfast, &packet(3));
+    assert!(s.pop(500).is_none());
+    assert_eq!(s.overdue(2000), 3);
+    // Packets that are due at once go out on successive ticks.
+    assert_eq!(s.pop(2000).unwrap().data[0], 1);
+    assert_eq!(s.pop(3000).unwrap().data[0], 3);
+    assert_eq!(s.pop(4000).unwrap().data[0], 2);
+    assert!(s.pop(5000).is_none());
+}
+
+#[test]
+fn drop_policy() {
+    let mut s = Scheduler::new(3, 1000);
+    s.schedule(1000, Priority::Convenient, &packet(1));
+    s.schedule(1000, Priority::Steadfast, &packet(2));
+    s.schedule(5000, Priority::Steadfast, &packet(3));
+    // When full, the convenient packet goes first...
+    s.schedule(2000, Priority::Steadfast, &packet(4));
+    assert_eq!((s.len(), s.dropped_full), (3, 1));
+    // ... and then the one that would be sent last.
+    s.schedule(3000, Priority::Steadfast, &packet(5));
+    assert_eq!(s.dropped_full, 2);
+    assert_eq!(s.drain().iter().map(|p| p.data[0]).collect::<Vec<_>>(), vec![2, 4, 5]);
+
+    // A convenient packet that misses its tick is dropped.
+    s.schedule(1000, Priority::Steadfast, &packet(6));
+    s.schedule(1000, Priority::Convenient, &packet(7));
+    assert_eq!(s.pop(1000).unwrap().data[0], 6);
+    assert!(s.pop(2500).is_none());
+    assert_eq!(s.dropped_late, 1);
+}
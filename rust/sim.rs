@@ -0,0 +1,313 @@
+//! A whole pmail network in one process, for seeing whether messages
+//! get through without needing the real relays.  We start a number of
+//! relays and users on a `loopback::LoopbackNetwork`, all running on
+//! one `clock::FakeClock`, which we move along faster than real time.
+//! The users send one another messages through their `AddressBook`s,
+//! and we report how many arrived, and how long they took.
+//!
+//! The network can be made to delay and lose packets, some of the
+//! users can be put behind NATs, and relays can be made to go away
+//! and be replaced by new ones (with new keys) while all this goes on.
+
+use std;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::collections::HashMap;
+use std::thread::sleep;
+use std::time::Duration;
+
+use onionsalt::crypto;
+
+use clock::{Clock, FakeClock};
+use config::{NodeConfig, Timing};
+use dht;
+use dht::RoutingGift;
+use loopback::{Conditions, LoopbackNetwork};
+use message;
+use pmail::{AddressBook, Message, Thread};
+use udp;
+use work;
+
+/// How many of the relays everyone else bootstraps from.  These never
+/// go away.
+const NUM_BOOTSTRAP: usize = 3;
+
+/// The longest we wait in real time for the nodes to pick up the
+/// packets of a single step.
+const MAX_CATCH_UP_MS: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// How many relays to run, the first few of which are the
+    /// bootstrap relays.
+    pub relays: usize,
+    /// How many users send one another messages.
+    pub users: usize,
+    /// What the network does to packets.
+    pub conditions: Conditions,
+    /// The fraction of users that are behind a NAT.
+    pub nat_fraction: f64,
+    /// How often (in simulated ms) one of the relays that is not a
+    /// bootstrap relay goes away, to be replaced by a new one.
+    pub churn_period_ms: Option<u64>,
+    /// How often each user sends a message.
+    pub message_period_ms: u64,
+    /// How long we let the network settle before anyone sends
+    /// anything.
+    pub warmup_ms: u64,
+    /// How long the users keep sending messages.
+    pub duration_ms: u64,
+    /// How long we wait after that for the last messages to arrive.
+    pub drain_ms: u64,
+    /// How far we move the clock at a time...
+    pub step_ms: u64,
+    /// ... and how long we give the nodes in real time to deal with
+    /// each step, once they have picked up every packet that arrived
+    /// during it.
+    pub real_ms_per_step: u64,
+    /// The configuration of every node.  Its bootstrap relays are
+    /// ignored, since we make our own.
+    pub node: NodeConfig,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            relays: 20,
+            users: 4,
+            conditions: Conditions { latency_ms: 20, jitter_ms: 20, loss: 0.01 },
+            nat_fraction: 0.5,
+            churn_period_ms: None,
+            message_period_ms: 30*1000,
+            warmup_ms: 60*1000,
+            duration_ms: 5*60*1000,
+            drain_ms: 60*1000,
+            step_ms: 20,
+            real_ms_per_step: 2,
+            node: NodeConfig {
+                timing: Timing::profile("lan-test").unwrap(),
+                // Our relays are all on one network, and we have no
+                // time to spend making keys.
+                key_difficulty: 0,
+                distinct_networks: false,
+                key_lifetime_secs: None,
+                .. NodeConfig::default()
+            },
+        }
+    }
+}
+
+/// What happened to the messages sent during a simulation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub sent: usize,
+    /// How many messages arrived (once each, however many copies we
+    /// were given).
+    pub delivered: usize,
+    /// How many of the senders heard back that their message arrived.
+    pub acknowledged: usize,
+    /// How long each delivered message took, in simulated ms.
+    pub latencies_ms: Vec<u64>,
+    /// How many relays were replaced along the way.
+    pub relays_replaced: usize,
+}
+
+impl Report {
+    /// The fraction of messages sent that arrived.
+    pub fn delivery_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.delivered as f64 / self.sent as f64
+    }
+    /// The latency below which a fraction `p` of messages arrived.
+    pub fn latency_percentile_ms(&self, p: f64) -> Option<u64> {
+        if self.latencies_ms.is_empty() {
+            return None;
+        }
+        let mut l = self.latencies_ms.clone();
+        l.sort();
+        let i = std::cmp::min((p*l.len() as f64) as usize, l.len() - 1);
+        Some(l[i])
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(writeln!(f, "sent {} messages, {} delivered ({:.1}%), {} acknowledged",
+                      self.sent, self.delivered, 100.0*self.delivery_rate(), self.acknowledged));
+        let secs = |p| self.latency_percentile_ms(p).unwrap_or(0) as f64/1000.0;
+        if !self.latencies_ms.is_empty() {
+            try!(writeln!(f, "latency: median {:.1}s, 90th percentile {:.1}s, max {:.1}s",
+                          secs(0.5), secs(0.9), secs(1.0)));
+        }
+        write!(f, "{} relays replaced", self.relays_replaced)
+    }
+}
+
+struct User {
+    book: AddressBook,
+    next_pickup_ms: u64,
+    next_send_ms: u64,
+}
+
+fn relay_addr(i: usize) -> SocketAddr {
+    SocketAddr::from_str(&format!("10.0.{}.{}:54321", i/200, i%200 + 1)).unwrap()
+}
+
+fn user_addr(i: usize) -> SocketAddr {
+    SocketAddr::from_str(&format!("192.168.{}.{}:40000", i/200, i%200 + 1)).unwrap()
+}
+
+/// A random number of ms below `n`.
+fn random_ms(n: u64) -> u64 {
+    crypto::random_u64() % std::cmp::max(n, 1)
+}
+
+/// Run a simulation, which takes about `real_ms_per_step/step_ms` of
+/// the simulated time in real time, or longer if the nodes cannot
+/// keep up.  We wait for them rather than letting packets pile up, so
+/// that what gets through does not depend on how busy the machine is.
+pub fn run(config: &SimConfig) -> Report {
+    assert!(config.relays >= NUM_BOOTSTRAP, "we need at least {} relays", NUM_BOOTSTRAP);
+    assert!(config.users >= 2, "we need someone to send messages to");
+    let clock = FakeClock::new(udp::now_ms());
+    let net = LoopbackNetwork::with_conditions(config.conditions, clock.clone());
+    let start_relay = |i: usize, key: crypto::KeyPair, bootstrap: &Vec<RoutingGift>| {
+        let transport = net.bind(relay_addr(i)).unwrap();
+        dht::start_node_among(key, transport, &config.node, clock.clone(), bootstrap.clone())
+            .unwrap()
+    };
+
+    let keys: Vec<_> = (0..config.relays).map(|_| work::generate_keypair(config.node.key_difficulty))
+        .collect();
+    let bootstrap: Vec<_> = (0..NUM_BOOTSTRAP).map(|i| RoutingGift {
+        addr: relay_addr(i),
+        key: keys[i].public,
+    }).collect();
+    let mut relays: Vec<_> = keys.iter().enumerate()
+        .map(|(i, k)| Some(start_relay(i, *k, &bootstrap))).collect();
+
+    let start = clock.monotonic_ms();
+    let send_from = start + config.warmup_ms;
+    let send_until = send_from + config.duration_ms;
+    let end = send_until + config.drain_ms;
+    let num_natted = (config.nat_fraction*config.users as f64).round() as usize;
+    let mut users: Vec<_> = (0..config.users).map(|i| {
+        let transport = if i < num_natted {
+            net.bind_behind_nat(user_addr(i)).unwrap()
+        } else {
+            net.bind(user_addr(i)).unwrap()
+        };
+        let node_key = work::generate_keypair(config.node.key_difficulty);
+        let node = dht::start_node_among(node_key, transport, &config.node, clock.clone(),
+                                         bootstrap.clone()).unwrap();
+        User {
+            book: AddressBook::on_node(crypto::box_keypair(), node, &config.node, clock.clone()),
+            next_pickup_ms: start + random_ms(config.node.timing.pickup_period_ms),
+            next_send_ms: send_from + random_ms(config.message_period_ms),
+        }
+    }).collect();
+    let user_keys: Vec<_> = users.iter().map(|u| u.book.my_key()).collect();
+
+    let mut report = Report::default();
+    // When each message was sent, until it arrives.
+    let mut in_transit: HashMap<message::Id, u64> = HashMap::new();
+    let mut next_churn = config.churn_period_ms.map(|p| start + p);
+    let mut last_progress = start;
+    loop {
+        clock.advance(config.step_ms);
+        net.deliver_due();
+        // A node that has gone away leaves nothing behind, so this
+        // only takes long if a node is stuck, in which case we carry
+        // on and let its packets count as lost.
+        for _ in 0..MAX_CATCH_UP_MS {
+            if net.is_idle() {
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        sleep(Duration::from_millis(config.real_ms_per_step));
+        let now = clock.monotonic_ms();
+        if now >= end {
+            break;
+        }
+        for (i, u) in users.iter_mut().enumerate() {
+            while let Some((from, msg_id, m)) = u.book.listen() {
+                if let Message::Comment { .. } = m {
+                    if let Some(sent) = in_transit.remove(&msg_id) {
+                        report.delivered += 1;
+                        report.latencies_ms.push(now - sent);
+                    }
+                    u.book.send(&from, &Message::Acknowledge { msg_id: msg_id });
+                }
+            }
+            if now >= u.next_pickup_ms {
+                u.book.pickup();
+                u.next_pickup_ms += config.node.timing.pickup_period_ms;
+            }
+            if now >= u.next_send_ms && now < send_until {
+                let to = (i + 1 + random_ms(config.users as u64 - 1) as usize) % config.users;
+                let text = format!("message {} from user {}", report.sent, i);
+                let mut contents = [0; 394];
+                for (c, b) in contents.iter_mut().zip(text.bytes()) {
+                    *c = b;
+                }
+                let msg_id = u.book.send(&user_keys[to], &Message::Comment {
+                    thread: Thread::random(),
+                    time: clock.epoch_time(),
+                    message_length: text.len() as u32,
+                    message_start: 0,
+                    contents: contents,
+                });
+                in_transit.insert(msg_id, now);
+                report.sent += 1;
+                u.next_send_ms += config.message_period_ms;
+            }
+        }
+        if let Some(t) = next_churn {
+            if now >= t && config.relays > NUM_BOOTSTRAP {
+                let i = NUM_BOOTSTRAP + random_ms((config.relays - NUM_BOOTSTRAP) as u64) as usize;
+                info!("Replacing relay {} at {}", i, relay_addr(i));
+                // Dropping the node waits until it has let go of its
+                // address, so the new one can take it over.
+                relays[i] = None;
+                relays[i] = Some(start_relay(i, work::generate_keypair(config.node.key_difficulty),
+                                             &bootstrap));
+                report.relays_replaced += 1;
+                next_churn = Some(t + config.churn_period_ms.unwrap());
+            }
+        }
+        if now >= last_progress + 60*1000 {
+            info!("{}s simulated: {} sent, {} delivered",
+                  (now - start)/1000, report.sent, report.delivered);
+            last_progress = now;
+        }
+    }
+    report.acknowledged = report.sent
+        - users.iter().fold(0, |n, u| n + u.book.num_unacknowledged());
+    report
+}
+
+/// This takes a while in real time, so it only runs when asked
+/// for, with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn messages_get_through() {
+    let config = SimConfig {
+        relays: 8,
+        users: 3,
+        churn_period_ms: Some(20*1000),
+        message_period_ms: 10*1000,
+        warmup_ms: 20*1000,
+        duration_ms: 30*1000,
+        drain_ms: 30*1000,
+        .. SimConfig::default()
+    };
+    let report = run(&config);
+    println!("{}", report);
+    assert!(report.sent >= 6);
+    assert!(report.delivered > 0);
+    assert!(report.relays_replaced >= 3);
+}
@@ -1,5 +1,5 @@
 use std;
-use dht::{MyBytes};
+use dht::{WireBytes, DecodeError};
 
 pub struct Str255 {
     pub length: u8,
@@ -13,25 +13,34 @@ impl Clone for Str255 {
         }
     }
 }
-impl MyBytes<[u8; 256]> for Str255 {
+impl WireBytes<[u8; 256]> for Str255 {
     fn bytes(&self, out: &mut[u8; 256]) {
         let (l, c) = mut_array_refs![out,1,255];
         l[0] = self.length;
         *c = self.content;
     }
-    fn from_bytes(inp: &[u8; 256]) -> Str255 {
+    fn decode(inp: &[u8; 256]) -> Result<Str255, DecodeError> {
         let (l, c) = array_refs![inp,1,255];
-        Str255 {
+        let length = l[0] as usize;
+        if std::str::from_utf8(&c[..length]).is_err() || c[length..].iter().any(|&b| b != 0) {
+            return Err(DecodeError::BadString);
+        }
+        Ok(Str255 {
             length: l[0],
             content: *c,
-        }
+        })
     }
 }
 
 impl<'a> std::convert::From<&'a str> for Str255 {
+    /// A string too long to fit is cut short, but never in the middle
+    /// of a character.
     fn from(s: &'a str) -> Str255 {
-        let b = &s.as_bytes();
-        let b: &[u8] = if b.len() > 255 { &b[..255] } else { b };
+        let mut n = std::cmp::min(s.len(), 255);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        let b = &s.as_bytes()[..n];
         let mut bb = [0u8; 255];
         for i in 0 .. b.len() {
             bb[i] = b[i];
@@ -78,4 +87,24 @@ fn test_from_string() {
     let s255 = Str255::from(s.as_ref());
     println!("{}", s255.length);
     assert_eq!(s255.length, 4);
+    // Each of these is two bytes long, so the 128th does not fit.
+    let long: String = std::iter::repeat('é').take(200).collect();
+    let s255 = Str255::from(long.as_ref());
+    assert_eq!(s255.length, 254);
+    assert_eq!(s255.chars().count(), 127);
+    let mut buf = [0; 256];
+    s255.bytes(&mut buf);
+    assert!(Str255::decode(&buf).is_ok());
+}
+
+#[test]
+fn test_bad_bytes() {
+    let mut buf = [0; 256];
+    Str255::from("hello").bytes(&mut buf);
+    assert_eq!(&*Str255::decode(&buf).unwrap(), "hello");
+    buf[3] = 0xff;
+    assert_eq!(Str255::decode(&buf).err(), Some(DecodeError::BadString));
+    buf[3] = b'l';
+    buf[100] = b'!';
+    assert_eq!(Str255::decode(&buf).err(), Some(DecodeError::BadString));
 }
@@ -0,0 +1,646 @@
+//! A TCP transport, for reaching relays from networks that drop
+//! unknown UDP.  The very same fixed-size onion packets are carried,
+//! each in a frame prefixed with its length.
+//!
+//! A connection starts with `PREAMBLE` and the port that the
+//! connecting node listens on, so that the packets it sends us can be
+//! attributed to the same address it uses for udp.  Connections are
+//! used in both directions, so a node that cannot accept connections
+//! can still hear back over the connection it made.
+//!
+//! The `HybridTransport` chooses per peer whether to use udp or tcp.
+//! We start out with udp, and fall back to tcp when a peer never
+//! answers our greetings, as the DHT tells us through `Reachability`.
+//! We cannot judge this from the packets we send a peer, since most
+//! of those are onions whose answers come back through other relays.
+//! The sender thread of `udp::listen_on` still
+//! hands us one packet per period, and we never block it while a
+//! connection is being made: at most one packet waits for a
+//! connection, and any more are dropped just as udp would drop them.
+//! Thus the constant-rate discipline holds on the tcp path too.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError,
+                      channel, sync_channel};
+use std::thread;
+use std::time::Duration;
+
+use clock::Clock;
+use config::NodeConfig;
+use dht::MyBytes;
+use expiring::ExpiringMap;
+use udp::{RawEncryptedMessage, Transport, UdpTransport, PACKET_LENGTH, POLL_MS};
+use udp;
+
+const PREAMBLE: &'static [u8; 4] = b"pmtc";
+
+/// How long we wait for a new connection to introduce itself.
+const PREAMBLE_TIMEOUT_MS: u64 = 10*1000;
+
+/// The most connections others may have open to us at once.  Each
+/// costs us a couple of threads, so anyone who can reach us could
+/// otherwise run us out of them.  Connections we make ourselves are
+/// not counted, since we only make them to peers we are sending to.
+const MAX_INBOUND: usize = 256;
+
+/// How many send periods a connection made to us may stay silent
+/// before we close it.  A peer that still wants us simply connects
+/// again.
+const IDLE_SEND_PERIODS: u64 = 6;
+
+struct Connections {
+    next_id: usize,
+    /// The queue of packets for the connection to each peer, along
+    /// with an id, so that a connection that closes does not remove
+    /// its replacement.
+    writers: HashMap<SocketAddr, (usize, SyncSender<[u8; PACKET_LENGTH]>)>,
+    /// Peers we were unable to connect to, since the last time we
+    /// were asked to send to them.
+    failed: HashSet<SocketAddr>,
+}
+
+impl Connections {
+    fn add_writer(&mut self, peer: SocketAddr) -> (usize, Receiver<[u8; PACKET_LENGTH]>) {
+        let (tx, rx) = sync_channel(1);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.writers.insert(peer, (id, tx));
+        (id, rx)
+    }
+    fn remove_writer(&mut self, peer: SocketAddr, id: usize) {
+        if self.writers.get(&peer).map(|w| w.0) == Some(id) {
+            self.writers.remove(&peer);
+        }
+    }
+}
+
+struct Shared {
+    /// The port we announce to the peers we connect to.
+    port: u16,
+    conns: Mutex<Connections>,
+    incoming: Mutex<Sender<RawEncryptedMessage>>,
+    closed: AtomicBool,
+    /// How many connections others have open to us.
+    inbound: AtomicUsize,
+    idle_ms: u64,
+}
+
+/// One connection someone made to us, which counts against
+/// `MAX_INBOUND` until it is dropped.
+struct Inbound(Arc<Shared>);
+
+impl Drop for Inbound {
+    fn drop(&mut self) {
+        self.0.inbound.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct TcpTransport {
+    shared: Arc<Shared>,
+    received: Mutex<Receiver<RawEncryptedMessage>>,
+}
+
+impl TcpTransport {
+    /// Listen for connections on `port`, which should be the port we
+    /// use for udp, at the address given in `config` (or at both `::`
+    /// and `0.0.0.0`).  If we are unable to listen, we can still make
+    /// connections to others.
+    pub fn bind(config: &NodeConfig, port: u16) -> TcpTransport {
+        let ips = match config.bind_address {
+            Some(ip) => vec![ip],
+            None => vec![IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+                         IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))],
+        };
+        let mut listeners = Vec::new();
+        for ip in ips {
+            match TcpListener::bind((ip, port)) {
+                Ok(l) => listeners.push(l),
+                Err(e) => info!("Unable to listen for tcp on {} port {}: {}", ip, port, e),
+            }
+        }
+        let (tx, rx) = channel();
+        let shared = Arc::new(Shared {
+            port: port,
+            conns: Mutex::new(Connections {
+                next_id: 0,
+                writers: HashMap::new(),
+                failed: HashSet::new(),
+            }),
+            incoming: Mutex::new(tx),
+            closed: AtomicBool::new(false),
+            inbound: AtomicUsize::new(0),
+            idle_ms: IDLE_SEND_PERIODS*config.timing.send_period_ms,
+        });
+        if listeners.is_empty() {
+            warn!("Not accepting tcp connections, so only outgoing tcp will work");
+        } else {
+            let shared = shared.clone();
+            thread::spawn(move|| { accept_connections(shared, listeners) });
+        }
+        TcpTransport { shared: shared, received: Mutex::new(rx) }
+    }
+    /// Return a packet if one has arrived, without waiting.
+    pub fn try_recv_packet(&self) -> Option<RawEncryptedMessage> {
+        self.received.lock().unwrap().try_recv().ok()
+    }
+}
+
+impl Transport for TcpTransport {
+    /// Queue a packet on the connection to `msg.ip`, making the
+    /// connection if need be.  If our last attempt to connect failed,
+    /// we return an error (once), so that the caller may try another
+    /// way.
+    fn send_packet(&self, msg: &RawEncryptedMessage) -> Result<(), Error> {
+        let peer = udp::normalize(msg.ip);
+        let mut conns = self.shared.conns.lock().unwrap();
+        if conns.failed.remove(&peer) {
+            return Err(Error::new(ErrorKind::ConnectionRefused,
+                                  format!("unable to connect to {}", peer)));
+        }
+        if !conns.writers.contains_key(&peer) {
+            let (id, rx) = conns.add_writer(peer);
+            let shared = self.shared.clone();
+            thread::spawn(move|| { connect(shared, peer, id, rx) });
+        }
+        let result = conns.writers[&peer].1.try_send(msg.data);
+        match result {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                info!("Dropping packet to {} while waiting for tcp", peer);
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                // The connection just closed, so we lose this packet,
+                // and will reconnect next time.
+                conns.writers.remove(&peer);
+            },
+        }
+        Ok(())
+    }
+    fn recv_packet(&self) -> Result<RawEncryptedMessage, Error> {
+        match self.received.lock().unwrap().recv_timeout(Duration::from_millis(POLL_MS)) {
+            Ok(m) => Ok(m),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(Error::new(ErrorKind::TimedOut, "no packet arrived"))
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::new(ErrorKind::NotConnected, "tcp transport is gone"))
+            },
+        }
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        // Dropping the writers closes every connection, and the
+        // listening thread notices `closed` shortly.
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.conns.lock().unwrap().writers.clear();
+    }
+}
+
+fn accept_connections(shared: Arc<Shared>, listeners: Vec<TcpListener>) {
+    for l in listeners.iter() {
+        if let Err(e) = l.set_nonblocking(true) {
+            error!("Unable to accept tcp connections: {}", e);
+            return;
+        }
+    }
+    while !shared.closed.load(Ordering::SeqCst) {
+        let mut accepted = false;
+        for l in listeners.iter() {
+            match l.accept() {
+                Ok((stream, addr)) => {
+                    accepted = true;
+                    // Only this thread adds connections, so the count
+                    // cannot grow behind our back.
+                    if shared.inbound.load(Ordering::SeqCst) >= MAX_INBOUND {
+                        info!("Refusing tcp connection from {}, since {} are open already",
+                              addr, MAX_INBOUND);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                    shared.inbound.fetch_add(1, Ordering::SeqCst);
+                    let inbound = Inbound(shared.clone());
+                    thread::spawn(move|| { introduce(inbound, stream, addr) });
+                },
+                Err(ref e) if udp::is_timeout(e) => (),
+                Err(e) => error!("Error accepting tcp connection: {}", e),
+            }
+        }
+        if !accepted {
+            thread::sleep_ms(POLL_MS as u32);
+        }
+    }
+}
+
+/// Handle a connection someone made to us, once it has told us which
+/// port it listens on.  Once it has, it may stay open as long as it
+/// hears something every `Shared::idle_ms`, so that a peer cannot hold
+/// it (and our threads) by saying nothing.
+fn introduce(inbound: Inbound, mut stream: TcpStream, addr: SocketAddr) {
+    let shared = inbound.0.clone();
+    let idle = Some(Duration::from_millis(shared.idle_ms));
+    let mut preamble = [0; 6];
+    let read = stream.set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(PREAMBLE_TIMEOUT_MS))))
+        .and_then(|_| stream.read_exact(&mut preamble))
+        .and_then(|_| stream.set_read_timeout(idle))
+        .and_then(|_| stream.set_write_timeout(idle));
+    if let Err(e) = read {
+        info!("Connection from {} never introduced itself: {}", addr, e);
+        return;
+    }
+    if &preamble[..4] != &PREAMBLE[..] {
+        info!("Connection from {} is not speaking our language", addr);
+        return;
+    }
+    let peer = SocketAddr::new(udp::normalize(addr).ip(),
+                               u16::from_bytes(array_ref![preamble, 4, 2]));
+    let (id, rx) = shared.conns.lock().unwrap().add_writer(peer);
+    serve(shared, peer, stream, id, rx);
+}
+
+/// Make a connection to `peer`, and use it to send whatever is queued.
+fn connect(shared: Arc<Shared>, peer: SocketAddr, id: usize,
+           rx: Receiver<[u8; PACKET_LENGTH]>) {
+    let mut preamble = [0; 6];
+    *array_mut_ref![preamble, 0, 4] = *PREAMBLE;
+    shared.port.bytes(array_mut_ref![preamble, 4, 2]);
+    let stream = TcpStream::connect(peer)
+        .and_then(|mut s| s.write_all(&preamble).map(|_| s));
+    match stream {
+        Ok(s) => serve(shared, peer, s, id, rx),
+        Err(e) => {
+            info!("Unable to connect to {} over tcp: {}", peer, e);
+            let mut conns = shared.conns.lock().unwrap();
+            conns.remove_writer(peer, id);
+            conns.failed.insert(peer);
+        },
+    }
+}
+
+/// Write the packets queued for `peer` to `stream`, while another
+/// thread reads what arrives on it.
+fn serve(shared: Arc<Shared>, peer: SocketAddr, mut stream: TcpStream, id: usize,
+         rx: Receiver<[u8; PACKET_LENGTH]>) {
+    if let Err(e) = stream.set_nodelay(true) {
+        info!("Unable to set nodelay for {}: {}", peer, e);
+    }
+    match stream.try_clone() {
+        Ok(r) => {
+            let shared = shared.clone();
+            thread::spawn(move|| { read_frames(shared, peer, r, id) });
+        },
+        Err(e) => {
+            error!("Unable to read from connection to {}: {}", peer, e);
+            shared.conns.lock().unwrap().remove_writer(peer, id);
+            return;
+        },
+    }
+    for data in rx.iter() {
+        let mut frame = [0; 2 + PACKET_LENGTH];
+        (PACKET_LENGTH as u16).bytes(array_mut_ref![frame, 0, 2]);
+        *array_mut_ref![frame, 2, PACKET_LENGTH] = data;
+        if let Err(e) = stream.write_all(&frame) {
+            info!("Lost tcp connection to {}: {}", peer, e);
+            break;
+        }
+    }
+    shared.conns.lock().unwrap().remove_writer(peer, id);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+fn read_frames(shared: Arc<Shared>, peer: SocketAddr, mut stream: TcpStream, id: usize) {
+    let incoming = shared.incoming.lock().unwrap().clone();
+    loop {
+        let mut frame = [0; 2 + PACKET_LENGTH];
+        if let Err(e) = stream.read_exact(array_mut_ref![frame, 0, 2]) {
+            info!("Tcp connection to {} closed: {}", peer, e);
+            break;
+        }
+        let len = u16::from_bytes(array_ref![frame, 0, 2]) as usize;
+        if len != PACKET_LENGTH {
+            info!("A tcp frame of a strange size {} from {}", len, peer);
+            break;
+        }
+        if let Err(e) = stream.read_exact(array_mut_ref![frame, 2, PACKET_LENGTH]) {
+            info!("Tcp connection to {} closed: {}", peer, e);
+            break;
+        }
+        let p = RawEncryptedMessage { ip: peer, data: *array_ref![frame, 2, PACKET_LENGTH] };
+        if incoming.send(p).is_err() {
+            break;
+        }
+    }
+    shared.conns.lock().unwrap().remove_writer(peer, id);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// How long we keep reaching a peer over tcp before giving udp
+/// another chance, and how long we remember greetings that went
+/// unanswered.
+const PEER_MEMORY_MS: u64 = 60*60*1000;
+
+/// The most peers we keep track of.  Anyone can reach us over tcp, so
+/// this must be bounded.
+const MAX_PEERS: usize = 1024;
+
+/// How many greetings in a row the DHT has sent straight to each peer
+/// without hearing back.  A greeting is a round trip to that peer
+/// alone, so this is what tells us whether udp gets through to it.
+pub struct Reachability {
+    unanswered: Mutex<ExpiringMap<SocketAddr, u32>>,
+    clock: Arc<Clock>,
+}
+
+impl Reachability {
+    pub fn new(clock: &Arc<Clock>) -> Arc<Reachability> {
+        Arc::new(Reachability {
+            unanswered: Mutex::new(ExpiringMap::new(PEER_MEMORY_MS, MAX_PEERS)),
+            clock: clock.clone(),
+        })
+    }
+    /// We are greeting `peer`, and expect an answer straight back.
+    pub fn greeted(&self, peer: SocketAddr) {
+        let peer = udp::normalize(peer);
+        let mut unanswered = self.unanswered.lock().unwrap();
+        let n = unanswered.get(&peer).cloned().unwrap_or(0);
+        unanswered.insert(peer, n + 1, self.clock.monotonic_ms());
+    }
+    /// `peer` answered a greeting, so it can hear us.
+    pub fn answered(&self, peer: SocketAddr) {
+        self.unanswered.lock().unwrap().remove(&udp::normalize(peer));
+    }
+    /// How many greetings to `peer` have gone unanswered since it last
+    /// answered one.
+    pub fn unanswered(&self, peer: SocketAddr) -> u32 {
+        let mut unanswered = self.unanswered.lock().unwrap();
+        unanswered.expire(self.clock.monotonic_ms());
+        unanswered.get(&udp::normalize(peer)).cloned().unwrap_or(0)
+    }
+}
+
+/// Which way we reach a given peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerTransport {
+    Udp,
+    Tcp,
+}
+
+/// A transport that uses udp where it can, and tcp where it must.
+pub struct HybridTransport {
+    udp: UdpTransport,
+    tcp: TcpTransport,
+    /// The peers we were configured to always reach over tcp...
+    forced: HashSet<SocketAddr>,
+    /// ... and those we have lately found we have to.
+    via_tcp: Mutex<ExpiringMap<SocketAddr, ()>>,
+    reach: Arc<Reachability>,
+    fallback_after: Option<u32>,
+    clock: Arc<Clock>,
+}
+
+impl HybridTransport {
+    pub fn new(udp: UdpTransport, tcp: TcpTransport, config: &NodeConfig,
+               reach: Arc<Reachability>, clock: &Arc<Clock>) -> HybridTransport {
+        HybridTransport {
+            udp: udp,
+            tcp: tcp,
+            forced: config.tcp_peers.iter().map(|&p| udp::normalize(p)).collect(),
+            via_tcp: Mutex::new(ExpiringMap::new(PEER_MEMORY_MS, MAX_PEERS)),
+            reach: reach,
+            fallback_after: config.tcp_fallback_after,
+            clock: clock.clone(),
+        }
+    }
+    /// How we currently reach `peer`.
+    pub fn transport_for(&self, peer: SocketAddr) -> PeerTransport {
+        let peer = udp::normalize(peer);
+        if self.forced.contains(&peer) || self.via_tcp.lock().unwrap().contains_key(&peer) {
+            PeerTransport::Tcp
+        } else {
+            PeerTransport::Udp
+        }
+    }
+    fn heard_from(&self, p: RawEncryptedMessage, via: PeerTransport) -> RawEncryptedMessage {
+        if via == PeerTransport::Tcp && self.transport_for(p.ip) == PeerTransport::Udp {
+            // They had to use tcp to reach us, so udp likely will not
+            // reach them either.
+            info!("{} reached us over tcp, so we will answer over tcp", p.ip);
+            self.via_tcp.lock().unwrap().insert(udp::normalize(p.ip), (),
+                                                self.clock.monotonic_ms());
+        }
+        p
+    }
+}
+
+impl Transport for HybridTransport {
+    fn send_packet(&self, msg: &RawEncryptedMessage) -> Result<(), Error> {
+        let peer = udp::normalize(msg.ip);
+        let forced = self.forced.contains(&peer);
+        let via = if forced {
+            PeerTransport::Tcp
+        } else {
+            let mut via_tcp = self.via_tcp.lock().unwrap();
+            let now = self.clock.monotonic_ms();
+            via_tcp.expire(now);
+            match self.fallback_after {
+                _ if via_tcp.contains_key(&peer) => PeerTransport::Tcp,
+                Some(n) if self.reach.unanswered(peer) > n => {
+                    info!("{} never answered {} greetings over udp, so we will try tcp",
+                          peer, n);
+                    via_tcp.insert(peer, (), now);
+                    PeerTransport::Tcp
+                },
+                _ => PeerTransport::Udp,
+            }
+        };
+        if via == PeerTransport::Tcp {
+            match self.tcp.send_packet(msg) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    if forced {
+                        return Err(e);
+                    }
+                    info!("Unable to reach {} over tcp ({}), so back to udp", peer, e);
+                    self.via_tcp.lock().unwrap().remove(&peer);
+                    // We give udp as many greetings as it had the
+                    // first time before trying tcp again.
+                    self.reach.answered(peer);
+                },
+            }
+        }
+        self.udp.send_packet(msg)
+    }
+    fn recv_packet(&self) -> Result<RawEncryptedMessage, Error> {
+        if let Some(p) = self.tcp.try_recv_packet() {
+            return Ok(self.heard_from(p, PeerTransport::Tcp));
+        }
+        match self.udp.recv_packet() {
+            Ok(p) => Ok(self.heard_from(p, PeerTransport::Udp)),
+            Err(ref e) if udp::is_timeout(e) => {
+                match self.tcp.try_recv_packet() {
+                    Some(p) => Ok(self.heard_from(p, PeerTransport::Tcp)),
+                    None => Err(Error::new(ErrorKind::TimedOut, "no packet arrived")),
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+fn free_port() -> u16 {
+    // We find a port that is free for both udp and tcp on localhost.
+    loop {
+        let s = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = s.local_addr().unwrap().port();
+        if TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+#[cfg(test)]
+fn recv_within(t: &Transport) -> RawEncryptedMessage {
+    for _ in 0..100 {
+        match t.recv_packet() {
+            Ok(p) => return p,
+            Err(ref e) if udp::is_timeout(e) => (),
+            Err(e) => panic!("error receiving: {}", e),
+        }
+    }
+    panic!("nothing arrived");
+}
+
+#[cfg(test)]
+fn localhost_config(port: u16) -> NodeConfig {
+    use std::str::FromStr;
+    NodeConfig {
+        bind_address: Some(IpAddr::from_str("127.0.0.1").unwrap()),
+        port: port,
+        .. NodeConfig::default()
+    }
+}
+
+#[test]
+fn tcp_both_ways() {
+    let (a_port, b_port) = (free_port(), free_port());
+    let a = TcpTransport::bind(&localhost_config(a_port), a_port);
+    let b = TcpTransport::bind(&localhost_config(b_port), b_port);
+    let a_addr = SocketAddr::new(localhost_config(0).bind_address.unwrap(), a_port);
+    let b_addr = SocketAddr::new(a_addr.ip(), b_port);
+    a.send_packet(&RawEncryptedMessage { ip: b_addr, data: [1; PACKET_LENGTH] }).unwrap();
+    let p = recv_within(&b);
+    assert_eq!(p.ip, a_addr);
+    assert_eq!(p.data[9], 1);
+    b.send_packet(&RawEncryptedMessage { ip: a_addr, data: [2; PACKET_LENGTH] }).unwrap();
+    let p = recv_within(&a);
+    assert_eq!(p.ip, b_addr);
+    assert_eq!(p.data[9], 2);
+}
+
+#[test]
+fn hybrid_falls_back_to_tcp() {
+    let (a_port, b_port) = (free_port(), free_port());
+    let mut config = localhost_config(a_port);
+    config.tcp_fallback_after = Some(2);
+    let clock = ::clock::SystemClock::new();
+    let reach = Reachability::new(&clock);
+    let a = HybridTransport::new(UdpTransport::bind(&config).unwrap(),
+                                 TcpTransport::bind(&config, a_port), &config, reach.clone(),
+                                 &clock);
+    // `b` only speaks tcp, so our udp greetings go unanswered.
+    let b = TcpTransport::bind(&localhost_config(b_port), b_port);
+    let a_addr = SocketAddr::new(localhost_config(0).bind_address.unwrap(), a_port);
+    let b_addr = SocketAddr::new(a_addr.ip(), b_port);
+    // Packets that are not greetings prove nothing either way.
+    for i in 0..3 {
+        a.send_packet(&RawEncryptedMessage { ip: b_addr, data: [i; PACKET_LENGTH] }).unwrap();
+    }
+    assert_eq!(a.transport_for(b_addr), PeerTransport::Udp);
+    for i in 3..6 {
+        reach.greeted(b_addr);
+        a.send_packet(&RawEncryptedMessage { ip: b_addr, data: [i; PACKET_LENGTH] }).unwrap();
+    }
+    assert_eq!(a.transport_for(b_addr), PeerTransport::Tcp);
+    let p = recv_within(&b);
+    assert_eq!(p.ip, a_addr);
+    assert_eq!(p.data[0], 5);
+    b.send_packet(&RawEncryptedMessage { ip: a_addr, data: [7; PACKET_LENGTH] }).unwrap();
+    let p = recv_within(&a);
+    assert_eq!(p.ip, b_addr);
+    assert_eq!(p.data[0], 7);
+    assert_eq!(a.transport_for(b_addr), PeerTransport::Tcp);
+}
+
+#[test]
+fn inbound_connections_are_capped() {
+    let port = free_port();
+    let _t = TcpTransport::bind(&localhost_config(port), port);
+    let connect = || {
+        let s = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        s.set_read_timeout(Some(Duration::from_millis(5*1000))).unwrap();
+        s
+    };
+    // Our first connections are held open waiting for a preamble,
+    // but one too many is closed at once.
+    let mut held: Vec<_> = (0..MAX_INBOUND).map(|_| connect()).collect();
+    assert_eq!(connect().read(&mut [0; 1]).unwrap(), 0);
+    let open = |mut s: &TcpStream| {
+        s.set_nonblocking(true).unwrap();
+        let open = match s.read(&mut [0; 1]) {
+            Err(ref e) => udp::is_timeout(e),
+            Ok(_) => false,
+        };
+        s.set_nonblocking(false).unwrap();
+        open
+    };
+    assert!(held.iter().all(|s| open(s)));
+    // Once some go away, there is room for more.
+    held.truncate(MAX_INBOUND - 10);
+    let mut admitted = false;
+    for _ in 0..50 {
+        let s = connect();
+        thread::sleep(Duration::from_millis(100));
+        if open(&s) {
+            admitted = true;
+            break;
+        }
+    }
+    assert!(admitted);
+}
+
+#[test]
+fn silent_connections_are_closed() {
+    use config::Timing;
+    let port = free_port();
+    let config = NodeConfig { timing: Timing::profile("lan-test").unwrap(),
+                              .. localhost_config(port) };
+    let _t = TcpTransport::bind(&config, port);
+    let mut s = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut preamble = [0; 6];
+    *array_mut_ref![preamble, 0, 4] = *PREAMBLE;
+    s.write_all(&preamble).unwrap();
+    s.set_read_timeout(Some(Duration::from_millis(10*1000))).unwrap();
+    assert_eq!(s.read(&mut [0; 1]).unwrap(), 0);
+}
+
+#[test]
+fn unanswered_greetings_are_forgotten() {
+    use std::str::FromStr;
+    let clock = ::clock::FakeClock::new(1000*1000);
+    let as_clock: Arc<Clock> = clock.clone();
+    let reach = Reachability::new(&as_clock);
+    let peer = SocketAddr::from_str("10.0.0.1:54321").unwrap();
+    reach.greeted(peer);
+    reach.greeted(peer);
+    clock.advance(PEER_MEMORY_MS/2);
+    assert_eq!(reach.unanswered(peer), 2);
+    clock.advance(PEER_MEMORY_MS);
+    assert_eq!(reach.unanswered(peer), 0);
+}
@@ -8,13 +8,23 @@ use std::fmt;
 use std;
 
 use std::net::UdpSocket;
-use std::net::{ SocketAddr, SocketAddrV4 };
+use std::net::{ SocketAddr, SocketAddrV4, SocketAddrV6,
+                IpAddr, Ipv4Addr, Ipv6Addr };
 // use onionsalt::crypto;
 // use onionsalt::crypto::{ToPublicKey};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::sync::mpsc::{Receiver, channel,
                       SyncSender, sync_channel};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::thread::JoinHandle;
+
+use capture::{Recording, Replay};
+use clock::Clock;
+use config::NodeConfig;
+use tcp::{HybridTransport, Reachability, TcpTransport};
+use flood::{FloodCounters, FloodGuard, FloodLimits};
 
 pub use onionsalt::{PACKET_LENGTH};
 
@@ -46,27 +56,303 @@ impl Debug for RawEncryptedMessage {
     }
 }
 
-pub fn listen(send_period_ms: u64) -> Result<(SyncSender<RawEncryptedMessage>,
-                                              Receiver<RawEncryptedMessage>), Error> {
-    // Create the socket we will use for all communications.  If we
-    // can bind to ipv6, we will only use ipv6 for listening. I'm not
-    // sure if this is wise, but it seems best not to listen on both
-    // protocols...
+/// A `Transport` is anything that can carry our fixed-size packets
+/// between nodes.  The real network uses a `UdpSocket`, but tests
+/// (and simulations) may use an in-memory network such as
+/// `loopback::Loopback`.  Every address handed to or returned from a
+/// `Transport` is the address of the remote peer.
+pub trait Transport: Send + Sync + 'static {
+    /// Send a single packet to `msg.ip`.  There is no guarantee of
+    /// delivery.
+    fn send_packet(&self, msg: &RawEncryptedMessage) -> Result<(), Error>;
+    /// Wait for a full-sized packet to arrive, and return it along
+    /// with the address of its sender.  If nothing arrives within a
+    /// short while (about `POLL_MS`), this returns an error for which
+    /// `is_timeout` is true, so that the caller may check whether it
+    /// ought to stop.  Any other error indicates that the transport
+    /// has gone down.
+    fn recv_packet(&self) -> Result<RawEncryptedMessage, Error>;
+}
+
+/// How long our threads wait on a socket (or sleep) before checking
+/// whether they have been asked to stop.
+pub const POLL_MS: u64 = 50;
 
-    // On ipv6 we only bind to the default port, since we presume that
-    // NAT isn't needed on ipv6, and don't want to store yet more
-    // routing bytes.
-    let socket = match UdpSocket::bind(("::", PORT)) {
-        Ok(s) => s,
-        _ => {
-            info!("Attempting to bind to ipv4...");
-            match UdpSocket::bind(("0.0.0.0", PORT)) {
-                Ok(s) => s,
-                _ => try!(UdpSocket::bind(("0.0.0.0", 0))) // any port in a storm
+/// Is this the error a `Transport` gives when no packet arrived in
+/// time?
+pub fn is_timeout(e: &Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+/// Flags with which the threads of a node tell one another to wind
+/// down.  Shutting down happens in stages: first we stop receiving
+/// packets, and once nothing more can be scheduled we flush out
+/// whatever is left to send.
+pub struct Halt {
+    /// Set when the node should stop receiving packets.
+    pub receiving: AtomicBool,
+    /// Set once nothing more will be scheduled, so the sender should
+    /// send out whatever remains without waiting its turn.
+    pub flushing: AtomicBool,
+}
+
+impl Halt {
+    pub fn new() -> Arc<Halt> {
+        Arc::new(Halt {
+            receiving: AtomicBool::new(false),
+            flushing: AtomicBool::new(false),
+        })
+    }
+}
+
+/// Our transport on the real network.  Whether an ipv6 socket also
+/// accepts ipv4 traffic depends on the host's configuration, so where
+/// we can we listen on ipv4 and ipv6 with separate sockets.  If the
+/// ipv4 socket cannot be bound because our ipv6 socket already covers
+/// the port, we send to ipv4 peers through the ipv6 socket using
+/// ipv4-mapped addresses.
+///
+/// Both sockets are non-blocking, and we take turns reading from
+/// them, so that a busy socket cannot starve the other, and an idle
+/// one cannot hold the other up.
+pub struct UdpTransport {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    /// Which socket to read from first next time, counting v6 first.
+    next: AtomicUsize,
+}
+
+/// How long we sleep when neither socket has anything for us.
+const IDLE_SLEEP_MS: u64 = 1;
+
+impl UdpTransport {
+    /// Bind the sockets we will use for all communications, as
+    /// specified by `config`.  If no `bind_address` is given, we bind
+    /// to both `::` and `0.0.0.0`, on the same port.
+    pub fn bind(config: &NodeConfig) -> Result<UdpTransport, Error> {
+        let ipv4_any = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let ipv6_any = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
+        let (v4, v6) = match config.bind_address {
+            Some(ip) => {
+                let s = try!(bind_one(ip, config.port, config));
+                match ip {
+                    IpAddr::V4(_) => (Some(s), None),
+                    IpAddr::V6(_) => (None, Some(s)),
+                }
+            },
+            None => {
+                match bind_one(ipv6_any, config.port, config) {
+                    Ok(v6) => {
+                        let port = try!(v6.local_addr()).port();
+                        let v4 = match UdpSocket::bind((ipv4_any, port)) {
+                            Ok(v4) => Some(v4),
+                            Err(e) => {
+                                info!("Using ipv6 socket for ipv4 as well: {}", e);
+                                None
+                            },
+                        };
+                        (v4, Some(v6))
+                    },
+                    Err(e) => {
+                        info!("Unable to bind to ipv6 ({}), attempting to bind to ipv4...", e);
+                        (Some(try!(bind_one(ipv4_any, config.port, config))), None)
+                    },
+                }
+            },
+        };
+        let t = try!(UdpTransport::from_sockets(v4, v6));
+        info!("Listening on {:?}", t.local_addrs());
+        Ok(t)
+    }
+    fn from_sockets(v4: Option<UdpSocket>, v6: Option<UdpSocket>) -> Result<UdpTransport, Error> {
+        for socket in v6.iter().chain(v4.iter()) {
+            try!(socket.set_nonblocking(true));
+        }
+        Ok(UdpTransport { v4: v4, v6: v6, next: AtomicUsize::new(0) })
+    }
+    fn sockets(&self) -> Vec<&UdpSocket> {
+        self.v6.iter().chain(self.v4.iter()).collect()
+    }
+    /// The addresses of the sockets we are listening on.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.v6.iter().chain(self.v4.iter()).filter_map(|s| s.local_addr().ok()).collect()
+    }
+    /// Pick the socket to use to reach `dest`, along with the address
+    /// to send to on that socket.
+    fn socket_for(&self, dest: SocketAddr) -> Option<(&UdpSocket, SocketAddr)> {
+        match dest {
+            SocketAddr::V4(sa) => {
+                if let Some(ref s) = self.v4 {
+                    return Some((s, dest));
+                }
+                self.v6.as_ref().map(|s| {
+                    let mapped = SocketAddrV6::new(sa.ip().to_ipv6_mapped(), sa.port(), 0, 0);
+                    (s, SocketAddr::V6(mapped))
+                })
+            },
+            SocketAddr::V6(_) => {
+                if let Some(ref s) = self.v6 {
+                    return Some((s, dest));
+                }
+                match (self.v4.as_ref(), normalize(dest)) {
+                    (Some(s), SocketAddr::V4(sa)) => Some((s, SocketAddr::V4(sa))),
+                    _ => None,
+                }
+            },
+        }
+    }
+}
+
+fn bind_one(ip: IpAddr, port: u16, config: &NodeConfig) -> Result<UdpSocket, Error> {
+    match UdpSocket::bind((ip, port)) {
+        Ok(s) => Ok(s),
+        Err(e) => {
+            if !config.allow_port_fallback {
+                return Err(Error::new(e.kind(),
+                                      format!("unable to bind to {} port {}: {}", ip, port, e)));
+            }
+            // any port in a storm
+            warn!("Unable to bind to {} port {} ({}), using a random port", ip, port, e);
+            UdpSocket::bind((ip, 0))
+        },
+    }
+}
+
+/// Read a single proper packet from `socket`, skipping any datagrams
+/// of the wrong size, and returning `None` once there is nothing left
+/// to read.
+fn recv_one(socket: &UdpSocket) -> Result<Option<RawEncryptedMessage>, Error> {
+    let mut buf = [0; PACKET_LENGTH];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((amt, src)) => {
+                if amt == PACKET_LENGTH {
+                    return Ok(Some(RawEncryptedMessage{ ip: normalize(src), data: buf }));
+                }
+                info!("A packet of a strange size {}", amt);
+            },
+            Err(ref e) if is_timeout(e) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_packet(&self, msg: &RawEncryptedMessage) -> Result<(), Error> {
+        let (socket, dest) = match self.socket_for(msg.ip) {
+            Some(x) => x,
+            None => {
+                return Err(Error::new(ErrorKind::AddrNotAvailable,
+                                      format!("no socket that can reach {}", msg.ip)));
+            },
+        };
+        // Our socket is non-blocking, so if the send buffer is full we
+        // wait for it ourselves, but not forever.
+        let mut waited = 0;
+        let sent;
+        loop {
+            match socket.send_to(&msg.data, &dest) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock && waited < POLL_MS => {
+                    thread::sleep(std::time::Duration::from_millis(IDLE_SLEEP_MS));
+                    waited += IDLE_SLEEP_MS;
+                },
+                r => {
+                    sent = try!(r);
+                    break;
+                },
             }
         }
+        if sent != PACKET_LENGTH {
+            info!("Short message {} sent to {}", sent, msg.ip);
+        }
+        Ok(())
+    }
+    fn recv_packet(&self) -> Result<RawEncryptedMessage, Error> {
+        let sockets = self.sockets();
+        let mut waited = 0;
+        while waited < POLL_MS {
+            let first = self.next.load(Ordering::Relaxed);
+            for i in 0..sockets.len() {
+                let which = (first + i) % sockets.len();
+                if let Some(p) = try!(recv_one(sockets[which])) {
+                    // The other socket gets the first look next time.
+                    self.next.store(which + 1, Ordering::Relaxed);
+                    return Ok(p);
+                }
+            }
+            thread::sleep(std::time::Duration::from_millis(IDLE_SLEEP_MS));
+            waited += IDLE_SLEEP_MS;
+        }
+        Err(Error::new(ErrorKind::TimedOut, "no packet arrived"))
+    }
+}
+
+/// The channels and threads of a running `listen_on`.
+pub struct Listener {
+    /// Packets given to `send` are sent out at a constant rate.
+    pub send: SyncSender<RawEncryptedMessage>,
+    /// Packets that arrive are delivered to `get`.
+    pub get: Receiver<RawEncryptedMessage>,
+    /// The thread that sends packets.  It exits once `send` (and any
+    /// clones of it) are dropped.
+    pub sender: JoinHandle<()>,
+    /// The thread that receives packets.  It exits once
+    /// `halt.receiving` is set.
+    pub receiver: JoinHandle<()>,
+    /// How many packets the receiver has accepted and dropped.
+    pub flood: Arc<FloodCounters>,
+    /// Where the DHT says which peers answer its greetings, for the
+    /// transport to decide how to reach them.
+    pub reach: Arc<Reachability>,
+}
+
+/// Start sending and receiving on the real network, as set up in
+/// `config`, falling back to tcp where udp does not get through, and
+/// recording or replaying packets if we are asked to.
+pub fn listen(config: &NodeConfig, send_period_ms: u64, clock: &Arc<Clock>,
+              halt: &Arc<Halt>) -> Result<Listener, Error> {
+    if let Some(ref path) = config.replay {
+        let replay = try!(Replay::open(path));
+        return Ok(listen_on(replay, send_period_ms, config.flood_limits, clock, halt));
+    }
+    let transport = try!(UdpTransport::bind(config));
+    if config.tcp_fallback_after.is_none() && config.tcp_peers.is_empty() {
+        return listen_recording(transport, config, send_period_ms, clock, halt);
+    }
+    let port = match transport.local_addrs().first() {
+        Some(a) => a.port(),
+        None => config.port,
     };
-    let send_socket = try!(socket.try_clone());
+    let tcp = TcpTransport::bind(config, port);
+    let reach = Reachability::new(clock);
+    let mut listener = try!(listen_recording(HybridTransport::new(transport, tcp, config,
+                                                                  reach.clone(), clock),
+                                             config, send_period_ms, clock, halt));
+    listener.reach = reach;
+    Ok(listener)
+}
+
+fn listen_recording<T: Transport>(transport: T, config: &NodeConfig, send_period_ms: u64,
+                                  clock: &Arc<Clock>, halt: &Arc<Halt>)
+                                  -> Result<Listener, Error> {
+    let limits = config.flood_limits;
+    if let Some(ref path) = config.capture {
+        let recording = try!(Recording::create(transport, path));
+        return Ok(listen_on(recording, send_period_ms, limits, clock, halt));
+    }
+    Ok(listen_on(transport, send_period_ms, limits, clock, halt))
+}
+
+/// Start sending and receiving packets over an arbitrary `Transport`.
+/// Packets given to the returned `send` are sent out at a constant
+/// rate of one per `send_period_ms` on `clock`, and packets that arrive are
+/// delivered to the returned `get`, unless they arrive faster than
+/// `limits` allow.  The transport is dropped once both threads have
+/// exited.
+pub fn listen_on<T: Transport>(transport: T, send_period_ms: u64, limits: FloodLimits,
+                               clock: &Arc<Clock>, halt: &Arc<Halt>) -> Listener {
+    let transport = Arc::new(transport);
+    let send_transport = transport.clone();
 
     // Create two channels, one for sending messages from the socket,
     // and one for receiving them.
@@ -106,55 +392,81 @@ pub fn listen(send_period_ms: u64) -> Result<(SyncSender<RawEncryptedMessage>,
     // Actually, the receiver also sends confirmation datagrams, but
     // prior to decrypting or reading any "secret" output.
 
-    thread::spawn(move|| {
+    let send_halt = halt.clone();
+    let send_clock = clock.clone();
+    let sender = thread::spawn(move|| {
         // This is the sender of messages.
         let ms_period = send_period_ms;
-        let mut next_time = (now_ms()/ms_period)*ms_period;
+        let mut next_time = (send_clock.monotonic_ms()/ms_period)*ms_period;
         loop {
-            if !sleep_until(next_time) {
-                // We are behind, so try to catch up by sleeping extra
-                // long this time.
+            if !send_halt.flushing.load(Ordering::SeqCst) {
+                if !send_clock.sleep_until_unless(next_time, &send_halt.flushing) {
+                    // We are behind, so try to catch up by sleeping extra
+                    // long this time.
+                    next_time += ms_period;
+                }
                 next_time += ms_period;
             }
-            next_time += ms_period;
-            let m = rs.recv().unwrap();
-            // println!("Sending to {}", m.ip);
-            match send_socket.send_to(&m.data, &m.ip) {
-                Ok(sent) => {
-                    if sent != PACKET_LENGTH {
-                        info!("Short message {} sent to {}", sent, m.ip);
-                    }
+            let m = match rs.recv() {
+                Ok(m) => m,
+                Err(_) => {
+                    // Nothing more will ever be sent, so we are done.
+                    return;
                 },
-                Err(e) => {
-                    error!("Error sending to {}: {:?}", m.ip, e);
-                }
+            };
+            // println!("Sending to {}", m.ip);
+            if let Err(e) = send_transport.send_packet(&m) {
+                error!("Error sending to {}: {:?}", m.ip, e);
             }
         }
     });
-    thread::spawn(move|| {
+    let halt = halt.clone();
+    let flood = Arc::new(FloodCounters::new());
+    let counters = flood.clone();
+    // Nobody is listening, unless our caller says otherwise.
+    let reach = Reachability::new(clock);
+    let clock = clock.clone();
+    let receiver = thread::spawn(move|| {
         // This is the receiver of messages.  It listens on the
-        // socket, and decrypts messages with the ephemeral session
-        // key prior to forwarding the contents on through the
-        // channel.
-        let mut buf = [0; PACKET_LENGTH];
-        loop {
-            // We assume that when we fail on a receive, the socket must
-            // have gone down, and we should exit this thread.
-            let (amt, src) = socket.recv_from(&mut buf).unwrap();
-            if amt == PACKET_LENGTH {
-                // println!("I got a packet from {}", src);
-                if let Err(e) = tr.send(RawEncryptedMessage{ ip: normalize(src), data: buf }) {
-                    // When no one is listending for messages, we may
-                    // as well shut down our listener.
-                    error!("Quitting now because {:?}", e);
+        // transport, and forwards the packets on through the
+        // channel.  Before doing so, it drops any packets that arrive
+        // too fast, since each one will cost the DHT thread some
+        // crypto work.
+        let mut guard = FloodGuard::new(limits, clock.monotonic_ms());
+        while !halt.receiving.load(Ordering::SeqCst) {
+            let packet = match transport.recv_packet() {
+                Ok(p) => p,
+                Err(ref e) if is_timeout(e) => {
+                    continue;
+                },
+                Err(e) => {
+                    // We assume that when we fail on a receive, the
+                    // transport must have gone down, and we should
+                    // exit this thread.
+                    error!("Unable to receive: {:?}", e);
                     return;
-                }
-            } else {
-                info!("A packet of a strange size {}", amt);
+                },
+            };
+            // println!("I got a packet from {}", packet.ip);
+            if !guard.allow(packet.ip.ip(), clock.monotonic_ms(), &counters) {
+                continue;
+            }
+            if let Err(e) = tr.send(packet) {
+                // When no one is listending for messages, we may
+                // as well shut down our listener.
+                error!("Quitting now because {:?}", e);
+                return;
             }
         }
     });
-    Ok((ts, rr))
+    Listener {
+        send: ts,
+        get: rr,
+        sender: sender,
+        receiver: receiver,
+        flood: flood,
+        reach: reach,
+    }
 }
 
 /// The `EPOCH` is when time begins.  We have not facilities for
@@ -179,17 +491,9 @@ pub fn now_ms() -> u64 {
     ms
 }
 
-pub fn sleep_until(ms_from_epoch: u64) -> bool {
-    let ms = now_ms();
-    if ms > ms_from_epoch {
-        info!("I am behind by {} seconds", (ms - ms_from_epoch) as f64 / 1000.0);
-        return false;
-    }
-    std::thread::sleep_ms((ms_from_epoch - ms) as u32);
-    true
-}
-
-fn normalize(sa: SocketAddr) -> SocketAddr {
+/// Turn an ipv4-mapped ipv6 address into a plain ipv4 one, so that a
+/// peer has the same address no matter which socket we hear it on.
+pub fn normalize(sa: SocketAddr) -> SocketAddr {
     // is it an IPv4-mapped IPv6 address?
     match sa {
         SocketAddr::V6(sa6) =>
@@ -200,3 +504,67 @@ fn normalize(sa: SocketAddr) -> SocketAddr {
         _ => sa,
     }
 }
+
+#[test]
+fn normalize_mapped() {
+    use std::str::FromStr;
+    let mapped = SocketAddr::from_str("[::ffff:128.193.96.51]:54321").unwrap();
+    assert_eq!(normalize(mapped), SocketAddr::from_str("128.193.96.51:54321").unwrap());
+    let v6 = SocketAddr::from_str("[2001:db8::1]:54321").unwrap();
+    assert_eq!(normalize(v6), v6);
+}
+
+#[test]
+fn dual_stack_reaches_ipv4() {
+    let config = NodeConfig { port: 0, .. NodeConfig::default() };
+    let t = UdpTransport::bind(&config).unwrap();
+    let port = t.local_addrs()[0].port();
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    peer.send_to(&[7; PACKET_LENGTH], ("127.0.0.1", port)).unwrap();
+    let got = t.recv_packet().unwrap();
+    assert_eq!(got.ip, peer_addr);
+    assert_eq!(got.data[0], 7);
+
+    t.send_packet(&RawEncryptedMessage { ip: peer_addr, data: [9; PACKET_LENGTH] }).unwrap();
+    let mut buf = [0; PACKET_LENGTH];
+    let (amt, _) = peer.recv_from(&mut buf).unwrap();
+    assert_eq!(amt, PACKET_LENGTH);
+    assert_eq!(buf[0], 9);
+}
+
+#[test]
+fn busy_ipv6_does_not_starve_ipv4() {
+    let v6 = match UdpSocket::bind("[::1]:0") {
+        Ok(s) => s,
+        Err(_) => return, // no ipv6 here, so nothing to starve
+    };
+    let v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (v4_addr, v6_addr) = (v4.local_addr().unwrap(), v6.local_addr().unwrap());
+    let t = UdpTransport::from_sockets(Some(v4), Some(v6)).unwrap();
+
+    // With ipv6 idle, ipv4 packets are not held up waiting on it.
+    let peer4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for _ in 0..50 {
+        peer4.send_to(&[4; PACKET_LENGTH], v4_addr).unwrap();
+    }
+    let start = time::precise_time_ns();
+    for _ in 0..50 {
+        assert_eq!(t.recv_packet().unwrap().data[0], 4);
+    }
+    let ms = (time::precise_time_ns() - start)/1_000_000;
+    assert!(ms < 500, "50 ipv4 packets took {} ms", ms);
+
+    // With a backlog on ipv6, ipv4 packets still get their turn
+    // rather than waiting for it to clear.
+    let peer6 = UdpSocket::bind("[::1]:0").unwrap();
+    for _ in 0..100 {
+        peer6.send_to(&[6; PACKET_LENGTH], v6_addr).unwrap();
+    }
+    for _ in 0..50 {
+        peer4.send_to(&[4; PACKET_LENGTH], v4_addr).unwrap();
+    }
+    let first: Vec<u8> = (0..100).map(|_| t.recv_packet().unwrap().data[0]).collect();
+    assert_eq!(first.iter().filter(|&&b| b == 4).count(), 50);
+}
@@ -0,0 +1,78 @@
+//! Proof of work for routing keys.  Anyone can make up as many keys as
+//! they like, so an attacker could otherwise flood the network with
+//! fake nodes whose keys are close to a victim's rendezvous point, and
+//! end up holding (or dropping) all of the victim's messages.  We
+//! therefore only admit keys whose hash starts with a given number of
+//! zero bits.  Making such a key takes about `2^difficulty` tries,
+//! which is nothing for a node that makes one key and keeps it, but
+//! adds up for an attacker who needs thousands.
+//!
+//! Nodes that predate this module have keys with no work at all, so
+//! for now we put work into our own keys without requiring it of
+//! anyone else's (see `DEFAULT_REQUIRED_KEY_DIFFICULTY`).
+
+use onionsalt::crypto;
+
+/// The work we put into our own keys on the real pmail network.
+pub const DEFAULT_KEY_DIFFICULTY: u32 = 16;
+
+/// The work we require of other nodes' keys on the real pmail network.
+/// This stays at zero for a transition period of one release, which
+/// is long enough for every node to have replaced its key at least
+/// once (see `NodeConfig::key_lifetime_secs`, 30 days by default), and
+/// so to have one with `DEFAULT_KEY_DIFFICULTY` bits of work.  After
+/// that, the next release raises this to match, and every node on the
+/// network must then agree on it.
+pub const DEFAULT_REQUIRED_KEY_DIFFICULTY: u32 = 0;
+
+/// What the work is bound to, besides the key itself.  Changing this
+/// makes every key that was ever made worthless, which is what we
+/// would do if anyone were found to have stockpiled them.
+const WORK_CONTEXT: &'static [u8; 24] = b"pmail routing key work 1";
+
+/// How much work went into `k`: the number of leading zero bits of its
+/// hash.
+pub fn key_work(k: &crypto::PublicKey) -> u32 {
+    // We hash by encrypting zeros with the key itself, using the
+    // context as the nonce, which gives us the salsa20 stream for that
+    // key in this context.  The first 32 bytes of a secretbox are
+    // padding, so we look at the ones after that.
+    let zeros = [0; 64];
+    let mut stream = [0; 64];
+    crypto::secretbox(&mut stream, &zeros, &crypto::Nonce(*WORK_CONTEXT), &k.0);
+    let mut work = 0;
+    for &b in stream[32..].iter() {
+        work += b.leading_zeros();
+        if b != 0 {
+            break;
+        }
+    }
+    work
+}
+
+pub fn has_enough_work(k: &crypto::PublicKey, difficulty: u32) -> bool {
+    difficulty == 0 || key_work(k) >= difficulty
+}
+
+/// Make a new keypair whose public key has at least `difficulty` bits
+/// of work behind it.
+pub fn generate_keypair(difficulty: u32) -> crypto::KeyPair {
+    loop {
+        let kp = crypto::box_keypair();
+        if has_enough_work(&kp.public, difficulty) {
+            return kp;
+        }
+    }
+}
+
+#[test]
+fn generated_keys_have_work() {
+    for d in 0..9 {
+        let kp = generate_keypair(d);
+        assert!(key_work(&kp.public) >= d);
+        assert!(has_enough_work(&kp.public, d));
+    }
+    // Most keys have no work at all.
+    let lazy = (0..100).filter(|_| key_work(&crypto::box_keypair().public) >= 4).count();
+    assert!(lazy < 25, "{} of 100 random keys had 4 bits of work", lazy);
+}